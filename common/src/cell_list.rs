@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+
+use random::{MyRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Vector,
+    quad_tree::{Bounded, Rect},
+};
+
+type Cell = (i32, i32);
+
+/// A uniform grid of square cells, every object is listed in the cell of the center of its box.
+///
+/// Queries visit the cells around the query box grown by the largest half extent of an
+/// object, so with a cell width around the size of the objects and the interaction radius
+/// a query only looks at a few cells, no matter how many objects there are.
+#[derive(Serialize, Deserialize)]
+pub struct CellList<T: Bounded> {
+    width: f32,
+    objects: Vec<T>,
+    // indices into `objects`
+    cells: BTreeMap<Cell, Vec<usize>>,
+    // the largest half width and half height of the boxes of the objects so far
+    reach: (f32, f32),
+}
+
+impl<T: Bounded> CellList<T> {
+    pub fn new(width: f32) -> Self {
+        assert!(width > 0.0, "the cell width has to be positive");
+        Self {
+            width,
+            objects: Vec::new(),
+            cells: BTreeMap::new(),
+            reach: (0.0, 0.0),
+        }
+    }
+
+    pub fn from_vec(objects: Vec<T>, width: f32) -> Self {
+        let mut cell_list = Self::new(width);
+        for object in objects {
+            cell_list.insert(object);
+        }
+        cell_list
+    }
+
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    fn cell_at(&self, point: Vector) -> Cell {
+        let scaled = point / self.width;
+        (scaled.x.floor() as i32, scaled.y.floor() as i32)
+    }
+
+    fn cell_of(&self, bounds: &Rect) -> Cell {
+        self.cell_at(bounds.get_center())
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn insert(&mut self, val: T) {
+        let bounds = val.bounding_box();
+        self.reach = (
+            self.reach.0.max(bounds.width() / 2.0),
+            self.reach.1.max(bounds.height() / 2.0),
+        );
+        let cell = self.cell_of(&bounds);
+        self.cells.entry(cell).or_default().push(self.objects.len());
+        self.objects.push(val);
+    }
+
+    /// removes the object at `idx`, the last object takes its place
+    fn remove(&mut self, idx: usize) -> T {
+        let cell = self.cell_of(&self.objects[idx].bounding_box());
+        let indices = self
+            .cells
+            .get_mut(&cell)
+            .expect("the object is in its cell");
+        let pos = indices
+            .iter()
+            .position(|&i| i == idx)
+            .expect("the object is in its cell");
+        indices.swap_remove(pos);
+        if indices.is_empty() {
+            self.cells.remove(&cell);
+        }
+        let last = self.objects.len() - 1;
+        if idx != last {
+            let moved = self.cell_of(&self.objects[last].bounding_box());
+            let indices = self
+                .cells
+                .get_mut(&moved)
+                .expect("the object is in its cell");
+            *indices
+                .iter_mut()
+                .find(|i| **i == last)
+                .expect("the object is in its cell") = idx;
+        }
+        self.objects.swap_remove(idx)
+    }
+
+    pub fn pop_random(&mut self, rng: &mut MyRng) -> T {
+        self.remove(rng.random_range(0..self.len()))
+    }
+
+    /// removes an object intersecting `bounds` for which `pred` is true
+    pub fn remove_where(&mut self, bounds: Rect, pred: impl Fn(&T) -> bool) -> Option<T> {
+        let idx = self
+            .query_indices(bounds)
+            .find(|&idx| pred(&self.objects[idx]))?;
+        Some(self.remove(idx))
+    }
+
+    fn query_indices(&self, bounds: Rect) -> impl Iterator<Item = usize> + '_ {
+        let reach = Vector::new(self.reach.0, self.reach.1);
+        let (x_min, y_min) = self.cell_at(bounds.from_box_coords((0.0, 0.0)) - reach);
+        let (x_max, y_max) = self.cell_at(bounds.from_box_coords((1.0, 1.0)) + reach);
+        // with few objects and a large query it is cheaper to look at every cell
+        let visit_all =
+            ((x_max - x_min + 1) as i64) * ((y_max - y_min + 1) as i64) > self.cells.len() as i64;
+        let cells: Vec<&Vec<usize>> = if visit_all {
+            self.cells
+                .iter()
+                .filter(|((i, j), _)| (x_min..=x_max).contains(i) && (y_min..=y_max).contains(j))
+                .map(|(_, indices)| indices)
+                .collect()
+        } else {
+            (x_min..=x_max)
+                .flat_map(|i| (y_min..=y_max).map(move |j| (i, j)))
+                .filter_map(|cell| self.cells.get(&cell))
+                .collect()
+        };
+        cells
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(move |&idx| self.objects[idx].bounding_box().intersects(&bounds))
+    }
+
+    /// every object whose box intersects `bounds`
+    pub fn query_intersects(&self, bounds: Rect) -> impl Iterator<Item = &T> {
+        self.query_indices(bounds).map(|idx| &self.objects[idx])
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.objects.iter()
+    }
+}
+
+impl<T: Bounded> From<CellList<T>> for Vec<T> {
+    fn from(val: CellList<T>) -> Self {
+        val.objects
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queries_find_what_the_quad_tree_finds() {
+        let mut rng = random::rng_from_seed(2);
+        let points: Vec<Vector> = (0..500)
+            .map(|_| Vector::new(rng.random(), rng.random()))
+            .collect();
+        let mut cells = CellList::from_vec(points.clone(), 0.05);
+        let tree = crate::QuadTree::from(points.clone());
+        for _ in 0..20 {
+            let corner = Vector::new(rng.random(), rng.random());
+            let query = Rect::new(corner.x, corner.x + 0.1, corner.y, corner.y + 0.2);
+            let mut found: Vec<_> = cells.query_intersects(query).map(|p| (p.x, p.y)).collect();
+            let mut expected: Vec<_> = tree.query_intersects(query).map(|p| (p.x, p.y)).collect();
+            found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(found, expected);
+        }
+        for _ in 0..250 {
+            cells.pop_random(&mut rng);
+        }
+        assert_eq!(cells.len(), 250);
+        let all = Rect::new(-1.0, 2.0, -1.0, 2.0);
+        assert_eq!(cells.query_intersects(all).count(), 250);
+        assert!(cells.remove_where(all, |p| p.x > 0.5).is_some());
+        assert_eq!(cells.iter().count(), 249);
+    }
+}
@@ -1,83 +1,122 @@
-use std::ops::{Add, AddAssign};
+use std::ops::{Add, AddAssign, Index, IndexMut};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-pub struct Energy {
-    pub strain_energy: f32,
-    pub bending_energy: f32,
-    pub potential_energy: f32,
-    pub field_energy: f32,
-    pub interaction_energy: f32,
-    pub boundary_energy: f32,
-}
+use crate::Vector;
 
-impl Default for Energy {
-    fn default() -> Self {
-        Self::zero()
-    }
-}
+/// the value of every energy term of a model, in the order the terms are registered
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Energy(Vec<f32>);
 
 impl Energy {
-    pub const NAMES: [&'static str; 6] = [
-        "strain",
-        "bending",
-        "potential",
-        "field",
-        "interaction",
-        "boundary",
-    ];
-
-    pub fn zero() -> Self {
-        Self {
-            strain_energy: 0.0,
-            bending_energy: 0.0,
-            potential_energy: 0.0,
-            field_energy: 0.0,
-            interaction_energy: 0.0,
-            boundary_energy: 0.0,
-        }
+    pub fn zero(terms: usize) -> Self {
+        Self(vec![0.0; terms])
+    }
+
+    pub fn from_vec(values: Vec<f32>) -> Self {
+        Self(values)
     }
 
-    pub fn as_array(self) -> [f32; 6] {
-        [
-            self.strain_energy,
-            self.bending_energy,
-            self.potential_energy,
-            self.field_energy,
-            self.interaction_energy,
-            self.boundary_energy,
-        ]
+    pub fn as_slice(&self) -> &[f32] {
+        &self.0
+    }
+
+    /// number of terms
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 
     pub fn tot(&self) -> f32 {
-        self.as_array().iter().sum()
+        self.0.iter().sum()
     }
 
     pub fn is_finite(&self) -> bool {
-        self.as_array()
-            .iter()
-            .fold(true, |acc, f| acc && f.is_finite())
+        self.0.iter().all(|f| f.is_finite())
+    }
+}
+
+impl Index<usize> for Energy {
+    type Output = f32;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.0[index]
+    }
+}
+
+impl IndexMut<usize> for Energy {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.0[index]
     }
 }
 
 impl Add for Energy {
     type Output = Energy;
 
-    fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            strain_energy: self.strain_energy + rhs.strain_energy,
-            bending_energy: self.bending_energy + rhs.bending_energy,
-            potential_energy: self.potential_energy + rhs.potential_energy,
-            field_energy: self.field_energy + rhs.field_energy,
-            interaction_energy: self.interaction_energy + rhs.interaction_energy,
-            boundary_energy: self.boundary_energy + rhs.boundary_energy,
-        }
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self += rhs;
+        self
     }
 }
 
 impl AddAssign for Energy {
     fn add_assign(&mut self, rhs: Self) {
-        *self = *self + rhs
+        // an empty energy is the zero of any number of terms
+        if self.is_empty() {
+            *self = rhs;
+            return;
+        }
+        debug_assert!(rhs.is_empty() || rhs.len() == self.len());
+        for (val, rhs) in self.0.iter_mut().zip(rhs.0) {
+            *val += rhs
+        }
+    }
+}
+
+/// derivatives of every energy term with respect to the points and vectors of a spline,
+/// each in the same order as they are stored in the spline
+#[derive(Debug, Clone)]
+pub struct EnergyGradient(Vec<Vec<Vector>>);
+
+impl EnergyGradient {
+    pub fn zeros(terms: usize, len: usize) -> Self {
+        Self(vec![vec![Vector::zeros(); len]; terms])
+    }
+
+    pub fn components(&self) -> &[Vec<Vector>] {
+        &self.0
+    }
+
+    pub fn component(&self, term: usize) -> &[Vector] {
+        &self.0[term]
+    }
+
+    pub fn component_mut(&mut self, term: usize) -> &mut [Vector] {
+        &mut self.0[term]
+    }
+
+    /// number of points and vectors
+    pub fn len(&self) -> usize {
+        self.0.first().map_or(0, |component| component.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// gradient of the total energy
+    pub fn tot(&self) -> Vec<Vector> {
+        (0..self.len())
+            .map(|i| self.0.iter().map(|component| component[i]).sum())
+            .collect()
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.0
+            .iter()
+            .all(|component| component.iter().all(|v| v.x.is_finite() && v.y.is_finite()))
     }
 }
@@ -1,21 +1,26 @@
 use nalgebra::{Rotation2, Vector2};
 
+pub mod cell_list;
 pub mod energy;
 pub mod plt;
 pub mod quad_tree;
 pub mod sampler;
+pub mod shape;
+pub mod spatial_index;
 pub mod spline;
 pub mod storage;
 
-pub use energy::Energy;
+pub use energy::{Energy, EnergyGradient};
 pub use quad_tree::{Bounded, QuadTree, Rect};
-pub use sampler::Samples2d;
+pub use sampler::{EdgePolicy, Interpolation, Samples2d};
+pub use shape::Shape;
+pub use spatial_index::{AnyIndex, IndexKind, SpatialIndex};
 pub use spline::{Segment, Spline};
-pub use storage::{SplineRef, SplineStorage};
+pub use storage::{SegmentRef, SplineRef, SplineStorage, Transaction};
 
-pub const CLEAR_LINE: &'static str = "\x1B[2K\r";
-pub const MOVE_UP: &'static str = "\x1B[A\r";
-pub const PIXEL_PER_CM: f32 = 37.795275591;
+pub const CLEAR_LINE: &str = "\x1B[2K\r";
+pub const MOVE_UP: &str = "\x1B[A\r";
+pub const PIXEL_PER_CM: f32 = 37.795_277;
 
 pub type Vector = Vector2<f32>;
 pub type Rotation = Rotation2<f32>;
@@ -16,7 +16,7 @@ pub fn simple_line(values: &[f32], caption: &str, path: impl AsRef<Path>) -> any
     root.fill(&WHITE)?;
 
     let x_range = 0..values.len();
-    let y_range = min(&values)..max(&values);
+    let y_range = min(values)..max(values);
     let mut chart = ChartBuilder::on(&root)
         .margin(200)
         .caption(caption, ("sans-serif", FONT))
@@ -44,8 +44,10 @@ pub fn simple_line(values: &[f32], caption: &str, path: impl AsRef<Path>) -> any
     Ok(())
 }
 
+/// one line per energy term, `names` are the names of the terms
 pub fn divergent_chart(
     energies: &[Energy],
+    names: &[&str],
     caption: &str,
     path: impl AsRef<Path>,
 ) -> anyhow::Result<()> {
@@ -55,11 +57,11 @@ pub fn divergent_chart(
     let first = energies
         .first()
         .expect("the chart should never be drawn empty")
-        .as_array();
+        .as_slice();
     let y_min = energies
         .iter()
         .map(|val| {
-            val.as_array()
+            val.as_slice()
                 .iter()
                 .enumerate()
                 .map(|(i, val)| val - first[i])
@@ -71,7 +73,7 @@ pub fn divergent_chart(
     let y_max = energies
         .iter()
         .map(|val| {
-            val.as_array()
+            val.as_slice()
                 .iter()
                 .enumerate()
                 .map(|(i, val)| val - first[i])
@@ -100,16 +102,13 @@ pub fn divergent_chart(
         .x_desc("Sweeps")
         .draw()?;
 
-    for i in 0..6 {
+    for (i, &offset) in first.iter().enumerate() {
         chart
             .draw_series(LineSeries::new(
-                energies
-                    .iter()
-                    .map(|val| val.as_array()[i] - first[i])
-                    .enumerate(),
+                energies.iter().map(|val| val[i] - offset).enumerate(),
                 Palette99::pick(i).stroke_width(STROKE_WIDTH),
             ))?
-            .label(format!("{} energy", Energy::NAMES[i]))
+            .label(format!("{} energy", names[i]))
             .legend(move |(x, y)| {
                 Rectangle::new(
                     [(x - 15, y - 15), (x + 15, y + 15)],
@@ -120,7 +119,7 @@ pub fn divergent_chart(
 
     chart
         .configure_series_labels()
-        .background_style(&WHITE.mix(0.2))
+        .background_style(WHITE.mix(0.2))
         .label_font(("sans-serif", S_FONT))
         .border_style(BLACK.stroke_width(S_STROKE_WIDTH))
         .draw()?;
@@ -133,11 +132,25 @@ pub fn rate_plot<const N: usize>(
     values: &[[f32; N]],
     caption: &str,
     path: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let names = ["lower", "accepted", "rejected"];
+    let series: Vec<Vec<f32>> = (0..N)
+        .map(|i| values.iter().map(|val| val[i]).collect())
+        .collect();
+    labeled_rate_plot(&series, &names[..N], caption, path)
+}
+
+/// plots every series of rates with its name in the legend
+pub fn labeled_rate_plot(
+    series: &[Vec<f32>],
+    names: &[impl AsRef<str>],
+    caption: &str,
+    path: impl AsRef<Path>,
 ) -> anyhow::Result<()> {
     let root = BitMapBackend::new(&path, PLOT_FORMAT).into_drawing_area();
     root.fill(&WHITE)?;
 
-    let x_range = 0..values.len();
+    let x_range = 0..series.iter().map(|values| values.len()).max().unwrap_or(0);
     let y_range = 0.0..1.0_f32;
     let mut chart = ChartBuilder::on(&root)
         .margin(200)
@@ -157,15 +170,13 @@ pub fn rate_plot<const N: usize>(
         .x_desc("Sweeps")
         .y_desc("Rate")
         .draw()?;
-    let names = ["lower", "accepted", "rejected"];
-    for i in 0..N {
-        let this = values.iter().map(|val| val[i]).enumerate();
+    for (i, (values, name)) in series.iter().zip(names).enumerate() {
         chart
             .draw_series(LineSeries::new(
-                this,
+                values.iter().copied().enumerate(),
                 Palette99::pick(i).stroke_width(STROKE_WIDTH),
             ))?
-            .label(names[i])
+            .label(name.as_ref())
             .legend(move |(x, y)| {
                 Rectangle::new(
                     [(x - 20, y - 20), (x + 20, y + 20)],
@@ -176,7 +187,7 @@ pub fn rate_plot<const N: usize>(
 
     chart
         .configure_series_labels()
-        .background_style(&WHITE.mix(0.8))
+        .background_style(WHITE.mix(0.8))
         .label_font(("sans-serif", S_FONT))
         .border_style(BLACK.stroke_width(S_STROKE_WIDTH))
         .draw()?;
@@ -186,9 +197,9 @@ pub fn rate_plot<const N: usize>(
 }
 
 fn min(values: &[f32]) -> f32 {
-    *values.iter().min_by(|a, b| (**a).total_cmp(*b)).unwrap()
+    *values.iter().min_by(|a, b| (**a).total_cmp(b)).unwrap()
 }
 
 fn max(values: &[f32]) -> f32 {
-    *values.iter().max_by(|a, b| (**a).total_cmp(*b)).unwrap()
+    *values.iter().max_by(|a, b| (**a).total_cmp(b)).unwrap()
 }
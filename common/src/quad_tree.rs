@@ -1,56 +1,106 @@
-use either::Either::{self, Left, Right};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 
-const MAX_DEPTH: usize = 3;
+const MAX_DEPTH: usize = 8;
+const LEAF_CAPACITY: usize = 8;
 
 mod iter;
 mod rect;
 use random::{MyRng, Rng};
 pub use rect::Rect;
+use serde::{Deserialize, Serialize};
+
+use crate::Vector;
 
 pub trait Bounded {
     fn bounding_box(&self) -> Rect;
 }
 
+/// how deep the tree may grow and how many objects a leaf holds before it is split
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Limits {
+    max_depth: usize,
+    capacity: usize,
+}
+
+/// A loose quad tree, every object sits in the deepest node whose quadrant contains its box.
+///
+/// Leaves are split once they hold more than `capacity` objects and merged again
+/// when their parent holds no more than half of `capacity` objects in total,
+/// so a node at the limit does not split and merge with every insertion and removal.
+#[derive(Serialize, Deserialize)]
 pub struct QuadTree<T: Bounded> {
     root: Node<T>,
     len: usize,
+    limits: Limits,
 }
 
 impl<T: Bounded> From<Vec<T>> for QuadTree<T> {
     fn from(objects: Vec<T>) -> Self {
-        let len = objects.len();
-        let bounds = objects
-            .iter()
-            .map(|val| val.bounding_box())
-            .reduce(|acc, val| val.combine(acc))
-            .expect("the vector should not be empty");
-        Self {
-            root: Node::new(objects, bounds, 0),
-            len,
-        }
+        Self::with_limits(objects, MAX_DEPTH, LEAF_CAPACITY)
+    }
+}
+
+impl<T: Bounded> Default for QuadTree<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<T: Bounded> QuadTree<T> {
     pub fn new() -> Self {
-        Self {
-            root: Node::new_placeholder(),
-            len: 0,
-        }
+        Self::with_limits(Vec::new(), MAX_DEPTH, LEAF_CAPACITY)
+    }
+
+    /// a tree whose nodes are at most `max_depth` levels below the root
+    /// and whose leaves are split once they hold more than `capacity` objects
+    pub fn with_limits(objects: Vec<T>, max_depth: usize, capacity: usize) -> Self {
+        assert!(capacity > 0, "a leaf has to hold at least one object");
+        let limits = Limits {
+            max_depth,
+            capacity,
+        };
+        let len = objects.len();
+        let root = match objects
+            .iter()
+            .map(|val| val.bounding_box())
+            .reduce(|acc, val| val.combine(acc))
+        {
+            Some(bounds) => Node::new(objects, bounds, 0, limits),
+            None => Node::new_placeholder(),
+        };
+        Self { root, len, limits }
     }
 
     pub fn with_bounds(objects: Vec<T>, bounds: Rect) -> Self {
+        let limits = Limits {
+            max_depth: MAX_DEPTH,
+            capacity: LEAF_CAPACITY,
+        };
         let len = objects.len();
         Self {
-            root: Node::new(objects, bounds, 0),
+            root: Node::new(objects, bounds, 0, limits),
             len,
+            limits,
         }
     }
 
+    pub fn max_depth(&self) -> usize {
+        self.limits.max_depth
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.limits.capacity
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     pub fn insert(&mut self, val: T) {
         if self.len == 0 {
             self.len += 1;
@@ -59,13 +109,13 @@ impl<T: Bounded> QuadTree<T> {
         }
         if self.root.bounds.contains(&val.bounding_box()) {
             self.len += 1;
-            self.root.insert(val);
+            self.root.insert(val, self.limits);
         } else {
             let new_bounds = self.root.bounds.combine(val.bounding_box());
             let mut as_vec: Vec<_> =
                 std::mem::replace(&mut self.root, Node::new_placeholder()).into();
             as_vec.push(val);
-            self.root = Node::new(as_vec, new_bounds, 0);
+            self.root = Node::new(as_vec, new_bounds, 0, self.limits);
             self.len += 1;
         }
     }
@@ -93,31 +143,159 @@ impl<T: Bounded> QuadTree<T> {
 
 impl<T: Bounded> QuadTree<T> {
     fn pop(&mut self, index: usize) -> T {
+        assert!(index < self.len, "out of bounds pop");
         self.len -= 1;
-        self.root.pop(index).expect_left("out of bounds pop")
+        self.root.pop(index, self.limits)
     }
 
     pub fn pop_random(&mut self, rng: &mut MyRng) -> T {
         self.pop(rng.random_range(0..self.len))
     }
+
+    /// removes an object intersecting `bounds` for which `pred` is true
+    pub fn remove_where(&mut self, bounds: Rect, pred: impl Fn(&T) -> bool) -> Option<T> {
+        let val = self.root.remove_where(&bounds, &pred, self.limits)?;
+        self.len -= 1;
+        Some(val)
+    }
 }
 
-impl<T: Bounded> Into<Vec<T>> for QuadTree<T> {
-    fn into(self) -> Vec<T> {
-        self.root.into()
+impl<T: Bounded + PartialEq> QuadTree<T> {
+    /// removes the object equal to `val`, only the nodes containing its box are searched
+    pub fn remove(&mut self, val: &T) -> Option<T> {
+        let bounds = val.bounding_box();
+        let removed =
+            self.root
+                .remove_contained(&bounds, &|other: &T| other == val, self.limits)?;
+        self.len -= 1;
+        Some(removed)
+    }
+
+    /// replaces `old` by `new` and returns `old`, `new` is moved up from the node `old`
+    /// was found in only as far as needed to contain it,
+    /// if `old` is not in the tree `new` is inserted and `None` is returned
+    pub fn update(&mut self, old: &T, new: T) -> Option<T> {
+        match self.root.update(old, &old.bounding_box(), new, self.limits) {
+            Update::Done(old) => Some(old),
+            Update::Moved(old, new) => {
+                self.len -= 1;
+                self.insert(new);
+                Some(old)
+            }
+            Update::Missing(new) => {
+                self.insert(new);
+                None
+            }
+        }
     }
 }
 
-#[derive(Clone)]
+impl<T: Bounded> QuadTree<T> {
+    /// the `k` objects whose boxes are closest to `point`, closest first
+    pub fn nearest(&self, point: Vector, k: usize) -> Vec<&T> {
+        let mut found = Vec::with_capacity(k);
+        if self.is_empty() || k == 0 {
+            return found;
+        }
+        let mut heap = BinaryHeap::new();
+        heap.push(Candidate {
+            dist: self.root.bounds.distance_to_point(point),
+            entry: Entry::Node(&self.root),
+        });
+        while let Some(Candidate { dist: _, entry }) = heap.pop() {
+            match entry {
+                Entry::Object(val) => {
+                    found.push(val);
+                    if found.len() == k {
+                        break;
+                    }
+                }
+                Entry::Node(node) => {
+                    for val in &node.objects {
+                        heap.push(Candidate {
+                            dist: val.bounding_box().distance_to_point(point),
+                            entry: Entry::Object(val),
+                        });
+                    }
+                    for child in node.children.iter().flatten() {
+                        if child.count > 0 {
+                            heap.push(Candidate {
+                                dist: child.bounds.distance_to_point(point),
+                                entry: Entry::Node(child),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+impl<T: Bounded> From<QuadTree<T>> for Vec<T> {
+    fn from(val: QuadTree<T>) -> Self {
+        val.root.into()
+    }
+}
+
+enum Entry<'a, T: Bounded> {
+    Node(&'a Node<T>),
+    Object(&'a T),
+}
+
+/// an entry of the nearest neighbour search, the heap pops the closest first
+struct Candidate<'a, T: Bounded> {
+    dist: f32,
+    entry: Entry<'a, T>,
+}
+
+impl<T: Bounded> PartialEq for Candidate<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Bounded> Eq for Candidate<'_, T> {}
+
+impl<T: Bounded> PartialOrd for Candidate<'_, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Bounded> Ord for Candidate<'_, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // objects before nodes at the same distance, so ties are returned right away
+        let rank = |entry: &Entry<T>| matches!(entry, Entry::Node(_));
+        other
+            .dist
+            .total_cmp(&self.dist)
+            .then_with(|| rank(&other.entry).cmp(&rank(&self.entry)))
+    }
+}
+
+/// the result of updating an object in a subtree
+enum Update<T> {
+    /// `new` took the place of the returned old object in the subtree
+    Done(T),
+    /// the old object was removed but `new` does not fit into the subtree
+    Moved(T, T),
+    /// the old object is not in the subtree
+    Missing(T),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct Node<T: Bounded> {
     bounds: Rect,
     depth: usize,
+    // number of objects in this node and all its children
+    count: usize,
     objects: Vec<T>,
     children: Option<[Box<Node<T>>; 4]>,
 }
 
 impl<T: Bounded> Node<T> {
-    fn new(objects: Vec<T>, bounds: Rect, depth: usize) -> Self {
+    fn new(objects: Vec<T>, bounds: Rect, depth: usize, limits: Limits) -> Self {
         assert!(
             objects
                 .iter()
@@ -126,10 +304,12 @@ impl<T: Bounded> Node<T> {
                 .unwrap_or(true)
         );
 
-        if depth >= MAX_DEPTH {
+        let count = objects.len();
+        if depth >= limits.max_depth || count <= limits.capacity {
             return Self {
                 bounds,
                 depth,
+                count,
                 objects,
                 children: None,
             };
@@ -153,12 +333,14 @@ impl<T: Bounded> Node<T> {
                 sorted_obj[i].drain(..).collect(),
                 boxes[i],
                 depth + 1,
+                limits,
             ))
         });
 
         Self {
             bounds,
             depth,
+            count,
             objects: remaining,
             children: Some(children),
         }
@@ -167,6 +349,7 @@ impl<T: Bounded> Node<T> {
     fn from_single(val: T, depth: usize) -> Self {
         Self {
             depth,
+            count: 1,
             bounds: val.bounding_box(),
             objects: vec![val],
             children: None,
@@ -177,57 +360,134 @@ impl<T: Bounded> Node<T> {
     fn new_placeholder() -> Self {
         Self {
             depth: 0,
+            count: 0,
             bounds: Rect::new(0.0, 0.0, 0.0, 0.0),
             objects: Vec::new(),
             children: None,
         }
     }
 
-    fn insert(&mut self, val: T) {
-        if self.depth >= MAX_DEPTH {
-            self.objects.push(val);
-            return;
-        }
-
+    fn insert(&mut self, val: T, limits: Limits) {
+        self.count += 1;
         let bounds = val.bounding_box();
         if let Some(children) = &mut self.children {
             for child in children {
                 if child.bounds.contains(&bounds) {
-                    child.insert(val);
+                    child.insert(val, limits);
                     return;
                 }
             }
-        } else {
-            let boxes = self.bounds.get_quadrants();
+            self.objects.push(val);
+            return;
+        }
+        self.objects.push(val);
+        if self.objects.len() > limits.capacity && self.depth < limits.max_depth {
+            self.split(limits);
+        }
+    }
 
-            for i in 0..4 {
-                if boxes[i].contains(&bounds) {
-                    let mut children = std::array::from_fn(|i| {
-                        Box::new(Node::new(Vec::new(), boxes[i], self.depth))
-                    });
-                    children[i].insert(val);
-                    self.children = Some(children);
-                    return;
-                }
-            }
+    /// moves every object which fits into a quadrant down into a new child
+    fn split(&mut self, limits: Limits) {
+        let boxes = self.bounds.get_quadrants();
+        self.children = Some(std::array::from_fn(|i| {
+            Box::new(Node::new(Vec::new(), boxes[i], self.depth + 1, limits))
+        }));
+        let objects = std::mem::take(&mut self.objects);
+        self.count -= objects.len();
+        for val in objects {
+            self.insert(val, limits);
+        }
+    }
+
+    /// turns the node back into a leaf if its children are leaves
+    /// and it holds at most half of the capacity
+    fn try_merge(&mut self, limits: Limits) {
+        let Some(children) = &self.children else {
+            return;
+        };
+        if self.count > limits.capacity / 2 || children.iter().any(|child| child.children.is_some())
+        {
+            return;
+        }
+        let children = self.children.take().expect("checked above");
+        for child in children {
+            self.objects.extend(child.objects);
         }
-        self.objects.push(val)
     }
 
-    fn pop(&mut self, mut index: usize) -> Either<T, usize> {
+    fn pop(&mut self, mut index: usize, limits: Limits) -> T {
+        self.count -= 1;
         if index < self.objects.len() {
-            return Left(self.objects.swap_remove(index));
+            let val = self.objects.swap_remove(index);
+            self.try_merge(limits);
+            return val;
         }
         index -= self.objects.len();
-        if let Some(children) = &mut self.children {
-            for child in children {
-                match child.pop(index) {
-                    Left(val) => return Left(val),
-                    Right(idx) => index = idx,
-                }
+        let children = self.children.as_mut().expect("the index is in a child");
+        for child in children {
+            if index < child.count {
+                let val = child.pop(index, limits);
+                self.try_merge(limits);
+                return val;
+            }
+            index -= child.count;
+        }
+        unreachable!("the count of a node is the sum of its children")
+    }
+
+    fn remove_where(
+        &mut self,
+        bounds: &Rect,
+        pred: &impl Fn(&T) -> bool,
+        limits: Limits,
+    ) -> Option<T> {
+        self.take(
+            limits,
+            pred,
+            |val| val.intersects(bounds),
+            |node| node.intersects(bounds),
+        )
+    }
+
+    fn remove_contained(
+        &mut self,
+        bounds: &Rect,
+        pred: &impl Fn(&T) -> bool,
+        limits: Limits,
+    ) -> Option<T> {
+        self.take(limits, pred, |_| true, |node| node.contains(bounds))
+    }
+
+    /// removes the first object matching `pred` whose box passes `check_val`,
+    /// only descending into children whose bounds pass `check_node`
+    fn take(
+        &mut self,
+        limits: Limits,
+        pred: &impl Fn(&T) -> bool,
+        check_val: impl Fn(Rect) -> bool + Copy,
+        check_node: impl Fn(&Rect) -> bool + Copy,
+    ) -> Option<T> {
+        if let Some(idx) = self
+            .objects
+            .iter()
+            .position(|val| check_val(val.bounding_box()) && pred(val))
+        {
+            self.count -= 1;
+            let val = self.objects.swap_remove(idx);
+            self.try_merge(limits);
+            return Some(val);
+        }
+        for child in self.children.iter_mut().flatten() {
+            if child.count > 0
+                && check_node(&child.bounds)
+                && let Some(val) = child.take(limits, pred, check_val, check_node)
+            {
+                self.count -= 1;
+                self.try_merge(limits);
+                return Some(val);
             }
         }
-        return Right(index);
+        None
     }
 
     fn count_objects(&self, vec: &mut Vec<usize>, this_level: usize) {
@@ -243,10 +503,46 @@ impl<T: Bounded> Node<T> {
     }
 }
 
-impl<T: Bounded> Into<Vec<T>> for Node<T> {
-    fn into(mut self) -> Vec<T> {
-        let mut vec: Vec<_> = self.objects.drain(..).collect();
-        if let Some(children) = self.children {
+impl<T: Bounded + PartialEq> Node<T> {
+    fn update(&mut self, old: &T, old_bounds: &Rect, new: T, limits: Limits) -> Update<T> {
+        if self.count == 0 || !self.bounds.contains(old_bounds) {
+            return Update::Missing(new);
+        }
+        if let Some(idx) = self.objects.iter().position(|val| val == old) {
+            self.count -= 1;
+            let old = self.objects.swap_remove(idx);
+            return self.place(old, new, limits);
+        }
+        let mut new = new;
+        for child in self.children.iter_mut().flatten() {
+            match child.update(old, old_bounds, new, limits) {
+                Update::Missing(val) => new = val,
+                Update::Done(old) => return Update::Done(old),
+                Update::Moved(old, val) => {
+                    self.count -= 1;
+                    return self.place(old, val, limits);
+                }
+            }
+        }
+        Update::Missing(new)
+    }
+
+    /// inserts `new` here if it fits, otherwise it has to move further up
+    fn place(&mut self, old: T, new: T, limits: Limits) -> Update<T> {
+        if self.bounds.contains(&new.bounding_box()) {
+            self.insert(new, limits);
+            Update::Done(old)
+        } else {
+            self.try_merge(limits);
+            Update::Moved(old, new)
+        }
+    }
+}
+
+impl<T: Bounded> From<Node<T>> for Vec<T> {
+    fn from(mut val: Node<T>) -> Self {
+        let mut vec: Vec<_> = val.objects.drain(..).collect();
+        if let Some(children) = val.children {
             for child in children {
                 vec.append(&mut ((*child).into()))
             }
@@ -254,3 +550,205 @@ impl<T: Bounded> Into<Vec<T>> for Node<T> {
         vec
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Item {
+        id: usize,
+        bounds: Rect,
+    }
+
+    impl Bounded for Item {
+        fn bounding_box(&self) -> Rect {
+            self.bounds
+        }
+    }
+
+    /// boxes of very different sizes, `scale` sets the region they are spread over
+    fn random_item(rng: &mut MyRng, id: usize, scale: f32) -> Item {
+        let size = 10_f32.powf(rng.random_range(-3.0..-0.5)) * scale;
+        let x = rng.random_range(-scale..scale);
+        let y = rng.random_range(-scale..scale);
+        let (w, h) = (rng.random::<f32>() * size, rng.random::<f32>() * size);
+        Item {
+            id,
+            bounds: Rect::new(x, x + w, y, y + h),
+        }
+    }
+
+    fn sorted_ids<'a>(items: impl Iterator<Item = &'a Item>) -> Vec<usize> {
+        let mut ids: Vec<usize> = items.map(|item| item.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn queries_match_a_linear_scan() {
+        for seed in 0..40 {
+            let mut rng = random::rng_from_seed(seed);
+            let max_depth = rng.random_range(0..7);
+            let capacity = rng.random_range(1..10);
+            let mut items: Vec<Item> = (0..rng.random_range(1..200))
+                .map(|id| random_item(&mut rng, id, 1.0))
+                .collect();
+            let mut tree = QuadTree::with_limits(items.clone(), max_depth, capacity);
+
+            for step in 0..150 {
+                match rng.random_range(0..4) {
+                    0 if !tree.is_empty() => {
+                        let popped = tree.pop_random(&mut rng);
+                        let pos = items.iter().position(|item| *item == popped).unwrap();
+                        items.swap_remove(pos);
+                    }
+                    1 => {
+                        // far outside of the root, so the tree is rebuilt
+                        let item = random_item(&mut rng, 1000 + step, 5.0);
+                        tree.insert(item);
+                        items.push(item);
+                    }
+                    _ => {
+                        let item = random_item(&mut rng, 1000 + step, 1.0);
+                        tree.insert(item);
+                        items.push(item);
+                    }
+                }
+                assert_eq!(tree.len(), items.len(), "seed {}", seed);
+                if !tree.is_empty() {
+                    assert!(
+                        items
+                            .iter()
+                            .all(|item| tree.get_bounds().contains(&item.bounds)),
+                        "seed {}",
+                        seed
+                    );
+                }
+
+                let query = random_item(&mut rng, 0, 1.5).bounds.add_radius(0.1);
+                assert_eq!(
+                    sorted_ids(tree.query_intersects(query)),
+                    sorted_ids(items.iter().filter(|item| item.bounds.intersects(&query))),
+                    "seed {}",
+                    seed
+                );
+                let point = Vector::new(rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0));
+                assert_eq!(
+                    sorted_ids(tree.query_contains_point(point)),
+                    sorted_ids(
+                        items
+                            .iter()
+                            .filter(|item| item.bounds.contains_point(point))
+                    ),
+                    "seed {}",
+                    seed
+                );
+                assert_eq!(sorted_ids(tree.iter()), sorted_ids(items.iter()));
+            }
+            check_node(&tree.root, tree.limits);
+            let owned: Vec<Item> = tree.into_iter().collect();
+            assert_eq!(sorted_ids(owned.iter()), sorted_ids(items.iter()));
+        }
+    }
+
+    fn random_points(rng: &mut MyRng, n: usize) -> Vec<Vector> {
+        (0..n)
+            .map(|_| Vector::new(rng.random(), rng.random()))
+            .collect()
+    }
+
+    /// checks the counts, the depths and that every object lies in its node
+    fn check_node<T: Bounded>(node: &Node<T>, limits: Limits) -> usize {
+        assert!(node.depth <= limits.max_depth);
+        assert!(
+            node.objects
+                .iter()
+                .all(|val| node.bounds.contains(&val.bounding_box()))
+        );
+        let mut count = node.objects.len();
+        match &node.children {
+            Some(children) => {
+                for child in children {
+                    assert_eq!(child.depth, node.depth + 1);
+                    count += check_node(child, limits);
+                }
+            }
+            None => {
+                assert!(node.objects.len() <= limits.capacity || node.depth == limits.max_depth)
+            }
+        }
+        assert_eq!(node.count, count);
+        count
+    }
+
+    #[test]
+    fn nodes_split_and_merge() {
+        let mut rng = random::rng_from_seed(3);
+        let mut tree = QuadTree::with_limits(random_points(&mut rng, 2), 5, 4);
+        for point in random_points(&mut rng, 500) {
+            tree.insert(point);
+        }
+        assert_eq!(check_node(&tree.root, tree.limits), 502);
+        let mut stats = Vec::new();
+        tree.root.count_objects(&mut stats, 0);
+        assert_eq!(stats.len(), 6);
+
+        for _ in 0..500 {
+            tree.pop_random(&mut rng);
+            check_node(&tree.root, tree.limits);
+        }
+        assert_eq!(tree.len(), 2);
+        assert!(tree.root.children.is_none());
+
+        // a split node only merges once it is down to half of the capacity
+        let mut tree = QuadTree::with_limits(random_points(&mut rng, 5), 5, 4);
+        assert!(tree.root.children.is_some());
+        tree.pop_random(&mut rng);
+        tree.pop_random(&mut rng);
+        assert!(tree.root.children.is_some());
+        tree.pop_random(&mut rng);
+        assert!(tree.root.children.is_none());
+    }
+
+    #[test]
+    fn removal_and_update_by_key() {
+        let mut rng = random::rng_from_seed(4);
+        let points = random_points(&mut rng, 300);
+        let mut tree = QuadTree::with_limits(points.clone(), 6, 2);
+        for point in &points[..100] {
+            assert_eq!(tree.remove(point), Some(*point));
+        }
+        assert_eq!(tree.remove(&points[0]), None);
+        for point in &points[100..200] {
+            let moved = point + Vector::new(rng.random_range(-0.1..0.1), 0.05);
+            assert_eq!(tree.update(point, moved), Some(*point));
+        }
+        assert_eq!(tree.update(&points[0], points[0]), None);
+        assert_eq!(check_node(&tree.root, tree.limits), 201);
+        assert!(tree.remove(&points[150]).is_none());
+        assert!(tree.remove(&points[250]).is_some());
+    }
+
+    #[test]
+    fn distance_queries_match_brute_force() {
+        let mut rng = random::rng_from_seed(5);
+        let points = random_points(&mut rng, 400);
+        let tree = QuadTree::with_limits(points.clone(), 6, 4);
+        for _ in 0..20 {
+            let center = Vector::new(rng.random(), rng.random());
+            let dist = |p: &Vector| (p - center).norm();
+
+            let mut found: Vec<f32> = tree.query_within_distance(center, 0.1).map(dist).collect();
+            let mut expected: Vec<f32> = points.iter().map(dist).filter(|d| *d <= 0.1).collect();
+            found.sort_by(f32::total_cmp);
+            expected.sort_by(f32::total_cmp);
+            assert_eq!(found, expected);
+
+            let nearest: Vec<f32> = tree.nearest(center, 7).into_iter().map(dist).collect();
+            let mut all: Vec<f32> = points.iter().map(dist).collect();
+            all.sort_by(f32::total_cmp);
+            assert_eq!(nearest, all[..7]);
+        }
+    }
+}
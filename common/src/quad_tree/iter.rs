@@ -22,6 +22,17 @@ impl Query for ContainsPoint {
     }
 }
 
+pub struct WithinDistance {
+    point: Vector,
+    radius: f32,
+}
+
+impl Query for WithinDistance {
+    fn predicate(&self, bounds: Rect) -> bool {
+        bounds.distance_to_point(self.point) <= self.radius
+    }
+}
+
 pub struct IntoIter<T: Bounded> {
     pub(super) stack: Vec<Node<T>>,
 }
@@ -55,17 +66,18 @@ impl<T: Bounded> IntoIterator for super::QuadTree<T> {
     type IntoIter = IntoIter<T>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut stack = Vec::new();
-        stack.push(self.root);
-        IntoIter { stack }
+        IntoIter {
+            stack: vec![self.root],
+        }
     }
 }
 
 impl<T: Bounded> super::QuadTree<T> {
     pub fn iter(&self) -> Iter<'_, T> {
-        let mut stack = Vec::new();
-        stack.push(&self.root);
-        Iter { stack, index: 0 }
+        Iter {
+            stack: vec![&self.root],
+            index: 0,
+        }
     }
 }
 
@@ -97,7 +109,7 @@ impl<'a, T: Bounded> Iterator for Iter<'a, T> {
         // if the empty node has children push them on the stack
         if let Some(children) = &empty_node.children {
             for child in children {
-                self.stack.push(&child)
+                self.stack.push(child)
             }
         }
 
@@ -131,22 +143,29 @@ where
 impl<T: Bounded> super::QuadTree<T> {
     /// returns an iterator over all elements for which func(element.bounding_box()) is true
     pub fn query_iter<Q: Query>(&self, query: Q) -> QueryIter<'_, T, Q> {
-        let mut stack = Vec::new();
-        stack.push(&self.root);
         QueryIter {
-            stack,
+            stack: vec![&self.root],
             query,
             index: 0,
         }
     }
 
-    pub fn query_contains_point(&self, point: Vector) -> QueryIter<T, ContainsPoint> {
+    pub fn query_contains_point(&self, point: Vector) -> QueryIter<'_, T, ContainsPoint> {
         self.query_iter(ContainsPoint(point))
     }
 
-    pub fn query_intersects(&self, bounds: Rect) -> QueryIter<T, IntersectsRect> {
+    pub fn query_intersects(&self, bounds: Rect) -> QueryIter<'_, T, IntersectsRect> {
         self.query_iter(IntersectsRect(bounds))
     }
+
+    /// every object whose box is at most `radius` away from `point`
+    pub fn query_within_distance(
+        &self,
+        point: Vector,
+        radius: f32,
+    ) -> QueryIter<'_, T, WithinDistance> {
+        self.query_iter(WithinDistance { point, radius })
+    }
 }
 
 impl<'a, T, F> Iterator for QueryIter<'a, T, F>
@@ -179,7 +198,7 @@ where
         if let Some(children) = &empty_node.children {
             for child in children {
                 if self.query.predicate(child.bounds) {
-                    self.stack.push(&child)
+                    self.stack.push(child)
                 }
             }
         }
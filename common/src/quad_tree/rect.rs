@@ -148,6 +148,33 @@ impl Rect {
         x_dist.max(y_dist)
     }
 
+    /// the distance from `position` to the closest point of the rect, zero inside
+    pub fn distance_to_point(&self, position: Vector) -> f32 {
+        let x_dist = (self.x_min - position.x)
+            .max(position.x - self.x_max)
+            .max(0.0);
+        let y_dist = (self.y_min - position.y)
+            .max(position.y - self.y_max)
+            .max(0.0);
+        x_dist.hypot(y_dist)
+    }
+
+    /// gradient of `signed_distance`, the outward normal of the closest side
+    pub fn signed_distance_gradient(&self, position: Vector) -> Vector {
+        let sides = [
+            (self.x_min - position.x, Vector::new(-1.0, 0.0)),
+            (position.x - self.x_max, Vector::new(1.0, 0.0)),
+            (self.y_min - position.y, Vector::new(0.0, -1.0)),
+            (position.y - self.y_max, Vector::new(0.0, 1.0)),
+        ];
+        sides
+            .into_iter()
+            .fold((f32::NEG_INFINITY, Vector::zeros()), |max, side| {
+                if side.0 > max.0 { side } else { max }
+            })
+            .1
+    }
+
     pub fn get_center(&self) -> Vector {
         Vector::new(
             (self.x_min + self.x_max) / 2.0,
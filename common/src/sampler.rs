@@ -1,15 +1,78 @@
+use std::ops::{Add, Div, Mul, Sub};
+
 use anyhow::Context;
 use image::Luma;
 use serde::{Deserialize, Serialize};
 
 use crate::{Vector, quad_tree::Rect};
 
+/// how values between the samples are found, written as its name in parameter files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Interpolation {
+    /// the closest sample, the values are piecewise constant
+    #[default]
+    Nearest,
+    /// linear between the four closest samples
+    Bilinear,
+    /// Catmull-Rom splines through the sixteen closest samples
+    Bicubic,
+}
+
+impl From<Interpolation> for String {
+    fn from(interpolation: Interpolation) -> Self {
+        format!("{:?}", interpolation)
+    }
+}
+
+impl TryFrom<String> for Interpolation {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match name.as_str() {
+            "Nearest" => Ok(Self::Nearest),
+            "Bilinear" => Ok(Self::Bilinear),
+            "Bicubic" => Ok(Self::Bicubic),
+            _ => Err(anyhow::anyhow!("unknown interpolation {}", name)),
+        }
+    }
+}
+
+/// the values outside of the bounds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum EdgePolicy<T> {
+    /// there are no values outside
+    #[default]
+    None,
+    /// the closest sample on the edge
+    Clamp,
+    /// the samples repeat periodically
+    Wrap,
+    /// a fixed value
+    Constant(T),
+}
+
+/// values which can be interpolated between samples
+pub trait Interpolate:
+    Copy + Add<Output = Self> + Sub<Output = Self> + Mul<f32, Output = Self> + Div<f32, Output = Self>
+{
+}
+
+impl<T> Interpolate for T where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T> + Div<f32, Output = T>
+{
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Samples2d<T> {
     samples: Vec<T>,
     width: usize,
     height: usize,
     bounds: Rect,
+    #[serde(default)]
+    interpolation: Interpolation,
+    #[serde(default)]
+    edges: EdgePolicy<T>,
 }
 
 impl<T> Samples2d<T> {
@@ -20,6 +83,8 @@ impl<T> Samples2d<T> {
             width,
             height,
             bounds,
+            interpolation: Interpolation::default(),
+            edges: EdgePolicy::default(),
         }
     }
 
@@ -32,23 +97,41 @@ impl<T> Samples2d<T> {
                 samples.push(func(position))
             }
         }
-        Self {
-            samples,
-            width,
-            height,
-            bounds,
-        }
+        Self::new(samples, width, height, bounds)
+    }
+
+    pub fn with_interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    pub fn with_edges(mut self, edges: EdgePolicy<T>) -> Self {
+        self.edges = edges;
+        self
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn edges(&self) -> &EdgePolicy<T> {
+        &self.edges
     }
 }
 
 impl<T: Clone> Samples2d<T> {
     pub fn new_filled(fill: T, width: usize, height: usize, bounds: Rect) -> Self {
-        Self {
-            samples: vec![fill; width * height],
-            width,
-            height,
-            bounds,
-        }
+        Self::new(vec![fill; width * height], width, height, bounds)
+    }
+
+    /// the number of samples in x and y
+    pub fn dim(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// the samples row by row
+    pub fn values(&self) -> &[T] {
+        &self.samples
     }
 
     pub fn get_bounds(&self) -> Rect {
@@ -57,10 +140,17 @@ impl<T: Clone> Samples2d<T> {
 
     pub fn map<S>(&self, func: impl Fn(&T) -> S) -> Samples2d<S> {
         Samples2d {
-            samples: self.samples.iter().map(func).collect(),
+            samples: self.samples.iter().map(&func).collect(),
             width: self.width,
             height: self.height,
             bounds: self.bounds,
+            interpolation: self.interpolation,
+            edges: match &self.edges {
+                EdgePolicy::None => EdgePolicy::None,
+                EdgePolicy::Clamp => EdgePolicy::Clamp,
+                EdgePolicy::Wrap => EdgePolicy::Wrap,
+                EdgePolicy::Constant(val) => EdgePolicy::Constant(func(val)),
+            },
         }
     }
 
@@ -83,7 +173,8 @@ impl<T> Samples2d<T> {
         Some(idx_x + self.width * idx_y)
     }
 
-    pub fn get_sample(&self, position: Vector) -> Option<&T> {
+    /// the sample closest to `position`, `None` outside of the bounds
+    pub fn get_nearest(&self, position: Vector) -> Option<&T> {
         let idx = self.calculate_idx(position)?;
         // SAFETY calculate_idx produces valid ideces only
         unsafe { Some(self.samples.get_unchecked(idx)) }
@@ -95,6 +186,171 @@ impl<T> Samples2d<T> {
     }
 }
 
+impl<T: Copy + Sub<Output = T> + Div<f32, Output = T>> Samples2d<T> {
+    /// partial derivatives in x and y of the sampled function around `position`,
+    /// from central differences of the neighbouring samples (one sided at the edges)
+    pub fn get_difference(&self, position: Vector) -> Option<(T, T)> {
+        let idx = self.calculate_idx(position)?;
+        let (idx_x, idx_y) = (idx % self.width, idx / self.width);
+        let spacing_x = self.bounds.get_width() / self.width as f32;
+        let spacing_y = self.bounds.get_height() / self.height as f32;
+
+        let left = idx_x.saturating_sub(1);
+        let right = (idx_x + 1).min(self.width - 1);
+        let below = idx_y.saturating_sub(1);
+        let above = (idx_y + 1).min(self.height - 1);
+
+        // with a single sample in a direction both indices agree and the difference vanishes
+        let difference = |lower: usize, upper: usize, steps: usize, spacing: f32| {
+            (self.samples[upper] - self.samples[lower]) / (steps.max(1) as f32 * spacing)
+        };
+        let d_x = difference(
+            left + self.width * idx_y,
+            right + self.width * idx_y,
+            right - left,
+            spacing_x,
+        );
+        let d_y = difference(
+            idx_x + self.width * below,
+            idx_x + self.width * above,
+            above - below,
+            spacing_y,
+        );
+        Some((d_x, d_y))
+    }
+}
+
+impl<T: Interpolate> Samples2d<T> {
+    /// the position in units of the sample spacing, the samples sit on whole numbers
+    fn grid_coords(&self, position: Vector) -> (f32, f32) {
+        let (frac_x, frac_y) = self.bounds.to_box_coords(position);
+        (frac_x * self.width as f32, frac_y * self.height as f32)
+    }
+
+    fn spacing(&self) -> (f32, f32) {
+        (
+            self.bounds.get_width() / self.width as f32,
+            self.bounds.get_height() / self.height as f32,
+        )
+    }
+
+    /// the sample at `(i, j)`, indices outside of the grid follow the edge policy,
+    /// with no policy they are clamped because they are only needed near the edges
+    fn texel(&self, i: isize, j: isize) -> T {
+        let (width, height) = (self.width as isize, self.height as isize);
+        let (i, j) = match self.edges {
+            EdgePolicy::Wrap => (i.rem_euclid(width), j.rem_euclid(height)),
+            EdgePolicy::Constant(val) if i < 0 || j < 0 || i >= width || j >= height => {
+                return val;
+            }
+            _ => (i.clamp(0, width - 1), j.clamp(0, height - 1)),
+        };
+        self.samples[i as usize + self.width * j as usize]
+    }
+
+    /// false if the sample closest to `(u, v)` is outside of the grid and there are
+    /// no values outside
+    fn is_defined(&self, u: f32, v: f32) -> bool {
+        if !matches!(self.edges, EdgePolicy::None) {
+            return u.is_finite() && v.is_finite();
+        }
+        u >= 0.0 && v >= 0.0 && u.round() < self.width as f32 && v.round() < self.height as f32
+    }
+
+    /// the interpolated value at `position`
+    pub fn get_sample(&self, position: Vector) -> Option<T> {
+        let (u, v) = self.grid_coords(position);
+        if !self.is_defined(u, v) {
+            return None;
+        }
+        let (i, j) = (u.floor() as isize, v.floor() as isize);
+        let (s, t) = (u - u.floor(), v - v.floor());
+        Some(match self.interpolation {
+            Interpolation::Nearest => self.texel(u.round() as isize, v.round() as isize),
+            Interpolation::Bilinear => {
+                let bottom = self.texel(i, j) * (1.0 - s) + self.texel(i + 1, j) * s;
+                let top = self.texel(i, j + 1) * (1.0 - s) + self.texel(i + 1, j + 1) * s;
+                bottom * (1.0 - t) + top * t
+            }
+            Interpolation::Bicubic => {
+                self.bicubic(i, j, catmull_rom_weights(s), catmull_rom_weights(t))
+            }
+        })
+    }
+
+    /// the partial derivatives in x and y at `position`, exact for the interpolation
+    /// and like `get_difference` without interpolation
+    pub fn get_gradient(&self, position: Vector) -> Option<(T, T)> {
+        let (u, v) = self.grid_coords(position);
+        if !self.is_defined(u, v) {
+            return None;
+        }
+        let (i, j) = (u.floor() as isize, v.floor() as isize);
+        let (s, t) = (u - u.floor(), v - v.floor());
+        let (spacing_x, spacing_y) = self.spacing();
+        let (d_u, d_v) = match self.interpolation {
+            Interpolation::Nearest
+                if matches!(self.edges, EdgePolicy::None | EdgePolicy::Clamp) =>
+            {
+                return self.get_difference(position);
+            }
+            Interpolation::Nearest => {
+                let (i, j) = (u.round() as isize, v.round() as isize);
+                let d_u = (self.texel(i + 1, j) - self.texel(i - 1, j)) / 2.0;
+                let d_v = (self.texel(i, j + 1) - self.texel(i, j - 1)) / 2.0;
+                (d_u, d_v)
+            }
+            Interpolation::Bilinear => {
+                let d_u = (self.texel(i + 1, j) - self.texel(i, j)) * (1.0 - t)
+                    + (self.texel(i + 1, j + 1) - self.texel(i, j + 1)) * t;
+                let d_v = (self.texel(i, j + 1) - self.texel(i, j)) * (1.0 - s)
+                    + (self.texel(i + 1, j + 1) - self.texel(i + 1, j)) * s;
+                (d_u, d_v)
+            }
+            Interpolation::Bicubic => {
+                let (w_s, w_t) = (catmull_rom_weights(s), catmull_rom_weights(t));
+                let d_u = self.bicubic(i, j, catmull_rom_derivatives(s), w_t);
+                let d_v = self.bicubic(i, j, w_s, catmull_rom_derivatives(t));
+                (d_u, d_v)
+            }
+        };
+        Some((d_u / spacing_x, d_v / spacing_y))
+    }
+
+    fn bicubic(&self, i: isize, j: isize, w_x: [f32; 4], w_y: [f32; 4]) -> T {
+        let row = |j: isize| {
+            (1..4).fold(self.texel(i - 1, j) * w_x[0], |acc, k| {
+                acc + self.texel(i - 1 + k as isize, j) * w_x[k]
+            })
+        };
+        (1..4).fold(row(j - 1) * w_y[0], |acc, k| {
+            acc + row(j - 1 + k as isize) * w_y[k]
+        })
+    }
+}
+
+/// the weights of the samples at -1, 0, 1 and 2 for a point at `t` between 0 and 1
+fn catmull_rom_weights(t: f32) -> [f32; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        (-t3 + 2.0 * t2 - t) / 2.0,
+        (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+        (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+        (t3 - t2) / 2.0,
+    ]
+}
+
+/// the derivatives of `catmull_rom_weights` with respect to `t`
+fn catmull_rom_derivatives(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    [
+        (-3.0 * t2 + 4.0 * t - 1.0) / 2.0,
+        (9.0 * t2 - 10.0 * t) / 2.0,
+        (-9.0 * t2 + 8.0 * t + 1.0) / 2.0,
+        (3.0 * t2 - 2.0 * t) / 2.0,
+    ]
+}
+
 impl Samples2d<f32> {
     pub fn as_img(&self, path: &str) -> anyhow::Result<()> {
         let img = image::ImageBuffer::<Luma<u8>, Vec<u8>>::from_vec(
@@ -107,3 +363,81 @@ impl Samples2d<f32> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn smooth(position: Vector) -> f32 {
+        (3.0 * position.x).sin() + position.y * position.y
+    }
+
+    fn samples(interpolation: Interpolation) -> Samples2d<f32> {
+        Samples2d::from_fn(smooth, 40, 40, Rect::new(0.0, 1.0, 0.0, 1.0))
+            .with_interpolation(interpolation)
+    }
+
+    #[test]
+    fn interpolated_values_and_gradients() {
+        let h = 1e-3;
+        for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic] {
+            let samples = samples(interpolation);
+            // on the samples the interpolation agrees with them
+            let on_grid = Vector::new(0.25, 0.5);
+            assert!((samples.get_sample(on_grid).unwrap() - smooth(on_grid)).abs() < 1e-5);
+            for position in [(0.31, 0.47), (0.5123, 0.2067), (0.7133, 0.88)] {
+                let position = Vector::new(position.0, position.1);
+                let value = samples.get_sample(position).unwrap();
+                assert!(
+                    (value - smooth(position)).abs() < 2e-3,
+                    "{:?}",
+                    interpolation
+                );
+
+                let (d_x, d_y) = samples.get_gradient(position).unwrap();
+                let dx = Vector::new(h, 0.0);
+                let dy = Vector::new(0.0, h);
+                let finite_x = (samples.get_sample(position + dx).unwrap()
+                    - samples.get_sample(position - dx).unwrap())
+                    / (2.0 * h);
+                let finite_y = (samples.get_sample(position + dy).unwrap()
+                    - samples.get_sample(position - dy).unwrap())
+                    / (2.0 * h);
+                assert!((d_x - finite_x).abs() < 1e-2, "{} {}", d_x, finite_x);
+                assert!((d_y - finite_y).abs() < 1e-2, "{} {}", d_y, finite_y);
+            }
+        }
+        // nearest sampling keeps the central differences
+        let samples = samples(Interpolation::Nearest);
+        let position = Vector::new(0.5, 0.5);
+        assert_eq!(
+            samples.get_gradient(position),
+            samples.get_difference(position)
+        );
+    }
+
+    #[test]
+    fn edge_policies() {
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let ramp = Samples2d::from_fn(|pos| pos.x, 4, 4, bounds);
+        let outside = Vector::new(1.3, 0.5);
+        assert_eq!(ramp.get_sample(outside), None);
+        assert_eq!(ramp.get_gradient(outside), None);
+
+        let clamped = Samples2d::from_fn(|pos| pos.x, 4, 4, bounds).with_edges(EdgePolicy::Clamp);
+        assert_eq!(clamped.get_sample(outside), Some(0.75));
+        assert_eq!(clamped.get_sample(Vector::new(-2.0, 0.5)), Some(0.0));
+
+        let wrapped = Samples2d::from_fn(|pos| pos.x, 4, 4, bounds).with_edges(EdgePolicy::Wrap);
+        assert_eq!(wrapped.get_sample(Vector::new(1.25, 0.5)), Some(0.25));
+        assert_eq!(wrapped.get_sample(Vector::new(-0.5, 0.5)), Some(0.5));
+
+        let constant = Samples2d::from_fn(|pos| pos.x, 4, 4, bounds)
+            .with_edges(EdgePolicy::Constant(-1.0))
+            .with_interpolation(Interpolation::Bilinear);
+        assert_eq!(constant.get_sample(Vector::new(2.0, 0.5)), Some(-1.0));
+        // half way between the last sample and the constant
+        let between = constant.get_sample(Vector::new(0.875, 0.5)).unwrap();
+        assert!((between - (0.75 - 1.0) / 2.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,448 @@
+use image::{ImageBuffer, Luma};
+use serde::{Deserialize, Serialize};
+use svg::node::element::{Path, path::Data};
+
+use crate::spline::BorrowedSpline;
+use crate::{EdgePolicy, Interpolation, Rect, Samples2d, Vector};
+
+/// The region of the canvas the splines are kept in.
+///
+/// Every shape has a signed distance which is negative inside and the outward normal
+/// of the closest edge as its gradient, like `Rect::signed_distance`.
+#[derive(Serialize, Deserialize)]
+pub enum Shape {
+    Rect(Rect),
+    /// the corners of a simple polygon in either orientation
+    Polygon(Vec<Vector>),
+    /// a sampled signed distance, outside of its bounds everything is outside
+    Mask(Samples2d<f32>),
+}
+
+// relative difference of two aspect ratios below which they are treated as equal,
+// e.g. for an image and a mask which was scaled to a whole number of pixels
+const ASPECT_TOLERANCE: f32 = 0.01;
+
+fn ensure_same_aspect(a: Rect, b: Rect) -> anyhow::Result<()> {
+    let (a_aspect, b_aspect) = (a.width() / a.height(), b.width() / b.height());
+    anyhow::ensure!(
+        (a_aspect / b_aspect - 1.0).abs() <= ASPECT_TOLERANCE,
+        "a mask with the aspect ratio {} does not fit a canvas with the aspect ratio {}",
+        a_aspect,
+        b_aspect
+    );
+    Ok(())
+}
+
+impl Shape {
+    /// a mask from the pixels of `mask` above one half on `bounds`,
+    /// which need the aspect ratio of the mask as the pixels are square
+    pub fn from_mask(
+        mask: &ImageBuffer<Luma<f32>, Vec<f32>>,
+        bounds: Rect,
+    ) -> anyhow::Result<Self> {
+        let width = mask.width() as usize;
+        let height = mask.height() as usize;
+        ensure_same_aspect(Rect::new(0.0, width as f32, 0.0, height as f32), bounds)?;
+        let inside: Vec<bool> = mask.pixels().map(|val| val.0[0] > 0.5).collect();
+        let spacing = bounds.width() / width as f32;
+        let to_inside = distance_to_edge(&inside, width, height, true);
+        let to_outside = distance_to_edge(&inside, width, height, false);
+        // the edge runs half a pixel beyond the centers of the pixels next to it
+        let distances = inside
+            .iter()
+            .zip(to_inside.iter().zip(to_outside.iter()))
+            .map(|(&inside, (&to_inside, &to_outside))| {
+                if inside {
+                    -(to_inside + 0.5) * spacing
+                } else {
+                    (to_outside + 0.5) * spacing
+                }
+            })
+            .collect();
+        Ok(Shape::Mask(
+            Samples2d::new(distances, width, height, bounds)
+                .with_interpolation(Interpolation::Bilinear)
+                .with_edges(EdgePolicy::Clamp),
+        ))
+    }
+
+    /// maps the shape onto `bounds`, rects and polygons are given in box coordinates
+    /// from 0 to 1 and masks are scaled from their own bounds, which is an error
+    /// if the aspect ratios differ as the distances would be distorted
+    pub fn fit(self, bounds: Rect) -> anyhow::Result<Self> {
+        Ok(match self {
+            Shape::Rect(rect) => {
+                let (lower, upper) = (
+                    rect.from_box_coords((0.0, 0.0)),
+                    rect.from_box_coords((1.0, 1.0)),
+                );
+                let lower = bounds.from_box_coords((lower.x, lower.y));
+                let upper = bounds.from_box_coords((upper.x, upper.y));
+                Shape::Rect(Rect::new(lower.x, upper.x, lower.y, upper.y))
+            }
+            Shape::Polygon(corners) => Shape::Polygon(
+                corners
+                    .into_iter()
+                    .map(|corner| bounds.from_box_coords((corner.x, corner.y)))
+                    .collect(),
+            ),
+            Shape::Mask(distances) => {
+                ensure_same_aspect(distances.get_bounds(), bounds)?;
+                let scale = bounds.width() / distances.get_bounds().width();
+                let mut distances = distances.map(|val| val * scale);
+                distances.set_bounds(bounds);
+                Shape::Mask(distances)
+            }
+        })
+    }
+
+    /// the smallest rect containing the shape
+    pub fn bounds(&self) -> Rect {
+        match self {
+            Shape::Rect(rect) => *rect,
+            Shape::Polygon(corners) => Rect::from_points(corners),
+            Shape::Mask(distances) => distances.get_bounds(),
+        }
+    }
+
+    pub fn signed_distance(&self, position: Vector) -> f32 {
+        match self {
+            Shape::Rect(rect) => rect.signed_distance(position),
+            Shape::Polygon(corners) => {
+                let (dist, _) = closest_on_polygon(corners, position);
+                if winds_around(corners, position) {
+                    -dist
+                } else {
+                    dist
+                }
+            }
+            Shape::Mask(distances) => {
+                let outside = distances.get_bounds().signed_distance(position);
+                distances
+                    .get_sample(position)
+                    .map_or(outside, |dist| dist.max(outside))
+            }
+        }
+    }
+
+    /// gradient of `signed_distance`, the outward normal of the closest edge
+    pub fn signed_distance_gradient(&self, position: Vector) -> Vector {
+        match self {
+            Shape::Rect(rect) => rect.signed_distance_gradient(position),
+            Shape::Polygon(corners) => {
+                let (_, closest) = closest_on_polygon(corners, position);
+                let normal = (position - closest).try_normalize(f32::EPSILON);
+                let normal = normal.unwrap_or_else(Vector::zeros);
+                if winds_around(corners, position) {
+                    -normal
+                } else {
+                    normal
+                }
+            }
+            Shape::Mask(distances) => {
+                let bounds = distances.get_bounds();
+                match distances.get_sample(position) {
+                    Some(dist) if dist > bounds.signed_distance(position) => distances
+                        .get_gradient(position)
+                        .map_or(Vector::zeros(), |(d_x, d_y)| Vector::new(d_x, d_y)),
+                    _ => bounds.signed_distance_gradient(position),
+                }
+            }
+        }
+    }
+
+    /// true if all points of `spline` sampled `steps` times per segment are further
+    /// inside than `margin`
+    pub fn contains_spline(&self, spline: BorrowedSpline, steps: usize, margin: f32) -> bool {
+        spline.segments().all(|segment| {
+            segment
+                .pos_iter(steps)
+                .all(|position| self.signed_distance(position) < -margin)
+        })
+    }
+
+    /// the area of the shape
+    pub fn area(&self) -> f32 {
+        match self {
+            Shape::Rect(rect) => rect.width() * rect.height(),
+            Shape::Polygon(corners) => polygon_area(corners).abs(),
+            Shape::Mask(distances) => {
+                let bounds = distances.get_bounds();
+                let values = distances.values();
+                let inside = values.iter().filter(|&&dist| dist < 0.0).count();
+                inside as f32 * bounds.width() * bounds.height() / values.len() as f32
+            }
+        }
+    }
+
+    /// the outline for a `clipPath`, masks are filled row by row
+    pub fn as_svg_clip(&self) -> Path {
+        let data = match self {
+            Shape::Rect(rect) => polygon_data(
+                &[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+                    .map(|corner| rect.from_box_coords(corner)),
+            ),
+            Shape::Polygon(corners) => polygon_data(corners),
+            Shape::Mask(distances) => {
+                let bounds = distances.get_bounds();
+                let (width, height) = distances.dim();
+                let (pixel_x, pixel_y) = (
+                    bounds.width() / width as f32,
+                    bounds.height() / height as f32,
+                );
+                let origin = bounds.from_box_coords((0.0, 0.0));
+                let mut data = Data::new();
+                for j in 0..height {
+                    let mut start = None;
+                    for i in 0..=width {
+                        let inside = i < width && distances.values()[i + width * j] < 0.0;
+                        match (inside, start) {
+                            (true, None) => start = Some(i),
+                            (false, Some(first)) => {
+                                let x_min = origin.x + (first as f32 - 0.5) * pixel_x;
+                                let x_max = origin.x + (i as f32 - 0.5) * pixel_x;
+                                let y_min = origin.y + (j as f32 - 0.5) * pixel_y;
+                                let y_max = y_min + pixel_y;
+                                data = data
+                                    .move_to((x_min, y_min))
+                                    .line_to((x_max, y_min))
+                                    .line_to((x_max, y_max))
+                                    .line_to((x_min, y_max))
+                                    .close();
+                                start = None;
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                data
+            }
+        };
+        Path::new().set("d", data)
+    }
+
+    /// the outline drawn in red, masks are traced by marching squares
+    pub fn as_svg(&self, stroke_width: f32) -> Path {
+        let data = match self {
+            Shape::Rect(rect) => return rect.as_svg(stroke_width),
+            Shape::Polygon(corners) => polygon_data(corners),
+            Shape::Mask(distances) => contour_data(distances),
+        };
+        Path::new()
+            .set("fill", "none")
+            .set("stroke", "red")
+            .set("stroke-width", stroke_width)
+            .set("d", data)
+    }
+}
+
+fn polygon_data(corners: &[Vector]) -> Data {
+    let mut data = Data::new().move_to((corners[0].x, corners[0].y));
+    for corner in &corners[1..] {
+        data = data.line_to((corner.x, corner.y));
+    }
+    data.close()
+}
+
+/// the signed area, positive for counterclockwise corners
+fn polygon_area(corners: &[Vector]) -> f32 {
+    edges(corners)
+        .map(|(start, end)| start.x * end.y - end.x * start.y)
+        .sum::<f32>()
+        / 2.0
+}
+
+fn edges(corners: &[Vector]) -> impl Iterator<Item = (Vector, Vector)> + '_ {
+    corners
+        .iter()
+        .zip(corners.iter().cycle().skip(1))
+        .map(|(&start, &end)| (start, end))
+}
+
+/// the distance to the closest point on the edges and the point
+fn closest_on_polygon(corners: &[Vector], position: Vector) -> (f32, Vector) {
+    edges(corners)
+        .map(|(start, end)| {
+            let edge = end - start;
+            let t = ((position - start).dot(&edge) / edge.norm_squared().max(f32::EPSILON))
+                .clamp(0.0, 1.0);
+            let closest = start + t * edge;
+            ((position - closest).norm(), closest)
+        })
+        .fold((f32::INFINITY, position), |best, candidate| {
+            if candidate.0 < best.0 {
+                candidate
+            } else {
+                best
+            }
+        })
+}
+
+/// even odd rule
+fn winds_around(corners: &[Vector], position: Vector) -> bool {
+    edges(corners)
+        .filter(|(start, end)| {
+            (start.y > position.y) != (end.y > position.y)
+                && position.x
+                    < start.x + (position.y - start.y) / (end.y - start.y) * (end.x - start.x)
+        })
+        .count()
+        % 2
+        == 1
+}
+
+/// the euclidean distance in pixels from every pixel to the closest pixel with `kind`
+/// which has a neighbour of the other kind, by propagating the closest such pixel
+/// forwards and backwards through the grid
+fn distance_to_edge(inside: &[bool], width: usize, height: usize, kind: bool) -> Vec<f32> {
+    let (w, h) = (width as isize, height as isize);
+    let at = |i: isize, j: isize| inside[(i + w * j) as usize];
+    let mut closest: Vec<Option<(isize, isize)>> = (0..h)
+        .flat_map(|j| (0..w).map(move |i| (i, j)))
+        .map(|(i, j)| {
+            let is_edge = at(i, j) == kind
+                && [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .into_iter()
+                    .map(|(di, dj)| (i + di, j + dj))
+                    .any(|(i, j)| i >= 0 && j >= 0 && i < w && j < h && at(i, j) != kind);
+            is_edge.then_some((i, j))
+        })
+        .collect();
+    let dist = |(i, j): (isize, isize), (k, l): (isize, isize)| {
+        (((i - k).pow(2) + (j - l).pow(2)) as f32).sqrt()
+    };
+    let mut relax = |i: isize, j: isize, offsets: &[(isize, isize)]| {
+        for (di, dj) in offsets {
+            let (k, l) = (i + di, j + dj);
+            if k < 0 || l < 0 || k >= w || l >= h {
+                continue;
+            }
+            let Some(candidate) = closest[(k + w * l) as usize] else {
+                continue;
+            };
+            let current = &mut closest[(i + w * j) as usize];
+            if current.is_none_or(|current| dist((i, j), candidate) < dist((i, j), current)) {
+                *current = Some(candidate);
+            }
+        }
+    };
+    for j in 0..h {
+        for i in 0..w {
+            relax(i, j, &[(-1, -1), (0, -1), (1, -1), (-1, 0)]);
+        }
+    }
+    for j in (0..h).rev() {
+        for i in (0..w).rev() {
+            relax(i, j, &[(1, 1), (0, 1), (-1, 1), (1, 0)]);
+        }
+    }
+    // without an edge the whole grid is one kind, as far away as the grid is large
+    (0..h)
+        .flat_map(|j| (0..w).map(move |i| (i, j)))
+        .map(|(i, j)| {
+            closest[(i + w * j) as usize]
+                .map_or((w + h) as f32, |candidate| dist((i, j), candidate))
+        })
+        .collect()
+}
+
+/// the zero crossings of the signed distance between every four neighbouring samples
+fn contour_data(distances: &Samples2d<f32>) -> Data {
+    let bounds = distances.get_bounds();
+    let (width, height) = distances.dim();
+    let position = |i: f32, j: f32| bounds.from_box_coords((i / width as f32, j / height as f32));
+    let mut data = Data::new();
+    for j in 0..height.saturating_sub(1) {
+        for i in 0..width.saturating_sub(1) {
+            let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+            let mut crossings = Vec::with_capacity(4);
+            for k in 0..4 {
+                let (a, b) = (corners[k], corners[(k + 1) % 4]);
+                let value = |(i, j): (usize, usize)| distances.values()[i + width * j];
+                let (val_a, val_b) = (value(a), value(b));
+                if (val_a < 0.0) != (val_b < 0.0) {
+                    let t = val_a / (val_a - val_b);
+                    crossings.push(position(
+                        a.0 as f32 + t * (b.0 as f32 - a.0 as f32),
+                        a.1 as f32 + t * (b.1 as f32 - a.1 as f32),
+                    ));
+                }
+            }
+            // one or two pieces of the contour pass through the square
+            for pair in crossings.chunks_exact(2) {
+                data = data
+                    .move_to((pair[0].x, pair[0].y))
+                    .line_to((pair[1].x, pair[1].y));
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn polygon_and_mask_distances_agree_for_a_circle() {
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let corners: Vec<Vector> = (0..64)
+            .map(|k| {
+                let angle = k as f32 / 64.0 * std::f32::consts::TAU;
+                Vector::new(0.5 + 0.3 * angle.cos(), 0.5 + 0.3 * angle.sin())
+            })
+            .collect();
+        let polygon = Shape::Polygon(corners);
+        let image = ImageBuffer::from_fn(100, 100, |x, y| {
+            let dist = Vector::new(x as f32 / 100.0 - 0.5, y as f32 / 100.0 - 0.5).norm();
+            Luma([if dist < 0.3 { 1.0 } else { 0.0 }])
+        });
+        let mask = Shape::from_mask(&image, bounds).unwrap();
+        for shape in [&polygon, &mask] {
+            for (x, y) in [
+                (0.5, 0.5),
+                (0.6, 0.45),
+                (0.83, 0.5),
+                (0.1, 0.9),
+                (0.5, 0.15),
+            ] {
+                let position = Vector::new(x, y);
+                let exact = (position - Vector::new(0.5, 0.5)).norm() - 0.3;
+                let dist = shape.signed_distance(position);
+                assert!(
+                    (dist - exact).abs() < 0.02,
+                    "{} {} at {}",
+                    dist,
+                    exact,
+                    position
+                );
+                let radial = (position - Vector::new(0.5, 0.5)).try_normalize(0.0);
+                if let Some(radial) = radial.filter(|_| exact.abs() > 0.05) {
+                    let gradient = shape.signed_distance_gradient(position);
+                    assert!(gradient.normalize().dot(&radial) > 0.95, "{}", gradient);
+                }
+            }
+            assert!((shape.area() - 0.09 * std::f32::consts::PI).abs() < 0.01);
+        }
+        // outside of its bounds a mask is outside even if its edge pixels are inside
+        let full = Shape::from_mask(&ImageBuffer::from_pixel(10, 10, Luma([1.0])), bounds).unwrap();
+        assert!(full.signed_distance(Vector::new(0.5, 0.5)) < 0.0);
+        assert!(full.signed_distance(Vector::new(1.2, 0.5)) > 0.0);
+        assert_eq!(
+            full.signed_distance_gradient(Vector::new(1.2, 0.5)),
+            Vector::new(1.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn masks_keep_their_aspect_ratio() {
+        let wide = ImageBuffer::from_pixel(20, 10, Luma([1.0]));
+        assert!(Shape::from_mask(&wide, Rect::new(0.0, 1.0, 0.0, 1.0)).is_err());
+        let mask = Shape::from_mask(&wide, Rect::new(0.0, 2.0, 0.0, 1.0)).unwrap();
+        assert!(mask.fit(Rect::new(0.0, 1.0, 0.0, 1.0)).is_err());
+        let mask = Shape::from_mask(&wide, Rect::new(0.0, 2.0, 0.0, 1.0)).unwrap();
+        let fitted = mask.fit(Rect::new(0.0, 4.0, 0.0, 2.0)).unwrap();
+        // the distances grow with the canvas, the center is half of its height inside
+        assert!((fitted.signed_distance(Vector::new(2.0, 1.0)) + 1.0).abs() < 0.05);
+    }
+}
@@ -0,0 +1,237 @@
+use either::Either::{Left, Right};
+use random::MyRng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cell_list::CellList,
+    quad_tree::{Bounded, QuadTree, Rect},
+};
+
+/// the queries the model needs to find the neighbours of a spline
+pub trait SpatialIndex<T: Bounded> {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn insert(&mut self, val: T);
+
+    fn pop_random(&mut self, rng: &mut MyRng) -> T;
+
+    /// removes an object intersecting `bounds` for which `pred` is true
+    fn remove_where(&mut self, bounds: Rect, pred: impl Fn(&T) -> bool) -> Option<T>;
+
+    /// removes the object equal to `val`
+    fn remove(&mut self, val: &T) -> Option<T>
+    where
+        T: PartialEq;
+
+    /// every object whose box intersects `bounds`
+    fn query_intersects<'a>(&'a self, bounds: Rect) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a;
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a;
+}
+
+impl<T: Bounded> SpatialIndex<T> for QuadTree<T> {
+    fn len(&self) -> usize {
+        QuadTree::len(self)
+    }
+
+    fn insert(&mut self, val: T) {
+        QuadTree::insert(self, val)
+    }
+
+    fn pop_random(&mut self, rng: &mut MyRng) -> T {
+        QuadTree::pop_random(self, rng)
+    }
+
+    fn remove_where(&mut self, bounds: Rect, pred: impl Fn(&T) -> bool) -> Option<T> {
+        QuadTree::remove_where(self, bounds, pred)
+    }
+
+    fn remove(&mut self, val: &T) -> Option<T>
+    where
+        T: PartialEq,
+    {
+        QuadTree::remove(self, val)
+    }
+
+    fn query_intersects<'a>(&'a self, bounds: Rect) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        QuadTree::query_intersects(self, bounds)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        QuadTree::iter(self)
+    }
+}
+
+impl<T: Bounded> SpatialIndex<T> for CellList<T> {
+    fn len(&self) -> usize {
+        CellList::len(self)
+    }
+
+    fn insert(&mut self, val: T) {
+        CellList::insert(self, val)
+    }
+
+    fn pop_random(&mut self, rng: &mut MyRng) -> T {
+        CellList::pop_random(self, rng)
+    }
+
+    fn remove_where(&mut self, bounds: Rect, pred: impl Fn(&T) -> bool) -> Option<T> {
+        CellList::remove_where(self, bounds, pred)
+    }
+
+    fn remove(&mut self, val: &T) -> Option<T>
+    where
+        T: PartialEq,
+    {
+        CellList::remove_where(self, val.bounding_box(), |other| other == val)
+    }
+
+    fn query_intersects<'a>(&'a self, bounds: Rect) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        CellList::query_intersects(self, bounds)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        CellList::iter(self)
+    }
+}
+
+/// which spatial index holds the splines, written as its name in parameter files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum IndexKind {
+    /// adapts to the objects, good for few splines of very different sizes
+    #[default]
+    QuadTree,
+    /// a uniform grid, good for many short splines
+    CellList,
+}
+
+impl From<IndexKind> for String {
+    fn from(kind: IndexKind) -> Self {
+        format!("{:?}", kind)
+    }
+}
+
+impl TryFrom<String> for IndexKind {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match name.as_str() {
+            "QuadTree" => Ok(Self::QuadTree),
+            "CellList" => Ok(Self::CellList),
+            _ => Err(anyhow::anyhow!("unknown spatial index {}", name)),
+        }
+    }
+}
+
+/// one of the spatial indices chosen at runtime with `IndexKind`
+#[derive(Serialize, Deserialize)]
+pub enum AnyIndex<T: Bounded> {
+    QuadTree(QuadTree<T>),
+    CellList(CellList<T>),
+}
+
+impl<T: Bounded> AnyIndex<T> {
+    /// `cell_width` is only used by the cell list
+    pub fn from_vec(kind: IndexKind, cell_width: f32, objects: Vec<T>) -> Self {
+        match kind {
+            IndexKind::QuadTree => Self::QuadTree(QuadTree::from(objects)),
+            IndexKind::CellList => Self::CellList(CellList::from_vec(objects, cell_width)),
+        }
+    }
+}
+
+impl<T: Bounded> Default for AnyIndex<T> {
+    fn default() -> Self {
+        Self::QuadTree(QuadTree::new())
+    }
+}
+
+impl<T: Bounded> From<AnyIndex<T>> for Vec<T> {
+    fn from(val: AnyIndex<T>) -> Self {
+        match val {
+            AnyIndex::QuadTree(tree) => tree.into(),
+            AnyIndex::CellList(cells) => cells.into(),
+        }
+    }
+}
+
+impl<T: Bounded> SpatialIndex<T> for AnyIndex<T> {
+    fn len(&self) -> usize {
+        match self {
+            Self::QuadTree(tree) => tree.len(),
+            Self::CellList(cells) => cells.len(),
+        }
+    }
+
+    fn insert(&mut self, val: T) {
+        match self {
+            Self::QuadTree(tree) => tree.insert(val),
+            Self::CellList(cells) => cells.insert(val),
+        }
+    }
+
+    fn pop_random(&mut self, rng: &mut MyRng) -> T {
+        match self {
+            Self::QuadTree(tree) => tree.pop_random(rng),
+            Self::CellList(cells) => cells.pop_random(rng),
+        }
+    }
+
+    fn remove_where(&mut self, bounds: Rect, pred: impl Fn(&T) -> bool) -> Option<T> {
+        match self {
+            Self::QuadTree(tree) => tree.remove_where(bounds, pred),
+            Self::CellList(cells) => cells.remove_where(bounds, pred),
+        }
+    }
+
+    fn remove(&mut self, val: &T) -> Option<T>
+    where
+        T: PartialEq,
+    {
+        match self {
+            Self::QuadTree(tree) => SpatialIndex::remove(tree, val),
+            Self::CellList(cells) => SpatialIndex::remove(cells, val),
+        }
+    }
+
+    fn query_intersects<'a>(&'a self, bounds: Rect) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        match self {
+            Self::QuadTree(tree) => Left(tree.query_intersects(bounds)),
+            Self::CellList(cells) => Right(cells.query_intersects(bounds)),
+        }
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        match self {
+            Self::QuadTree(tree) => Left(tree.iter()),
+            Self::CellList(cells) => Right(cells.iter()),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{f32::consts::TAU, usize};
+use std::f32::consts::TAU;
 
 use nalgebra::{Matrix2x4, Matrix4, Matrix4x2, Matrix4x3, Vector2, Vector3, Vector4};
 use random::{MyRng, Rng};
@@ -15,9 +15,14 @@ use crate::{
 
 use random::rand_unit;
 
+mod geometry;
+
+#[derive(Clone)]
 pub struct Spline {
     points_and_vecs: Vec<Vector>,
     bounds: Rect,
+    // the stroke width relative to the line width of the model
+    width: f32,
 }
 
 impl Spline {
@@ -32,13 +37,14 @@ impl Spline {
             "must contain at least two points and vectors"
         );
         let mut points_and_vecs = Vec::new();
-        for (p, v) in points.into_iter().zip(vectors.into_iter()) {
+        for (p, v) in points.into_iter().zip(vectors) {
             points_and_vecs.push(p);
             points_and_vecs.push(v)
         }
         let mut this = Self {
             points_and_vecs,
             bounds: Rect::default(),
+            width: 1.0,
         };
         this.update_bounds();
         this
@@ -46,23 +52,52 @@ impl Spline {
 
     pub fn from_parts(points_and_vecs: &[Vector], bounds: Rect) -> Self {
         debug_assert!(
-            points_and_vecs.len() >= 4 && points_and_vecs.len() % 2 == 0,
+            points_and_vecs.len() >= 4 && points_and_vecs.len().is_multiple_of(2),
             "tried to create spline from invalid slice of vector."
         );
         assert!(
-            BorrowedSpline(points_and_vecs).calculate_bounds() == bounds,
+            BorrowedSpline::from_slice(points_and_vecs).calculate_bounds() == bounds,
             "bounds were incorrect"
         );
         Self {
             points_and_vecs: points_and_vecs.to_vec(),
             bounds,
+            width: 1.0,
         }
     }
 
+    pub fn from_vec(points_and_vecs: Vec<Vector>) -> Self {
+        debug_assert!(
+            points_and_vecs.len() >= 4 && points_and_vecs.len().is_multiple_of(2),
+            "tried to create spline from invalid vector."
+        );
+        let mut this = Self {
+            points_and_vecs,
+            bounds: Rect::default(),
+            width: 1.0,
+        };
+        this.update_bounds();
+        this
+    }
+
     pub fn into_vec(self) -> Vec<Vector> {
         self.points_and_vecs
     }
 
+    pub fn with_width(mut self, width: f32) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// the stroke width relative to the line width of the model
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width
+    }
+
     pub fn as_slice(&self) -> &[Vector] {
         &self.points_and_vecs
     }
@@ -127,7 +162,7 @@ impl Spline {
     }
 
     pub fn as_borrowed_spline(&self) -> BorrowedSpline<'_> {
-        BorrowedSpline(&self.points_and_vecs)
+        BorrowedSpline(&self.points_and_vecs, self.width)
     }
 }
 
@@ -193,7 +228,7 @@ impl Spline {
             (self.points_and_vecs[0] + self.points_and_vecs[self.points_and_vecs.len() - 2]) / 2.0;
         self.points_mut()
             .for_each(|point| *point = factor * (*point - origin) + origin);
-        self.vecs_mut().for_each(|vec| *vec = *vec * factor);
+        self.vecs_mut().for_each(|vec| *vec *= factor);
         self.update_bounds();
     }
 
@@ -209,34 +244,143 @@ impl Spline {
     }
 }
 
+// changing the number of segments
+impl Spline {
+    /// the first or last point with its vector, the vector points along the spline
+    pub fn end(&self, at_start: bool) -> (Vector, Vector) {
+        let len = self.points_and_vecs.len();
+        if at_start {
+            (self.points_and_vecs[0], self.points_and_vecs[1])
+        } else {
+            (self.points_and_vecs[len - 2], self.points_and_vecs[len - 1])
+        }
+    }
+
+    /// adds a segment before the start or after the end which ends in `point`
+    pub fn push_segment(&mut self, at_start: bool, point: Vector, vector: Vector) {
+        if at_start {
+            self.points_and_vecs.splice(0..0, [point, vector]);
+        } else {
+            self.points_and_vecs.extend([point, vector]);
+        }
+        self.update_bounds();
+    }
+
+    /// removes the first or last segment and returns the point and vector it had alone
+    pub fn pop_segment(&mut self, at_start: bool) -> (Vector, Vector) {
+        assert!(self.count_segments() > 1, "a spline needs one segment");
+        let removed = self.end(at_start);
+        if at_start {
+            self.points_and_vecs.drain(0..2);
+        } else {
+            self.points_and_vecs
+                .truncate(self.points_and_vecs.len() - 2);
+        }
+        self.update_bounds();
+        removed
+    }
+
+    /// the point and vector in the middle of `segment` if it was split in two halves
+    pub fn midpoint(&self, segment: usize) -> (Vector, Vector) {
+        let [p0, v0, p1, v1] = self.points_and_vecs[2 * segment..2 * segment + 4] else {
+            unreachable!()
+        };
+        // de Casteljau with the bezier control points p0, p0 + v0, p1 - v1, p1
+        let a = p0 + v0 / 2.0;
+        let b = (p0 + v0 + p1 - v1) / 2.0;
+        let c = p1 - v1 / 2.0;
+        let (d, e) = ((a + b) / 2.0, (b + c) / 2.0);
+        ((d + e) / 2.0, (e - d) / 2.0)
+    }
+
+    /// splits `segment` in two halves with de Casteljau, the curve stays the same
+    pub fn subdivide(&mut self, segment: usize) {
+        let (mid_point, mid_vector) = self.midpoint(segment);
+        self.points_and_vecs[2 * segment + 1] /= 2.0;
+        self.points_and_vecs[2 * segment + 3] /= 2.0;
+        self.points_and_vecs
+            .splice(2 * segment + 2..2 * segment + 2, [mid_point, mid_vector]);
+        self.update_bounds();
+    }
+
+    /// joins the two segments around the inner `point`, the inverse of `subdivide`
+    /// if the point lies in the middle, returns the removed point and vector
+    pub fn join_at(&mut self, point: usize) -> (Vector, Vector) {
+        assert!(
+            point > 0 && point < self.count_segments(),
+            "only inner points can be removed"
+        );
+        let removed: Vec<Vector> = self
+            .points_and_vecs
+            .drain(2 * point..2 * point + 2)
+            .collect();
+        self.points_and_vecs[2 * point - 1] *= 2.0;
+        self.points_and_vecs[2 * point + 1] *= 2.0;
+        self.update_bounds();
+        (removed[0], removed[1])
+    }
+
+    /// removes `segment` and returns the parts before and after it,
+    /// which need at least one segment each
+    pub fn split_at(self, segment: usize) -> (Spline, Spline) {
+        assert!(
+            segment > 0 && segment + 1 < self.count_segments(),
+            "both parts need a segment"
+        );
+        let mut first = self.points_and_vecs;
+        let second = first.split_off(2 * segment + 2);
+        (
+            Spline::from_vec(first).with_width(self.width),
+            Spline::from_vec(second).with_width(self.width),
+        )
+    }
+
+    /// appends `other` to the end with a new segment between them, the inverse of `split_at`,
+    /// the width is the one of `self`
+    pub fn join(mut self, other: &Spline) -> Spline {
+        self.points_and_vecs
+            .extend_from_slice(&other.points_and_vecs);
+        self.update_bounds();
+        self
+    }
+}
+
 impl Bounded for Spline {
     fn bounding_box(&self) -> Rect {
         self.bounds
     }
 }
 
-pub struct BorrowedSpline<'a>(&'a [Vector]);
+#[derive(Clone, Copy)]
+pub struct BorrowedSpline<'a>(&'a [Vector], f32);
 
 impl<'a> BorrowedSpline<'a> {
     pub fn from_slice(slice: &'a [Vector]) -> Self {
         debug_assert!(
-            slice.len() > 2 && slice.len() % 2 == 0,
+            slice.len() > 2 && slice.len().is_multiple_of(2),
             "tried create BorrowedSpline from an invalid slice len"
         );
-        Self(slice)
+        Self(slice, 1.0)
+    }
+
+    pub fn with_width(self, width: f32) -> Self {
+        Self(self.0, width)
     }
+
+    /// the stroke width relative to the line width of the model
+    pub fn width(&self) -> f32 {
+        self.1
+    }
+
     pub fn count_segments(&self) -> usize {
         self.0.len() / 2 - 1
     }
+    /// the exact box around the curve, see `Segment::tight_bounds`
     pub fn calculate_bounds(&self) -> Rect {
-        let mut bounds = self.0[0].bounding_box();
-        for i in 0..self.count_segments() {
-            let c1 = self.0[2 * i] + self.0[2 * i + 1];
-            let c2 = self.0[2 * i + 2] - self.0[2 * i + 3];
-            let p2 = self.0[2 * i + 2];
-            bounds = bounds.combine(Rect::from_points(&[c1, c2, p2]));
-        }
-        bounds
+        self.segments()
+            .map(|segment| segment.tight_bounds())
+            .reduce(Rect::combine)
+            .expect("a spline has at least one segment")
     }
 
     pub fn segments(&self) -> impl Iterator<Item = Segment> {
@@ -259,27 +403,28 @@ impl<'a> BorrowedSpline<'a> {
         path.finish().expect("paths are always valid")
     }
 
+    /// `width` is the line width, it is scaled by the width of the spline
     pub fn as_svg_path(&self, color: &'static str, width: f32) -> SvgPath {
         let mut data = Data::new().move_to((self.0[0].x, self.0[0].y));
         // i points to the start of the segment
         for i in 0..self.0.len() / 2 - 1 {
-            let c1 = self.0[2 * i as usize] + self.0[(2 * i + 1) as usize];
-            let c2 = self.0[(2 * i + 2) as usize] - self.0[(2 * i + 3) as usize];
-            let c3 = self.0[(2 * i + 2) as usize];
+            let c1 = self.0[2 * i] + self.0[2 * i + 1];
+            let c2 = self.0[2 * i + 2] - self.0[2 * i + 3];
+            let c3 = self.0[2 * i + 2];
             data.append(Command::CubicCurve(
                 Position::Absolute,
                 ((c1.x, c1.y), (c2.x, c2.y), (c3.x, c3.y)).into(),
             ));
         }
-        let path = SvgPath::new()
+
+        SvgPath::new()
             .set("fill", "none")
             .set("stroke", color)
-            .set("stroke-width", width)
-            .set("d", data);
-        path
+            .set("stroke-width", width * self.1)
+            .set("d", data)
     }
 
-    pub fn as_slice(&self) -> &[Vector] {
+    pub fn as_slice(&self) -> &'a [Vector] {
         self.0
     }
 }
@@ -428,13 +573,9 @@ impl MatrixGenerator {
     pub fn precompute_mats(steps: usize) -> Precomputed {
         Precomputed {
             steps,
-            position: Self::s_iter_end(steps).map(|s| Self::position(s)).collect(),
-            derivative: Self::s_iter_end(steps)
-                .map(|s| Self::derivative(s))
-                .collect(),
-            derivative2: Self::s_iter_end(steps)
-                .map(|s| Self::derivative2(s))
-                .collect(),
+            position: Self::s_iter_end(steps).map(Self::position).collect(),
+            derivative: Self::s_iter_end(steps).map(Self::derivative).collect(),
+            derivative2: Self::s_iter_end(steps).map(Self::derivative2).collect(),
         }
     }
 }
@@ -447,6 +588,9 @@ pub struct Precomputed {
 }
 
 impl Precomputed {
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
     pub fn position(&self) -> impl Iterator<Item = &Vector4<f32>> {
         self.position[0..self.steps - 1].iter()
     }
@@ -507,7 +651,7 @@ mod test {
         let mut ders = Group::new();
         for segment in spline.segments() {
             for (pos, mut der) in segment.pos_and_der_iter(steps) {
-                der = der / steps as f32;
+                der /= steps as f32;
                 poss.append(
                     Circle::new()
                         .set("cx", pos.x)
@@ -531,4 +675,31 @@ mod test {
         doc.append(poss);
         svg::save("test.svg", &doc).unwrap()
     }
+
+    #[test]
+    fn segment_changes_are_reversible() {
+        let mut rng = random::rng_from_seed(4);
+        let spline = Spline::new_random(Vector::new(1.0, 1.0), 0.5, 3, &mut rng);
+
+        let mut subdivided = spline.clone();
+        subdivided.subdivide(1);
+        assert_eq!(subdivided.count_segments(), 4);
+        // the curve stays the same
+        let original = spline.segments().nth(1).unwrap();
+        let (left, right) = (
+            subdivided.segments().nth(1).unwrap(),
+            subdivided.segments().nth(2).unwrap(),
+        );
+        assert!((original.position(0.25) - left.position(0.5)).norm() < 1e-5);
+        assert!((original.position(0.75) - right.position(0.5)).norm() < 1e-5);
+        let (point, vector) = subdivided.join_at(2);
+        assert_eq!((point, vector), spline.midpoint(1));
+        for (a, b) in subdivided.as_slice().iter().zip(spline.as_slice()) {
+            assert!((a - b).norm() < 1e-6);
+        }
+
+        let (first, second) = spline.clone().split_at(1);
+        assert_eq!((first.count_segments(), second.count_segments()), (1, 1));
+        assert_eq!(first.join(&second).as_slice(), spline.as_slice());
+    }
 }
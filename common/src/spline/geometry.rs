@@ -0,0 +1,586 @@
+use crate::{Vector, quad_tree::Rect};
+
+use super::{BorrowedSpline, Segment, Spline};
+
+// subdivisions after which two pieces are treated as straight lines
+const MAX_DEPTH: usize = 40;
+// tolerances relative to the size of the segments
+const RELATIVE_TOLERANCE: f32 = 1e-4;
+const NEWTON_ITERATIONS: usize = 30;
+// samples per segment to find the starting points of the newton refinement
+const STARTS: usize = 8;
+
+/// the part of a segment between two parameters of the original segment
+#[derive(Clone, Copy)]
+struct Piece {
+    points: [Vector; 4],
+    start: f32,
+    end: f32,
+}
+
+impl Piece {
+    fn hull(&self) -> Rect {
+        Rect::from_points(&self.points)
+    }
+
+    fn size(&self) -> f32 {
+        let hull = self.hull();
+        hull.width().max(hull.height())
+    }
+
+    /// the inner control points are within `tolerance` of the chord
+    fn is_flat(&self, tolerance: f32) -> bool {
+        let [a, b, c, d] = self.points;
+        let chord = d - a;
+        let len = chord.norm();
+        if len < tolerance {
+            return (b - a).norm() < tolerance && (c - a).norm() < tolerance;
+        }
+        let dist = |p: Vector| (chord.x * (p - a).y - chord.y * (p - a).x).abs() / len;
+        dist(b) < tolerance && dist(c) < tolerance
+    }
+
+    fn split(&self) -> (Self, Self) {
+        let (left, right) = de_casteljau(self.points, 0.5);
+        let mid = (self.start + self.end) / 2.0;
+        (
+            Self {
+                points: left,
+                start: self.start,
+                end: mid,
+            },
+            Self {
+                points: right,
+                start: mid,
+                end: self.end,
+            },
+        )
+    }
+
+    fn at(&self, s: f32) -> f32 {
+        self.start + s * (self.end - self.start)
+    }
+}
+
+fn de_casteljau([a, b, c, d]: [Vector; 4], s: f32) -> ([Vector; 4], [Vector; 4]) {
+    let ab = a.lerp(&b, s);
+    let bc = b.lerp(&c, s);
+    let cd = c.lerp(&d, s);
+    let abc = ab.lerp(&bc, s);
+    let bcd = bc.lerp(&cd, s);
+    let mid = abc.lerp(&bcd, s);
+    ([a, ab, abc, mid], [mid, bcd, cd, d])
+}
+
+/// the shortest distance between two rectangles, zero if they overlap
+fn rect_gap(a: &Rect, b: &Rect) -> f32 {
+    let (a_min, a_max) = (a.from_box_coords((0.0, 0.0)), a.from_box_coords((1.0, 1.0)));
+    let (b_min, b_max) = (b.from_box_coords((0.0, 0.0)), b.from_box_coords((1.0, 1.0)));
+    let dx = (b_min.x - a_max.x).max(a_min.x - b_max.x).max(0.0);
+    let dy = (b_min.y - a_max.y).max(a_min.y - b_max.y).max(0.0);
+    dx.hypot(dy)
+}
+
+fn cross(a: Vector, b: Vector) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+/// parameters of the intersection of the lines from `a` to `b` and from `c` to `d`,
+/// overlapping parallel lines meet at the middle of the overlap
+fn line_intersection(
+    a: Vector,
+    b: Vector,
+    c: Vector,
+    d: Vector,
+    tolerance: f32,
+) -> Option<(f32, f32)> {
+    let r = b - a;
+    let q = d - c;
+    let denominator = cross(r, q);
+    let slack = 1e-4;
+    if denominator.abs() > f32::EPSILON * r.norm() * q.norm() {
+        let s = cross(c - a, q) / denominator;
+        let t = cross(c - a, r) / denominator;
+        if (-slack..=1.0 + slack).contains(&s) && (-slack..=1.0 + slack).contains(&t) {
+            return Some((s.clamp(0.0, 1.0), t.clamp(0.0, 1.0)));
+        }
+        return None;
+    }
+    // parallel, they only meet if they lie on the same line
+    if r.norm() < f32::EPSILON {
+        return None;
+    }
+    if cross(r, c - a).abs() / r.norm() > tolerance {
+        return None;
+    }
+    let project = |p: Vector| (p - a).dot(&r) / r.norm_squared();
+    let (lo, hi) = {
+        let (c, d) = (project(c), project(d));
+        (c.min(d).max(0.0), c.max(d).min(1.0))
+    };
+    if lo > hi {
+        return None;
+    }
+    let s = (lo + hi) / 2.0;
+    let point = a + s * r;
+    let t = if q.norm() < f32::EPSILON {
+        0.0
+    } else {
+        (point - c).dot(&q) / q.norm_squared()
+    };
+    Some((s, t.clamp(0.0, 1.0)))
+}
+
+/// collects the intersections of the two pieces in `out`,
+/// with `first_only` it stops at the first one and returns true once it is found
+fn find_intersections(
+    a: Piece,
+    b: Piece,
+    tolerance: f32,
+    depth: usize,
+    first_only: bool,
+    out: &mut Vec<(f32, f32)>,
+) -> bool {
+    if !a.hull().add_radius(tolerance).intersects(&b.hull()) {
+        return false;
+    }
+    if depth >= MAX_DEPTH || (a.is_flat(tolerance) && b.is_flat(tolerance)) {
+        if let Some((s, t)) = line_intersection(
+            a.points[0],
+            a.points[3],
+            b.points[0],
+            b.points[3],
+            tolerance,
+        ) {
+            out.push((a.at(s), b.at(t)));
+            return first_only;
+        }
+        return false;
+    }
+    if a.size() >= b.size() {
+        let (left, right) = a.split();
+        find_intersections(left, b, tolerance, depth + 1, first_only, out)
+            || find_intersections(right, b, tolerance, depth + 1, first_only, out)
+    } else {
+        let (left, right) = b.split();
+        find_intersections(a, left, tolerance, depth + 1, first_only, out)
+            || find_intersections(a, right, tolerance, depth + 1, first_only, out)
+    }
+}
+
+/// the parameter on the line from `a` to `b` closest to `point`
+fn project_on_line(point: Vector, a: Vector, b: Vector) -> f32 {
+    let r = b - a;
+    if r.norm_squared() < f32::EPSILON {
+        return 0.0;
+    }
+    ((point - a).dot(&r) / r.norm_squared()).clamp(0.0, 1.0)
+}
+
+/// Lowers `best` to the shortest distance between the two pieces of the segments `a` and `b`.
+///
+/// The gap between the boxes of the control points is a lower bound of the distance
+/// of two pieces, so pieces which can't get closer than `best` by more than `tolerance`
+/// are skipped and the others are split until they are straight.
+fn closest_pieces(
+    (a, b): (&[Vector; 4], &[Vector; 4]),
+    (a_piece, b_piece): (Piece, Piece),
+    tolerance: f32,
+    depth: usize,
+    best: &mut (f32, (f32, f32)),
+) {
+    if rect_gap(&a_piece.hull(), &b_piece.hull()) >= best.0 - tolerance {
+        return;
+    }
+    if depth >= MAX_DEPTH || (a_piece.is_flat(tolerance) && b_piece.is_flat(tolerance)) {
+        let ([a_0, .., a_1], [b_0, .., b_1]) = (a_piece.points, b_piece.points);
+        let candidates = [
+            (0.0, project_on_line(a_0, b_0, b_1)),
+            (1.0, project_on_line(a_1, b_0, b_1)),
+            (project_on_line(b_0, a_0, a_1), 0.0),
+            (project_on_line(b_1, a_0, a_1), 1.0),
+        ];
+        for (s, t) in candidates {
+            let params = (a_piece.at(s), b_piece.at(t));
+            let dist = dist_at(a, b, params);
+            if dist < best.0 {
+                *best = (dist, params);
+            }
+        }
+        return;
+    }
+    let pairs = if a_piece.size() >= b_piece.size() {
+        let (left, right) = a_piece.split();
+        [(left, b_piece), (right, b_piece)]
+    } else {
+        let (left, right) = b_piece.split();
+        [(a_piece, left), (a_piece, right)]
+    };
+    for pieces in pairs {
+        closest_pieces((a, b), pieces, tolerance, depth + 1, best);
+    }
+}
+
+fn point_at([a, b, c, d]: &[Vector; 4], s: f32) -> Vector {
+    let u = 1.0 - s;
+    u * u * u * a + 3.0 * u * u * s * b + 3.0 * u * s * s * c + s * s * s * d
+}
+
+fn tangent_at([a, b, c, d]: &[Vector; 4], s: f32) -> Vector {
+    let u = 1.0 - s;
+    3.0 * (u * u * (b - a) + 2.0 * u * s * (c - b) + s * s * (d - c))
+}
+
+fn curvature_at([a, b, c, d]: &[Vector; 4], s: f32) -> Vector {
+    6.0 * ((1.0 - s) * (c - 2.0 * b + a) + s * (d - 2.0 * c + b))
+}
+
+/// roots of a s^2 + b s + c
+fn quadratic_roots(a: f32, b: f32, c: f32) -> Vec<f32> {
+    if a.abs() <= f32::EPSILON * (b.abs() + c.abs()) {
+        return if b == 0.0 { Vec::new() } else { vec![-c / b] };
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return Vec::new();
+    }
+    let root = discriminant.sqrt();
+    vec![(-b + root) / (2.0 * a), (-b - root) / (2.0 * a)]
+}
+
+fn dist_at(a: &[Vector; 4], b: &[Vector; 4], (s, t): (f32, f32)) -> f32 {
+    (point_at(a, s) - point_at(b, t)).norm()
+}
+
+/// the best pairs of a coarse sampling refined with `refine`
+fn nearest_params(a: &[Vector; 4], b: &[Vector; 4]) -> (f32, f32) {
+    let params = |i: usize| i as f32 / STARTS as f32;
+    let positions: Vec<Vector> = (0..=STARTS).map(|i| point_at(a, params(i))).collect();
+    let o_positions: Vec<Vector> = (0..=STARTS).map(|j| point_at(b, params(j))).collect();
+    let mut starts: Vec<(f32, (f32, f32))> = positions
+        .iter()
+        .enumerate()
+        .flat_map(|(i, p)| {
+            o_positions
+                .iter()
+                .enumerate()
+                .map(move |(j, q)| ((p - q).norm(), (params(i), params(j))))
+        })
+        .collect();
+    starts.sort_by(|x, y| x.0.partial_cmp(&y.0).expect("distances are finite"));
+    starts
+        .into_iter()
+        .take(4)
+        .map(|(_, params)| refine(a, b, params))
+        .map(|params| (dist_at(a, b, params), params))
+        .min_by(|x, y| x.0.partial_cmp(&y.0).expect("distances are finite"))
+        .map(|(_, params)| params)
+        .expect("there are starting points")
+}
+
+/// newton steps on half the squared distance, a parameter at an end stays there
+/// while the gradient points outwards
+fn refine(a: &[Vector; 4], b: &[Vector; 4], (mut s, mut t): (f32, f32)) -> (f32, f32) {
+    for _ in 0..NEWTON_ITERATIONS {
+        let d = point_at(a, s) - point_at(b, t);
+        let (da, db) = (tangent_at(a, s), tangent_at(b, t));
+        let (dda, ddb) = (curvature_at(a, s), curvature_at(b, t));
+        let g = [d.dot(&da), -d.dot(&db)];
+        let mut h = [
+            [da.norm_squared() + d.dot(&dda), -da.dot(&db)],
+            [-da.dot(&db), db.norm_squared() - d.dot(&ddb)],
+        ];
+        let det = h[0][0] * h[1][1] - h[0][1] * h[1][0];
+        if h[0][0] <= 0.0 || det <= 0.0 {
+            // fall back to gauss newton which is always positive semi definite
+            h = [
+                [da.norm_squared(), -da.dot(&db)],
+                [-da.dot(&db), db.norm_squared()],
+            ];
+        }
+        let fixed_s = (s <= 0.0 && g[0] > 0.0) || (s >= 1.0 && g[0] < 0.0);
+        let fixed_t = (t <= 0.0 && g[1] > 0.0) || (t >= 1.0 && g[1] < 0.0);
+        let damping = 1e-6 * (h[0][0] + h[1][1]) + f32::MIN_POSITIVE;
+        let step = match (fixed_s, fixed_t) {
+            (true, true) => break,
+            (true, false) => (0.0, g[1] / (h[1][1] + damping)),
+            (false, true) => (g[0] / (h[0][0] + damping), 0.0),
+            (false, false) => {
+                let (p, q, r) = (h[0][0] + damping, h[0][1], h[1][1] + damping);
+                let det = p * r - q * q;
+                ((r * g[0] - q * g[1]) / det, (p * g[1] - q * g[0]) / det)
+            }
+        };
+        if step.0.abs() < 1e-6 && step.1.abs() < 1e-6 {
+            break;
+        }
+        // halve the step until the distance decreases, without decrease it converged
+        let current = d.norm();
+        let Some(next) = (0..10)
+            .map(|i| {
+                let factor = 0.5_f32.powi(i);
+                (
+                    (s - factor * step.0).clamp(0.0, 1.0),
+                    (t - factor * step.1).clamp(0.0, 1.0),
+                )
+            })
+            .find(|&next| dist_at(a, b, next) < current)
+        else {
+            break;
+        };
+        (s, t) = next;
+    }
+    (s, t)
+}
+
+// the bezier curve, the control points are p, p + v, q - w and q
+impl Segment {
+    pub fn control_points(&self) -> [Vector; 4] {
+        let column = |i: usize| Vector::new(self.0[(0, i)], self.0[(1, i)]);
+        let (p, v, q, w) = (column(0), column(1), column(2), column(3));
+        [p, p + v, q - w, q]
+    }
+
+    /// the smallest rectangle containing the control points and with them the segment
+    pub fn hull(&self) -> Rect {
+        Rect::from_points(&self.control_points())
+    }
+
+    /// the smallest rectangle containing the segment, it goes through the end points
+    /// and the extrema where a component of the derivative vanishes
+    pub fn tight_bounds(&self) -> Rect {
+        let points = self.control_points();
+        let mut extrema = vec![points[0], points[3]];
+        for axis in 0..2 {
+            let [d0, d1, d2] = [0, 1, 2].map(|i| points[i + 1][axis] - points[i][axis]);
+            // the derivative divided by three
+            for s in quadratic_roots(d0 - 2.0 * d1 + d2, 2.0 * (d1 - d0), d0) {
+                if 0.0 < s && s < 1.0 {
+                    extrema.push(point_at(&points, s));
+                }
+            }
+        }
+        Rect::from_points(&extrema)
+    }
+
+    fn piece(&self) -> Piece {
+        Piece {
+            points: self.control_points(),
+            start: 0.0,
+            end: 1.0,
+        }
+    }
+
+    fn tolerance(&self, other: &Self) -> f32 {
+        let hull = self.hull().combine(other.hull());
+        (RELATIVE_TOLERANCE * hull.width().max(hull.height())).max(f32::EPSILON)
+    }
+
+    /// Parameters of the points where the two segments cross or touch.
+    ///
+    /// The segments are split in half until the bounding boxes of the control points
+    /// don't overlap anymore or both pieces are straight, where the lines are intersected.
+    pub fn intersections(&self, other: &Self) -> Vec<(f32, f32)> {
+        let tolerance = self.tolerance(other);
+        let mut out = Vec::new();
+        find_intersections(self.piece(), other.piece(), tolerance, 0, false, &mut out);
+        out.sort_by(|a, b| a.partial_cmp(b).expect("parameters are finite"));
+        out.dedup_by(|a, b| (a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3);
+        out
+    }
+
+    /// true if the segments cross or touch, stops at the first intersection
+    pub fn intersects(&self, other: &Self) -> bool {
+        let tolerance = self.tolerance(other);
+        find_intersections(
+            self.piece(),
+            other.piece(),
+            tolerance,
+            0,
+            true,
+            &mut Vec::new(),
+        )
+    }
+
+    /// Parameters of the closest points of the two segments.
+    ///
+    /// The best pairs of a coarse sampling are refined with newton steps on the squared
+    /// distance, so the minimum can also lie at an end point. Then every pair of pieces
+    /// whose boxes could still be closer is subdivided, which finds the global minimum
+    /// up to the tolerance of the intersections.
+    pub fn closest_params(&self, other: &Self) -> (f32, f32) {
+        match self.intersections(other).first() {
+            Some(&params) => params,
+            None => self.closest_apart(other).1,
+        }
+    }
+
+    /// the distance and the parameters of the closest points of two segments
+    /// which don't intersect
+    fn closest_apart(&self, other: &Self) -> (f32, (f32, f32)) {
+        let (a, b) = (self.control_points(), other.control_points());
+        let params = nearest_params(&a, &b);
+        let mut best = (dist_at(&a, &b, params), params);
+        closest_pieces(
+            (&a, &b),
+            (self.piece(), other.piece()),
+            self.tolerance(other),
+            0,
+            &mut best,
+        );
+        if best.1 != params {
+            let params = refine(&a, &b, best.1);
+            let dist = dist_at(&a, &b, params);
+            if dist < best.0 {
+                best = (dist, params);
+            }
+        }
+        best
+    }
+
+    /// the shortest distance up to the tolerance of `closest_params`,
+    /// zero if the segments intersect
+    pub fn distance(&self, other: &Self) -> f32 {
+        if self.intersects(other) {
+            return 0.0;
+        }
+        self.closest_apart(other).0
+    }
+}
+
+impl BorrowedSpline<'_> {
+    /// the exact shortest distance between the two splines
+    pub fn distance(&self, other: &BorrowedSpline) -> f32 {
+        // the pairs of segments ordered by the distance of their boxes, which is a lower bound
+        let mut pairs: Vec<(f32, Segment, Segment)> = self
+            .segments()
+            .flat_map(|segment| {
+                other.segments().map(move |o_segment| {
+                    let gap = rect_gap(&segment.hull(), &o_segment.hull());
+                    (gap, Segment(segment.0), o_segment)
+                })
+            })
+            .collect();
+        pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("distances are finite"));
+        let mut min = f32::INFINITY;
+        for (gap, segment, o_segment) in pairs {
+            if gap >= min {
+                break;
+            }
+            min = min.min(segment.distance(&o_segment));
+        }
+        min
+    }
+
+    /// true if any segment of `self` crosses or touches a segment of `other`
+    pub fn crosses(&self, other: &BorrowedSpline) -> bool {
+        self.segments().any(|segment| {
+            other
+                .segments()
+                .any(|o_segment| segment.intersects(&o_segment))
+        })
+    }
+}
+
+impl Spline {
+    pub fn distance(&self, other: &BorrowedSpline) -> f32 {
+        self.as_borrowed_spline().distance(other)
+    }
+
+    pub fn crosses(&self, other: &BorrowedSpline) -> bool {
+        self.as_borrowed_spline().crosses(other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use random::Rng;
+
+    use super::*;
+
+    fn segment(points: [Vector; 4]) -> Segment {
+        let [a, b, c, d] = points;
+        Segment::from_slice(&[a, b - a, d, d - c])
+    }
+
+    #[test]
+    fn crossings_between_samples_are_found() {
+        // two flat arcs crossing twice close to their ends,
+        // the crossings lie between the points of a coarse sampling
+        let a = segment([
+            Vector::new(0.0, 0.0),
+            Vector::new(0.3, 0.1),
+            Vector::new(0.7, 0.1),
+            Vector::new(1.0, 0.0),
+        ]);
+        let b = segment([
+            Vector::new(0.0, 0.06),
+            Vector::new(0.3, -0.04),
+            Vector::new(0.7, -0.04),
+            Vector::new(1.0, 0.06),
+        ]);
+        assert!(a.shortest_dist(&b, 4) > 0.01);
+        let crossings = a.intersections(&b);
+        assert_eq!(crossings.len(), 2);
+        for (s, t) in crossings {
+            assert!((a.position(s) - b.position(t)).norm() < 1e-3);
+        }
+        assert_eq!(a.distance(&b), 0.0);
+    }
+
+    #[test]
+    fn tight_bounds_touch_the_curve() {
+        let a = segment([
+            Vector::new(0.0, 0.0),
+            Vector::new(0.3, 1.0),
+            Vector::new(1.2, -0.5),
+            Vector::new(1.0, 0.2),
+        ]);
+        let bounds = a.tight_bounds();
+        assert!(a.hull().contains(&bounds));
+        let samples: Vec<Vector> = (0..=1000).map(|i| a.position(i as f32 / 1000.0)).collect();
+        let sampled = Rect::from_points(&samples);
+        assert!(bounds.add_radius(1e-5).contains(&sampled));
+        assert!(sampled.add_radius(1e-4).contains(&bounds));
+    }
+
+    #[test]
+    fn distance_is_exact() {
+        let a = segment([
+            Vector::new(0.0, 0.0),
+            Vector::new(0.3, 0.5),
+            Vector::new(0.7, 0.5),
+            Vector::new(1.0, 0.0),
+        ]);
+        // the curve peaks at 0.375 in the middle
+        let b = segment([
+            Vector::new(0.0, 1.0),
+            Vector::new(0.3, 1.0),
+            Vector::new(0.7, 1.0),
+            Vector::new(1.0, 1.0),
+        ]);
+        assert!(!a.intersects(&b));
+        assert!((a.distance(&b) - 0.625).abs() < 1e-5);
+        assert!(a.distance(&b) <= a.shortest_dist(&b, 100));
+
+        // closest to an end point
+        let c = segment([
+            Vector::new(2.0, 0.0),
+            Vector::new(2.5, 0.0),
+            Vector::new(3.0, 0.0),
+            Vector::new(3.5, 0.0),
+        ]);
+        assert!((a.distance(&c) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn distance_is_never_above_a_fine_sampling() {
+        let mut rng = random::rng_from_seed(8);
+        let mut point = || Vector::new(rng.random_range(-1.0..1.0), rng.random_range(-1.0..1.0));
+        for _ in 0..50 {
+            let a = segment([point(), point(), point(), point()]);
+            let b = segment([point(), point(), point(), point()]);
+            assert!(a.distance(&b) <= a.shortest_dist(&b, 100) + 1e-4);
+        }
+    }
+}
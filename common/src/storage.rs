@@ -1,9 +1,7 @@
-use std::{
-    ops::{Index, IndexMut},
-    usize,
-};
+use std::ops::{Index, IndexMut, Range};
 
 use nalgebra::Matrix2x4;
+use serde::{Deserialize, Serialize};
 use tiny_skia::{Color, Paint, Pixmap, Stroke, Transform};
 
 use crate::{
@@ -12,10 +10,23 @@ use crate::{
     spline::{BorrowedSpline, Segment, Spline},
 };
 
+#[derive(Serialize, Deserialize)]
 pub struct SplineStorage {
     points_and_vecs: Vec<Vector>,
     spline_starts: Vec<usize>,
+    widths: SplineInfo<f32>,
+    removed: SplineInfo<bool>,
+    // list indices of the removed splines, their slots are reused by `add_spline`
+    free: Vec<usize>,
+    // increased whenever a slot is removed, a reference with another generation is stale
+    generations: SplineInfo<u32>,
+    // the generation of new slots, after `compact` it is above every earlier generation
+    first_generation: u32,
+    #[serde(skip)]
     empty_slot: Option<SplineRef>,
+    // the splines checked out by transactions
+    #[serde(skip)]
+    checked_out: Vec<SplineRef>,
 }
 
 impl Clone for SplineStorage {
@@ -23,57 +34,299 @@ impl Clone for SplineStorage {
         Self {
             points_and_vecs: self.points_and_vecs.clone(),
             spline_starts: self.spline_starts.clone(),
+            widths: self.widths.clone(),
+            removed: self.removed.clone(),
+            free: self.free.clone(),
+            generations: self.generations.clone(),
+            first_generation: self.first_generation,
             empty_slot: None,
+            checked_out: Vec::new(),
         }
     }
 }
 
+impl Default for SplineStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SplineStorage {
     pub fn new() -> Self {
         Self {
             points_and_vecs: Vec::new(),
             spline_starts: Vec::new(),
+            widths: SplineInfo(Vec::new()),
+            removed: SplineInfo(Vec::new()),
+            free: Vec::new(),
+            generations: SplineInfo(Vec::new()),
+            first_generation: 0,
             empty_slot: None,
+            checked_out: Vec::new(),
         }
     }
 
+    /// the range in `points_and_vecs` of the slot with `list_idx`
+    fn slot(&self, list_idx: usize) -> Range<usize> {
+        let end = self
+            .spline_starts
+            .get(list_idx + 1)
+            .copied()
+            .unwrap_or(self.points_and_vecs.len());
+        self.spline_starts[list_idx]..end
+    }
+
+    fn slot_ref(&self, list_idx: usize) -> SplineRef {
+        let slot = self.slot(list_idx);
+        let spline = BorrowedSpline::from_slice(&self.points_and_vecs[slot.clone()]);
+        SplineRef {
+            storage_idx: slot.start as u32,
+            segments: spline.count_segments() as u32,
+            list_idx: list_idx as u32,
+            generation: self.generations.0[list_idx],
+            bounds: spline.calculate_bounds(),
+        }
+    }
+
+    /// false if the spline of the reference was removed or the storage was compacted since
+    pub fn is_valid(&self, spline_ref: &SplineRef) -> bool {
+        self.generations
+            .0
+            .get(spline_ref.list_idx as usize)
+            .is_some_and(|&generation| generation == spline_ref.generation)
+            && !self.removed[spline_ref]
+    }
+
+    /// number of splines which are not removed
+    pub fn count_splines(&self) -> usize {
+        self.spline_starts.len() - self.free.len()
+    }
+
+    /// reuses the slot of a removed spline with the same number of segments if there is one,
+    /// otherwise the spline is appended
     pub fn add_spline(&mut self, spline: Spline) -> SplineRef {
+        let segments = spline.count_segments();
+        if let Some(pos) = self
+            .free
+            .iter()
+            .position(|&list_idx| self.slot(list_idx).len() == 2 * (segments + 1))
+        {
+            let list_idx = self.free.swap_remove(pos);
+            self.removed.0[list_idx] = false;
+            // the generation was already increased by `remove`
+            let slot_ref = self.slot_ref(list_idx);
+            return self.overwrite(slot_ref, spline);
+        }
         let bounds = spline.bounding_box();
         let storage_idx = self.points_and_vecs.len();
         let list_idx = self.spline_starts.len();
         self.spline_starts.push(storage_idx);
-        let segments = spline.count_segments();
+        self.widths.0.push(spline.width());
+        self.removed.0.push(false);
+        self.generations.0.push(self.first_generation);
         self.points_and_vecs.append(&mut spline.into_vec());
         SplineRef {
             storage_idx: storage_idx as u32,
             segments: segments as u32,
             list_idx: list_idx as u32,
+            generation: self.first_generation,
             bounds,
         }
     }
 
+    /// removes the spline, its slot stays in the storage until a spline
+    /// with the same number of segments is added or the storage is compacted
+    pub fn remove(&mut self, spline_ref: SplineRef) {
+        debug_assert!(self.is_valid(&spline_ref), "tried to remove a stale spline");
+        self.removed[&spline_ref] = true;
+        self.generations[&spline_ref] += 1;
+        self.free.push(spline_ref.list_idx as usize);
+    }
+
+    /// the share of the storage taken by the slots of removed splines
+    pub fn fragmentation(&self) -> f32 {
+        if self.points_and_vecs.is_empty() {
+            return 0.0;
+        }
+        let free: usize = self
+            .free
+            .iter()
+            .map(|&list_idx| self.slot(list_idx).len())
+            .sum();
+        free as f32 / self.points_and_vecs.len() as f32
+    }
+
+    /// Drops the slots of the removed splines so the remaining splines are contiguous again.
+    ///
+    /// `refs` have to be all references to the storage, they are returned in the same order
+    /// pointing to the moved splines. Every other reference becomes stale
+    /// and spline infos have to be made again.
+    pub fn compact(&mut self, refs: Vec<SplineRef>) -> Vec<SplineRef> {
+        assert!(
+            self.empty_slot.is_none() && self.checked_out.is_empty(),
+            "tried to compact with a checked out spline"
+        );
+        assert_eq!(
+            refs.len(),
+            self.count_splines(),
+            "not all references were given"
+        );
+        let next_generation = self.generations.iter().max().map_or(0, |max| max + 1);
+        let mut new_idx = vec![0; self.spline_starts.len()];
+        let mut compacted = SplineStorage::new();
+        compacted.first_generation = next_generation;
+        for (list_idx, new) in new_idx.iter_mut().enumerate() {
+            if self.removed.0[list_idx] {
+                continue;
+            }
+            *new = compacted.spline_starts.len();
+            let slot = self.slot(list_idx);
+            compacted
+                .spline_starts
+                .push(compacted.points_and_vecs.len());
+            compacted.widths.0.push(self.widths.0[list_idx]);
+            compacted.removed.0.push(false);
+            compacted.generations.0.push(next_generation);
+            compacted
+                .points_and_vecs
+                .extend_from_slice(&self.points_and_vecs[slot]);
+        }
+        let refs = refs
+            .into_iter()
+            .map(|spline_ref| {
+                assert!(
+                    self.is_valid(&spline_ref),
+                    "tried to compact a stale reference"
+                );
+                compacted.slot_ref(new_idx[spline_ref.list_idx as usize])
+            })
+            .collect();
+        *self = compacted;
+        refs
+    }
+
+    /// removes the spline which was checked out with `read`
+    pub fn remove_spline(&mut self, spline: Spline) {
+        let this_ref = self
+            .empty_slot
+            .take()
+            .expect("tried to remove spline but there was no empty slot");
+        debug_assert!(spline.count_segments() as u32 == this_ref.segments);
+        self.remove(this_ref)
+    }
+
+    /// new references to every spline, e.g. to build a second quad tree over a cloned storage
+    pub fn make_refs(&self) -> Vec<SplineRef> {
+        (0..self.spline_starts.len())
+            .filter(|&list_idx| !self.removed.0[list_idx])
+            .map(|list_idx| self.slot_ref(list_idx))
+            .collect()
+    }
+
+    /// a new reference to the spline of `spline_ref`, e.g. to remove it from an index
+    /// while `spline_ref` is borrowed from it
+    pub fn make_ref(&self, spline_ref: &SplineRef) -> SplineRef {
+        self.slot_ref(spline_ref.list_idx as usize)
+    }
+
     pub fn shrink_to_fit(&mut self) {
         self.points_and_vecs.shrink_to_fit();
         self.spline_starts.shrink_to_fit();
+        self.widths.0.shrink_to_fit();
+        self.removed.0.shrink_to_fit();
+        self.free.shrink_to_fit();
+        self.generations.0.shrink_to_fit();
     }
 
     pub fn read(&mut self, spline: SplineRef) -> Spline {
+        debug_assert!(self.is_valid(&spline), "tried to read a stale spline");
         let owned = Spline::from_parts(
             &self.points_and_vecs[spline.storage_idx as usize
                 ..(spline.storage_idx + 2 * (spline.segments + 1)) as usize],
             spline.bounds,
-        );
+        )
+        .with_width(self.widths[&spline]);
         self.empty_slot = Some(spline);
         owned
     }
 
+    /// true if the spline is checked out by `read` or by a transaction
     pub fn is_empty(&self, spline: &SplineRef) -> bool {
-        if let Some(ref this_spline) = self.empty_slot.as_ref() {
-            if spline.storage_idx == this_spline.storage_idx {
-                return true;
+        let same_slot = |this_spline: &SplineRef| {
+            spline.storage_idx == this_spline.storage_idx
+                && spline.generation == this_spline.generation
+        };
+        self.empty_slot.as_ref().is_some_and(same_slot) || self.checked_out.iter().any(same_slot)
+    }
+
+    /// Checks out all splines of `refs` together, independent of the spline checked out
+    /// by `read` and of other transactions.
+    ///
+    /// The transaction has to be finished with `commit` or `rollback`,
+    /// until then `is_empty` is true for its splines.
+    pub fn checkout(&mut self, refs: Vec<SplineRef>) -> Transaction {
+        let mut slots = Vec::with_capacity(refs.len());
+        let mut originals = Vec::with_capacity(refs.len());
+        for spline_ref in refs {
+            debug_assert!(
+                self.is_valid(&spline_ref),
+                "tried to check out a stale spline"
+            );
+            debug_assert!(
+                !self.is_empty(&spline_ref),
+                "tried to check out a spline twice"
+            );
+            slots.push(spline_ref.list_idx);
+            originals.push(self.get_owned(&spline_ref));
+            self.checked_out.push(spline_ref);
+        }
+        Transaction {
+            proposals: originals.iter().cloned().map(Some).collect(),
+            slots,
+            originals,
+            added: Vec::new(),
+        }
+    }
+
+    fn take_checked_out(&mut self, list_idx: u32) -> SplineRef {
+        let pos = self
+            .checked_out
+            .iter()
+            .position(|spline_ref| spline_ref.list_idx == list_idx)
+            .expect("the spline of the transaction is checked out");
+        self.checked_out.swap_remove(pos)
+    }
+
+    /// writes the proposals of the transaction, removes the splines it removed
+    /// and adds the new ones, returns the references of every spline which is left
+    pub fn commit(&mut self, transaction: Transaction) -> Vec<SplineRef> {
+        let Transaction {
+            slots,
+            proposals,
+            added,
+            ..
+        } = transaction;
+        let mut refs = Vec::with_capacity(slots.len() + added.len());
+        for (list_idx, proposal) in slots.into_iter().zip(proposals) {
+            let spline_ref = self.take_checked_out(list_idx);
+            match proposal {
+                Some(spline) => refs.push(self.replace(spline_ref, spline)),
+                None => self.remove(spline_ref),
             }
         }
-        false
+        for spline in added {
+            refs.push(self.add_spline(spline));
+        }
+        refs
+    }
+
+    /// returns the unchanged references of the checked out splines
+    pub fn rollback(&mut self, transaction: Transaction) -> Vec<SplineRef> {
+        transaction
+            .slots
+            .into_iter()
+            .map(|list_idx| self.take_checked_out(list_idx))
+            .collect()
     }
 
     pub fn revalidate_ref(&mut self, spline: Spline) -> SplineRef {
@@ -98,12 +351,49 @@ impl SplineStorage {
                     .expect("tried to overwrite spline but there was no empty slot")
                     .segments,
         );
-        let mut this_ref = self.empty_slot.take().unwrap();
-        this_ref.bounds = spline.bounding_box();
+        let this_ref = self.empty_slot.take().unwrap();
+        self.overwrite(this_ref, spline)
+    }
+
+    /// writes the checked out spline back, with a different number of segments
+    /// it moves to another slot
+    pub fn replace_spline(&mut self, spline: Spline) -> SplineRef {
+        let this_ref = self
+            .empty_slot
+            .take()
+            .expect("tried to replace spline but there was no empty slot");
+        self.replace(this_ref, spline)
+    }
+
+    /// like `overwrite` but `spline` can have a different number of segments
+    pub fn replace(&mut self, spline_ref: SplineRef, spline: Spline) -> SplineRef {
+        if spline.count_segments() as u32 == spline_ref.segments {
+            return self.overwrite(spline_ref, spline);
+        }
+        self.remove(spline_ref);
+        self.add_spline(spline)
+    }
+
+    /// writes `spline` into the slot of `spline_ref` without checking it out first
+    pub fn overwrite(&mut self, mut spline_ref: SplineRef, spline: Spline) -> SplineRef {
+        debug_assert!(
+            self.is_valid(&spline_ref),
+            "tried to overwrite a stale spline"
+        );
+        debug_assert!(spline.count_segments() as u32 == spline_ref.segments);
+        spline_ref.bounds = spline.bounding_box();
+        self.widths[&spline_ref] = spline.width();
         for (i, val) in spline.into_vec().into_iter().enumerate() {
-            self.points_and_vecs[i + this_ref.storage_idx as usize] = val
+            self.points_and_vecs[i + spline_ref.storage_idx as usize] = val
         }
-        this_ref
+        spline_ref
+    }
+
+    /// spline infos have an entry for every slot, including the slots of removed splines,
+    /// this adds the entries for slots appended since `info` was made
+    pub fn grow_spline_info<T: Default>(&self, info: &mut SplineInfo<T>) {
+        info.0
+            .resize_with(self.spline_starts.len(), Default::default)
     }
 
     pub fn default_spline_info<T: Default>(&self) -> SplineInfo<T> {
@@ -118,36 +408,32 @@ impl SplineStorage {
     }
 
     pub fn make_spline_info<T>(&self, func: impl Fn(BorrowedSpline<'_>) -> T) -> SplineInfo<T> {
-        let mut vec = Vec::with_capacity(self.spline_starts.len());
-        for i in 0..self.spline_starts.len() - 1 {
-            let start = self.spline_starts[i];
-            let end = self.spline_starts[i + 1];
-            vec.push(func(BorrowedSpline::from_slice(
-                &self.points_and_vecs[start..end],
-            )))
-        }
-        vec.push(func(BorrowedSpline::from_slice(
-            &self.points_and_vecs[*self
-                .spline_starts
-                .last()
-                .expect("spline starts is never empty")
-                ..(self.points_and_vecs.len())],
-        )));
-        SplineInfo(vec)
+        SplineInfo(
+            (0..self.spline_starts.len())
+                .map(|list_idx| {
+                    func(BorrowedSpline::from_slice(
+                        &self.points_and_vecs[self.slot(list_idx)],
+                    ))
+                })
+                .collect(),
+        )
+    }
+
+    /// number of segments in every slot, including the slots of removed splines
+    fn count_all_segments(&self) -> usize {
+        // a slot with n segments holds n + 1 points and n + 1 vectors
+        self.points_and_vecs.len() / 2 - self.spline_starts.len()
     }
 
     pub fn default_segement_info<T: Default>(&self) -> SegmentInfo<T> {
         SegmentInfo(
-            (0..self.points_and_vecs.len() - self.spline_starts.len())
+            (0..self.count_all_segments())
                 .map(|_| Default::default())
                 .collect(),
         )
     }
     pub fn new_segment_info<T: Clone>(&self, val: T) -> SegmentInfo<T> {
-        SegmentInfo(vec![
-            val;
-            self.points_and_vecs.len() - self.spline_starts.len()
-        ])
+        SegmentInfo(vec![val; self.count_all_segments()])
     }
 
     pub fn make_segment_info<T>(&self, func: impl Fn(Segment) -> T) -> SegmentInfo<T> {
@@ -157,6 +443,10 @@ impl SplineStorage {
 
 impl SplineStorage {
     pub fn get_segments(&self, idx: &SplineRef) -> impl Iterator<Item = Segment> {
+        debug_assert!(
+            self.is_valid(idx),
+            "tried to get the segments of a stale spline"
+        );
         debug_assert!(
             (idx.storage_idx + (idx.segments + 1) * 2) as usize <= self.points_and_vecs.len(),
             "spline with idx {} out of bounds, storage_len: {}",
@@ -164,29 +454,74 @@ impl SplineStorage {
             self.points_and_vecs.len()
         );
         self.points_and_vecs
-            [idx.storage_idx as usize..(idx.storage_idx + 2 * idx.segments) as usize]
+            [idx.storage_idx as usize..(idx.storage_idx + 2 * (idx.segments + 1)) as usize]
             .windows(4)
             .step_by(2)
-            .map(|slice| Segment::from_slice(slice))
+            .map(Segment::from_slice)
     }
 
-    pub fn get_spline(&self, idx: &SplineRef) -> BorrowedSpline {
+    pub fn get_spline(&self, idx: &SplineRef) -> BorrowedSpline<'_> {
+        debug_assert!(self.is_valid(idx), "tried to get a stale spline");
         BorrowedSpline::from_slice(
             &self.points_and_vecs
-                [idx.storage_idx as usize..(idx.storage_idx + 2 * idx.segments) as usize],
+                [idx.storage_idx as usize..(idx.storage_idx + 2 * (idx.segments + 1)) as usize],
         )
+        .with_width(self.widths[idx])
     }
 
-    pub fn all_splines(&self) -> impl Iterator<Item = BorrowedSpline> {
-        SplineIter {
-            points_and_vecs: &self.points_and_vecs,
-            spline_starts: &self.spline_starts[1..],
-            next_start: 0,
-        }
+    pub fn get_owned(&self, idx: &SplineRef) -> Spline {
+        Spline::from_parts(self.get_spline(idx).as_slice(), idx.bounds).with_width(self.widths[idx])
+    }
+
+    /// a reference to every segment of the spline with the exact box around the segment
+    pub fn make_segment_refs(&self, spline_ref: &SplineRef) -> Vec<SegmentRef> {
+        self.get_spline(spline_ref)
+            .segments()
+            .enumerate()
+            .map(|(segment, points)| SegmentRef {
+                spline: spline_ref.duplicate(),
+                segment: segment as u32,
+                bounds: points.tight_bounds(),
+            })
+            .collect()
     }
 
+    pub fn get_segment(&self, idx: &SegmentRef) -> Segment {
+        debug_assert!(self.is_valid(&idx.spline), "tried to get a stale segment");
+        let start = (idx.spline.storage_idx + 2 * idx.segment) as usize;
+        Segment::from_slice(&self.points_and_vecs[start..start + 4])
+    }
+
+    /// every spline which is not removed
+    pub fn all_splines(&self) -> impl Iterator<Item = BorrowedSpline<'_>> {
+        self.all_splines_with_info(&self.widths)
+            .map(|(spline, _)| spline)
+    }
+
+    /// every spline which is not removed with its entry in `info`
+    pub fn all_splines_with_info<'a, T>(
+        &'a self,
+        info: &'a SplineInfo<T>,
+    ) -> impl Iterator<Item = (BorrowedSpline<'a>, &'a T)> {
+        (0..self.spline_starts.len())
+            .filter(|&list_idx| !self.removed.0[list_idx])
+            .map(move |list_idx| {
+                (
+                    BorrowedSpline::from_slice(&self.points_and_vecs[self.slot(list_idx)])
+                        .with_width(self.widths.0[list_idx]),
+                    &info.0[list_idx],
+                )
+            })
+    }
+
+    /// the stroke widths relative to the line width of the model
+    pub fn widths(&self) -> &SplineInfo<f32> {
+        &self.widths
+    }
+
+    /// the segments of every slot, including the slots of removed splines
     pub fn all_segments(&self) -> impl Iterator<Item = Segment> {
-        (0..self.points_and_vecs.len() / 2 - 1).flat_map(|i| {
+        (0..(self.points_and_vecs.len() / 2).saturating_sub(1)).flat_map(|i| {
             let st_idx = i * 2;
             // if the end point of the window I'm trying to construct is the start of a segment
             // then that window is no valid segment and should be skipped
@@ -220,7 +555,7 @@ impl SplineStorage {
                 &spline.as_ts_path(),
                 &paint,
                 &Stroke {
-                    width: line_width,
+                    width: line_width * spline.width(),
                     ..Default::default()
                 },
                 Transform::from_scale(scaling_factor, scaling_factor),
@@ -231,45 +566,72 @@ impl SplineStorage {
     }
 }
 
-pub struct SplineIter<'a> {
-    points_and_vecs: &'a [Vector],
-    spline_starts: &'a [usize],
-    next_start: usize,
+/// Splines checked out of the storage together with `SplineStorage::checkout`.
+///
+/// Every spline keeps its original and a proposal which can be changed or removed,
+/// nothing is written to the storage until the transaction is committed.
+pub struct Transaction {
+    slots: Vec<u32>,
+    originals: Vec<Spline>,
+    proposals: Vec<Option<Spline>>,
+    added: Vec<Spline>,
 }
 
-impl<'a> Iterator for SplineIter<'a> {
-    type Item = BorrowedSpline<'a>;
+impl Transaction {
+    /// number of checked out splines
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
 
-    // FIXME: this can be done nicer
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.spline_starts.is_empty() {
-            if self.next_start == 0 {
-                return None;
-            }
-            let item = Some(BorrowedSpline::from_slice(
-                &self.points_and_vecs[self.next_start..],
-            ));
-            self.next_start = 0;
-            return item;
-        }
-        let next = self.spline_starts[0];
-        self.spline_starts = &self.spline_starts[1..];
-        let item = BorrowedSpline::from_slice(&self.points_and_vecs[self.next_start..next]);
-        self.next_start = next;
-        Some(item)
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn original(&self, idx: usize) -> &Spline {
+        &self.originals[idx]
+    }
+
+    pub fn originals(&self) -> &[Spline] {
+        &self.originals
+    }
+
+    /// the proposal for the spline at `idx`, `None` if it is removed
+    pub fn proposal(&self, idx: usize) -> Option<&Spline> {
+        self.proposals[idx].as_ref()
+    }
+
+    pub fn propose(&mut self, idx: usize, spline: Spline) {
+        self.proposals[idx] = Some(spline)
+    }
+
+    /// the spline at `idx` is removed on commit
+    pub fn remove(&mut self, idx: usize) {
+        self.proposals[idx] = None
+    }
+
+    /// `spline` is added to the storage on commit
+    pub fn add(&mut self, spline: Spline) {
+        self.added.push(spline)
+    }
+
+    /// every spline as it will be after the commit, including the added ones
+    pub fn proposed(&self) -> impl Iterator<Item = &Spline> {
+        self.proposals.iter().flatten().chain(&self.added)
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct SplineRef {
     storage_idx: u32,
     segments: u32,
     list_idx: u32,
+    generation: u32,
     bounds: Rect,
 }
 
 impl PartialEq for SplineRef {
     fn eq(&self, other: &Self) -> bool {
-        self.storage_idx == other.storage_idx
+        self.storage_idx == other.storage_idx && self.generation == other.generation
     }
 }
 
@@ -277,12 +639,12 @@ impl Eq for SplineRef {}
 
 impl PartialOrd for SplineRef {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.storage_idx.partial_cmp(&other.storage_idx)
+        Some(self.cmp(other))
     }
 }
 impl Ord for SplineRef {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.storage_idx.cmp(&other.storage_idx)
+        (self.storage_idx, self.generation).cmp(&(other.storage_idx, other.generation))
     }
 }
 
@@ -292,6 +654,34 @@ impl Bounded for SplineRef {
     }
 }
 
+impl SplineRef {
+    // only the storage hands out references, so copies stay inside this module
+    fn duplicate(&self) -> SplineRef {
+        SplineRef { ..*self }
+    }
+}
+
+/// a segment of a stored spline, its box is the exact box around the segment
+#[derive(Serialize, Deserialize)]
+pub struct SegmentRef {
+    spline: SplineRef,
+    segment: u32,
+    bounds: Rect,
+}
+
+impl SegmentRef {
+    pub fn spline(&self) -> &SplineRef {
+        &self.spline
+    }
+}
+
+impl Bounded for SegmentRef {
+    fn bounding_box(&self) -> Rect {
+        self.bounds
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SplineInfo<T>(Vec<T>);
 
 impl<T> SplineInfo<T> {
@@ -320,10 +710,23 @@ impl<T> Index<&SplineRef> for SplineInfo<T> {
 
 pub struct SegmentInfo<T>(Vec<T>);
 
+impl SplineRef {
+    /// the entries of the spline in a `SegmentInfo`, which has no gaps between the slots
+    fn segment_range(&self) -> Range<usize> {
+        let start = (self.storage_idx / 2 - self.list_idx) as usize;
+        start..start + self.segments as usize
+    }
+}
+
+impl<T> SegmentInfo<T> {
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.0.iter()
+    }
+}
+
 impl<T> IndexMut<&SplineRef> for SegmentInfo<T> {
     fn index_mut(&mut self, index: &SplineRef) -> &mut Self::Output {
-        let start = index.storage_idx - index.list_idx;
-        &mut self.0[start as usize..(start + index.segments) as usize]
+        &mut self.0[index.segment_range()]
     }
 }
 
@@ -331,7 +734,192 @@ impl<T> Index<&SplineRef> for SegmentInfo<T> {
     type Output = [T];
 
     fn index(&self, index: &SplineRef) -> &Self::Output {
-        let start = index.storage_idx - index.list_idx;
-        &self.0[start as usize..(start + index.segments) as usize]
+        &self.0[index.segment_range()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use random::{MyRng, Rng};
+
+    use super::*;
+
+    fn line(start: f32, segments: usize) -> Spline {
+        Spline::new(
+            (0..=segments)
+                .map(|i| Vector::new(start + i as f32, 0.0))
+                .collect(),
+            vec![Vector::new(0.3, 0.0); segments + 1],
+        )
+    }
+
+    fn random_spline(rng: &mut MyRng) -> Spline {
+        let segments = rng.random_range(1..5);
+        let mut random_vectors = || {
+            (0..=segments)
+                .map(|_| Vector::new(rng.random(), rng.random()))
+                .collect()
+        };
+        Spline::new(random_vectors(), random_vectors())
+    }
+
+    #[test]
+    fn segments_and_infos_match_the_slots() {
+        assert_eq!(SplineStorage::new().all_segments().count(), 0);
+        for seed in 0..30 {
+            let mut rng = random::rng_from_seed(seed);
+            let mut storage = SplineStorage::new();
+            let mut live: Vec<(SplineRef, Vec<Vector>)> = Vec::new();
+            for _ in 0..60 {
+                if !live.is_empty() && rng.random_bool(0.4) {
+                    let (spline_ref, _) = live.swap_remove(rng.random_range(0..live.len()));
+                    storage.remove(spline_ref);
+                } else {
+                    let spline = random_spline(&mut rng);
+                    let vectors = spline.as_slice().to_vec();
+                    live.push((storage.add_spline(spline), vectors));
+                }
+            }
+            live.sort_by_key(|(spline_ref, _)| spline_ref.list_idx);
+
+            let splines: Vec<&[Vector]> = storage.all_splines().map(|s| s.as_slice()).collect();
+            let expected: Vec<&[Vector]> = live.iter().map(|(_, v)| v.as_slice()).collect();
+            assert_eq!(splines, expected, "seed {}", seed);
+
+            // every slot, including the removed ones, split into segments
+            let slot_segments: Vec<[Vector; 4]> = (0..storage.spline_starts.len())
+                .flat_map(|list_idx| {
+                    BorrowedSpline::from_slice(&storage.points_and_vecs[storage.slot(list_idx)])
+                        .segments()
+                        .map(|segment| segment.control_points())
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            let segments: Vec<[Vector; 4]> = storage
+                .all_segments()
+                .map(|segment| segment.control_points())
+                .collect();
+            assert_eq!(segments, slot_segments, "seed {}", seed);
+            assert_eq!(storage.new_segment_info(0).iter().count(), segments.len());
+            assert_eq!(
+                storage.default_segement_info::<u8>().iter().count(),
+                segments.len()
+            );
+
+            let info = storage.make_segment_info(|segment| segment.control_points());
+            for (spline_ref, _) in &live {
+                let expected: Vec<[Vector; 4]> = storage
+                    .get_segments(spline_ref)
+                    .map(|segment| segment.control_points())
+                    .collect();
+                assert_eq!(info[spline_ref], expected, "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn removed_slots_are_reused() {
+        let mut storage = SplineStorage::new();
+        let first = storage.add_spline(line(0.0, 1));
+        let second = storage.add_spline(line(10.0, 2));
+        storage.add_spline(line(20.0, 1));
+        let second_idx = second.storage_idx;
+        storage.remove(second);
+        storage.remove(first);
+        assert_eq!(storage.count_splines(), 1);
+        assert_eq!(storage.make_refs().len(), 1);
+        assert_eq!(storage.all_splines().count(), 1);
+
+        // a spline with two segments fits into the slot of the second spline
+        let reused = storage.add_spline(line(30.0, 2));
+        assert_eq!(reused.storage_idx, second_idx);
+        assert_eq!(
+            storage.get_spline(&reused).as_slice()[0],
+            Vector::new(30.0, 0.0)
+        );
+        // there is no free slot with three segments
+        let appended = storage.add_spline(line(40.0, 3));
+        assert_eq!(appended.list_idx, 3);
+        assert_eq!(storage.count_splines(), 3);
+        assert_eq!(storage.all_splines().count(), 3);
+    }
+
+    #[test]
+    fn transactions_commit_and_roll_back() {
+        let mut storage = SplineStorage::new();
+        for i in 0..4 {
+            storage.add_spline(line(10.0 * i as f32, 1));
+        }
+        let mut refs = storage.make_refs().into_iter();
+        let first = storage.read(refs.next().unwrap());
+        let mut transaction = storage.checkout(vec![refs.next().unwrap(), refs.next().unwrap()]);
+        let untouched = storage.checkout(vec![refs.next().unwrap()]);
+        assert!(storage.make_refs().iter().all(|p| storage.is_empty(p)));
+
+        transaction.propose(0, line(50.0, 2));
+        transaction.remove(1);
+        transaction.add(line(60.0, 1));
+        assert_eq!(transaction.proposed().count(), 2);
+        let committed = storage.commit(transaction);
+        assert_eq!(committed.len(), 2);
+        assert!(committed.iter().all(|p| !storage.is_empty(p)));
+
+        let rolled_back = storage.rollback(untouched);
+        assert!(!storage.is_empty(&rolled_back[0]));
+        assert_eq!(storage.get_spline(&rolled_back[0]).as_slice()[0].x, 30.0);
+        storage.revalidate_ref(first);
+        let starts: Vec<f32> = storage
+            .all_splines()
+            .map(|spline| spline.as_slice()[0].x)
+            .collect();
+        assert_eq!(starts.len(), 4);
+        for start in [0.0, 30.0, 50.0, 60.0] {
+            assert!(starts.contains(&start));
+        }
+    }
+
+    #[test]
+    fn compaction_makes_old_refs_stale() {
+        let mut storage = SplineStorage::new();
+        for i in 0..4 {
+            storage.add_spline(line(10.0 * i as f32, i + 1));
+        }
+        // refs are not clone, `make_refs` hands out a second set
+        let old = storage.make_refs();
+        let mut refs = storage.make_refs().into_iter();
+        let (first, second, third, fourth) = (
+            refs.next().unwrap(),
+            refs.next().unwrap(),
+            refs.next().unwrap(),
+            refs.next().unwrap(),
+        );
+        storage.remove(second);
+        assert!(!storage.is_valid(&old[1]));
+        // the slot is reused but the old reference stays stale
+        let reused = storage.add_spline(line(50.0, 2));
+        assert!(!storage.is_valid(&old[1]));
+        assert!(storage.is_valid(&reused));
+        // only the current reference to the slot is checked out
+        let spline = storage.read(storage.make_ref(&reused));
+        assert!(storage.is_empty(&reused));
+        assert!(!storage.is_empty(&old[1]));
+        storage.revalidate_ref(spline);
+
+        storage.remove(third);
+        assert!(storage.fragmentation() > 0.0);
+        let compacted = storage.compact(vec![fourth, reused, first]);
+        assert_eq!(storage.fragmentation(), 0.0);
+        assert_eq!(storage.count_splines(), 3);
+        assert!(old.iter().all(|spline_ref| !storage.is_valid(spline_ref)));
+        assert!(
+            compacted
+                .iter()
+                .all(|spline_ref| storage.is_valid(spline_ref))
+        );
+        let starts: Vec<f32> = compacted
+            .iter()
+            .map(|spline_ref| storage.get_spline(spline_ref).as_slice()[0].x)
+            .collect();
+        assert_eq!(starts, vec![30.0, 50.0, 0.0]);
     }
 }
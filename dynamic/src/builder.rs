@@ -0,0 +1,124 @@
+use super::DynamicParameters;
+
+pub struct ParamBuilder {
+    time_step: Option<f32>,
+    max_displacement: Option<f32>,
+    steps: Option<usize>,
+    density: Option<f32>,
+    damping: Option<f32>,
+    log_interval: Option<usize>,
+    svg_interval: Option<usize>,
+
+    save_parameters: bool,
+    save_start_svg: bool,
+    save_step_svg: bool,
+    save_end_svg: bool,
+    make_plots: bool,
+}
+
+impl ParamBuilder {
+    pub fn build(self) -> DynamicParameters {
+        DynamicParameters {
+            time_step: self.time_step.unwrap_or(0.000001),
+            max_displacement: self.max_displacement.unwrap_or(0.0005),
+            steps: self.steps.unwrap_or(2000),
+            density: self.density.unwrap_or(1.0),
+            damping: self.damping.unwrap_or(10000.0),
+            log_interval: self.log_interval.unwrap_or(10),
+            svg_interval: self.svg_interval.unwrap_or(200),
+
+            save_parameters: self.save_parameters,
+            save_start_svg: self.save_start_svg,
+            save_step_svg: self.save_step_svg,
+            save_end_svg: self.save_end_svg,
+            make_plots: self.make_plots,
+        }
+    }
+    pub fn time_step(mut self, time_step: f32) -> Self {
+        self.time_step = Some(time_step);
+        self
+    }
+    pub fn max_displacement(mut self, max_displacement: f32) -> Self {
+        self.max_displacement = Some(max_displacement);
+        self
+    }
+    pub fn steps(mut self, steps: usize) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+    pub fn density(mut self, density: f32) -> Self {
+        self.density = Some(density);
+        self
+    }
+    pub fn damping(mut self, damping: f32) -> Self {
+        self.damping = Some(damping);
+        self
+    }
+    pub fn log_interval(mut self, log_interval: usize) -> Self {
+        self.log_interval = Some(log_interval);
+        self
+    }
+    pub fn svg_interval(mut self, svg_interval: usize) -> Self {
+        self.svg_interval = Some(svg_interval);
+        self
+    }
+    pub fn set_make_plots(mut self) -> Self {
+        self.make_plots = true;
+        self
+    }
+    pub fn unset_make_plots(mut self) -> Self {
+        self.make_plots = false;
+        self
+    }
+    pub fn set_save_parameters(mut self) -> Self {
+        self.save_parameters = true;
+        self
+    }
+    pub fn unset_save_parameters(mut self) -> Self {
+        self.save_parameters = false;
+        self
+    }
+    pub fn set_save_start_svg(mut self) -> Self {
+        self.save_start_svg = true;
+        self
+    }
+    pub fn unset_save_start_svg(mut self) -> Self {
+        self.save_start_svg = false;
+        self
+    }
+    pub fn set_save_step_svg(mut self) -> Self {
+        self.save_step_svg = true;
+        self
+    }
+    pub fn unset_save_step_svg(mut self) -> Self {
+        self.save_step_svg = false;
+        self
+    }
+    pub fn set_save_end_svg(mut self) -> Self {
+        self.save_end_svg = true;
+        self
+    }
+    pub fn unset_save_end_svg(mut self) -> Self {
+        self.save_end_svg = false;
+        self
+    }
+}
+
+impl Default for ParamBuilder {
+    fn default() -> Self {
+        Self {
+            make_plots: true,
+            save_parameters: true,
+            save_start_svg: false,
+            save_step_svg: false,
+            save_end_svg: true,
+            time_step: None,
+            max_displacement: None,
+            steps: None,
+            density: None,
+            damping: None,
+            log_interval: None,
+            svg_interval: None,
+        }
+    }
+}
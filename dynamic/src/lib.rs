@@ -0,0 +1,531 @@
+use std::fs;
+use std::io::Write;
+
+use anyhow::ensure;
+use common::spline::Precomputed;
+use common::storage::SplineInfo;
+use common::{CLEAR_LINE, Energy, Spline, Vector, plt};
+use nalgebra::{DMatrix, Matrix2x4};
+use ron::ser::{PrettyConfig, to_string_pretty};
+use serde::{Deserialize, Serialize};
+
+mod builder;
+
+use builder::ParamBuilder;
+
+#[derive(Serialize, Deserialize)]
+pub struct DynamicParameters {
+    time_step: f32,
+    max_displacement: f32,
+    steps: usize,
+    density: f32,
+    damping: f32,
+    log_interval: usize,
+    svg_interval: usize,
+    make_plots: bool,
+    save_parameters: bool,
+    save_start_svg: bool,
+    save_step_svg: bool,
+    save_end_svg: bool,
+}
+
+impl DynamicParameters {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> ParamBuilder {
+        Default::default()
+    }
+}
+
+/// Molecular dynamics on the energy landscape of a `monte_carlo::Model`.
+///
+/// The splines are integrated with velocity Verlet using the equations of motion
+/// from the Lagrangian in the report: the kinetic energy `1/2 rho ∫ |dr/dt|^2 |r'| ds`
+/// gives a configuration dependent mass matrix per spline and the velocity dependent
+/// terms `d/dt(dM/dq') - dT/dq`, the potential is the total energy of the Monte Carlo model.
+pub struct Model {
+    system: monte_carlo::Model,
+    velocities: SplineInfo<Vec<Vector>>,
+    accelerations: SplineInfo<Vec<Vector>>,
+    params: DynamicParameters,
+    energies: Vec<Energy>,
+    kinetic_energies: Vec<f32>,
+}
+
+impl Model {
+    /// starts with all splines at rest
+    pub fn new(system: monte_carlo::Model, params: DynamicParameters) -> Self {
+        let storage = system.get_storage();
+        let velocities =
+            storage.make_spline_info(|spline| vec![Vector::zeros(); spline.as_slice().len()]);
+        let accelerations =
+            storage.make_spline_info(|spline| vec![Vector::zeros(); spline.as_slice().len()]);
+        let mut this = Self {
+            system,
+            velocities,
+            accelerations,
+            params,
+            energies: Vec::new(),
+            kinetic_energies: Vec::new(),
+        };
+        this.update_accelerations(false);
+        this
+    }
+}
+
+// the inertial terms of the Lagrangian
+fn segment_mat(coords: &[Vector], segment: usize) -> Matrix2x4<f32> {
+    Matrix2x4::from_columns(&coords[2 * segment..2 * segment + 4])
+}
+
+/// returns the mass matrix of a spline and the velocity dependent generalized forces
+/// `dT/dq - (dM/dt) dq/dt`
+fn inertia(
+    precomp: &Precomputed,
+    density: f32,
+    positions: &[Vector],
+    velocities: &[Vector],
+) -> (DMatrix<f32>, Vec<Vector>) {
+    let ds = 1.0 / precomp.steps() as f32;
+    let mut mass = DMatrix::zeros(positions.len(), positions.len());
+    let mut forces = vec![Vector::zeros(); positions.len()];
+    for segment in 0..positions.len() / 2 - 1 {
+        let pos_mat = segment_mat(positions, segment);
+        let vel_mat = segment_mat(velocities, segment);
+        for (basis, der_basis) in precomp.position().zip(precomp.derivative()) {
+            let der = pos_mat * der_basis;
+            let der_norm = der.norm();
+            let vel = vel_mat * basis;
+            // time derivative of |r'|
+            let stretch_rate = (vel_mat * der_basis).dot(&der) / der_norm;
+            for a in 0..4 {
+                for b in 0..4 {
+                    mass[(2 * segment + a, 2 * segment + b)] +=
+                        density * basis[a] * basis[b] * der_norm * ds;
+                }
+                forces[2 * segment + a] += density
+                    * ds
+                    * (vel.norm_squared() / 2.0 * der_basis[a] * der / der_norm
+                        - basis[a] * stretch_rate * vel);
+            }
+        }
+    }
+    (mass, forces)
+}
+
+fn kinetic_energy(
+    precomp: &Precomputed,
+    density: f32,
+    positions: &[Vector],
+    velocities: &[Vector],
+) -> f32 {
+    let ds = 1.0 / precomp.steps() as f32;
+    let mut energy = 0.0;
+    for segment in 0..positions.len() / 2 - 1 {
+        let pos_mat = segment_mat(positions, segment);
+        let vel_mat = segment_mat(velocities, segment);
+        for (basis, der_basis) in precomp.position().zip(precomp.derivative()) {
+            energy += (vel_mat * basis).norm_squared() * (pos_mat * der_basis).norm();
+        }
+    }
+    density * energy * ds / 2.0
+}
+
+fn acceleration(
+    precomp: &Precomputed,
+    params: &DynamicParameters,
+    positions: &[Vector],
+    velocities: &[Vector],
+    forces: &[Vector],
+) -> Vec<Vector> {
+    let (mass, inertial_forces) = inertia(precomp, params.density, positions, velocities);
+    let rhs = DMatrix::from_fn(positions.len(), 2, |i, axis| {
+        forces[i][axis] + inertial_forces[i][axis]
+    });
+    let solution = match mass.clone().cholesky() {
+        Some(cholesky) => cholesky.solve(&rhs),
+        // degenerate splines (e.g. with vanishing derivative) fall back to the diagonal
+        None => DMatrix::from_fn(positions.len(), 2, |i, axis| rhs[(i, axis)] / mass[(i, i)]),
+    };
+    velocities
+        .iter()
+        .enumerate()
+        .map(|(i, vel)| Vector::new(solution[(i, 0)], solution[(i, 1)]) - params.damping * vel)
+        .collect()
+}
+
+impl Model {
+    /// recalculates the accelerations at the current positions,
+    /// with `predict` the velocities are extrapolated by one time step first
+    /// and the velocity half step of velocity Verlet is completed
+    fn update_accelerations(&mut self, predict: bool) {
+        let dt = self.params.time_step;
+        let precomp = self.system.get_precomputed();
+        let storage = self.system.get_storage();
+        for spline_ref in self.system.spline_refs() {
+            let positions = storage.get_spline(spline_ref).as_slice();
+            let old_acc = &self.accelerations[spline_ref];
+            let velocities: Vec<Vector> = if predict {
+                self.velocities[spline_ref]
+                    .iter()
+                    .zip(old_acc)
+                    .map(|(v, a)| v + dt * a)
+                    .collect()
+            } else {
+                self.velocities[spline_ref].clone()
+            };
+
+            let forces: Vec<Vector> = self
+                .system
+                .gradient(spline_ref)
+                .tot()
+                .into_iter()
+                .map(|grad| -grad)
+                .collect();
+            if forces.iter().any(|f| !f.x.is_finite() || !f.y.is_finite()) {
+                // the spline touches the boundary, it is held in place
+                self.velocities[spline_ref]
+                    .iter_mut()
+                    .for_each(|v| *v = Vector::zeros());
+                self.accelerations[spline_ref]
+                    .iter_mut()
+                    .for_each(|a| *a = Vector::zeros());
+                continue;
+            }
+
+            let new_acc = acceleration(precomp, &self.params, positions, &velocities, &forces);
+            if new_acc.iter().any(|a| !a.x.is_finite() || !a.y.is_finite()) {
+                // a degenerate spline, same as above
+                self.velocities[spline_ref]
+                    .iter_mut()
+                    .for_each(|v| *v = Vector::zeros());
+                self.accelerations[spline_ref]
+                    .iter_mut()
+                    .for_each(|a| *a = Vector::zeros());
+                continue;
+            }
+            if predict {
+                for ((v, a_old), a_new) in self.velocities[spline_ref]
+                    .iter_mut()
+                    .zip(old_acc)
+                    .zip(&new_acc)
+                {
+                    *v += dt / 2.0 * (a_old + a_new)
+                }
+            }
+            self.accelerations[spline_ref] = new_acc;
+        }
+    }
+
+    pub fn step(&mut self) {
+        let dt = self.params.time_step;
+        let max_displacement = self.params.max_displacement;
+        let shape = self.system.get_shape();
+        let steps = self.system.get_precomputed().steps();
+        let velocities = &mut self.velocities;
+        let accelerations = &self.accelerations;
+        self.system.update_splines(|spline_ref, spline| {
+            let mut displacements: Vec<Vector> = velocities[spline_ref]
+                .iter()
+                .zip(&accelerations[spline_ref])
+                .map(|(v, a)| dt * v + dt * dt / 2.0 * a)
+                .collect();
+            let largest = displacements.iter().map(|d| d.norm()).fold(0.0, f32::max);
+            if largest > max_displacement {
+                // limits the step like LAMMPS' nve/limit, this keeps the stiff
+                // interaction and bending forces of overlapping splines from exploding
+                let factor = max_displacement / largest;
+                displacements.iter_mut().for_each(|d| *d *= factor);
+                velocities[spline_ref].iter_mut().for_each(|v| *v *= factor);
+            }
+            let moved = Spline::from_vec(
+                spline
+                    .as_slice()
+                    .iter()
+                    .zip(displacements)
+                    .map(|(q, d)| q + d)
+                    .collect(),
+            )
+            .with_width(spline.width());
+            if shape.contains_spline(moved.as_borrowed_spline(), steps, 0.0) {
+                moved
+            } else {
+                // inelastic collision with the boundary
+                velocities[spline_ref]
+                    .iter_mut()
+                    .for_each(|v| *v = Vector::zeros());
+                spline
+            }
+        });
+        self.update_accelerations(true);
+    }
+
+    pub fn calc_kinetic_energy(&self) -> f32 {
+        let storage = self.system.get_storage();
+        self.system
+            .spline_refs()
+            .map(|spline_ref| {
+                kinetic_energy(
+                    self.system.get_precomputed(),
+                    self.params.density,
+                    storage.get_spline(spline_ref).as_slice(),
+                    &self.velocities[spline_ref],
+                )
+            })
+            .sum()
+    }
+
+    pub fn log_energies(&mut self) {
+        let energy = self.system.calc_tot_energy();
+        self.energies.push(energy);
+        self.kinetic_energies.push(self.calc_kinetic_energy());
+    }
+}
+
+impl Model {
+    fn print_status(&self, step: usize) -> anyhow::Result<()> {
+        print!(
+            "{}running step {:>5}/{}",
+            CLEAR_LINE, step, self.params.steps
+        );
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+
+    pub fn run(mut self) -> anyhow::Result<()> {
+        let log_dir = self.system.get_log_dir().to_path_buf();
+        if self.params.save_parameters {
+            self.system.save_parameters()?;
+            fs::write(
+                log_dir.join("dynamic_parameters.ron"),
+                to_string_pretty(&self.params, PrettyConfig::default())?,
+            )?
+        }
+        if self.params.save_start_svg {
+            self.system.save_svg_doc("img_start.svg")?
+        }
+        ensure!(
+            self.system.calc_tot_energy().is_finite(),
+            "initial energy needs to be finite"
+        );
+        self.log_energies();
+
+        let start = cpu_time::ProcessTime::now();
+        for step in 1..=self.params.steps {
+            self.print_status(step)?;
+            self.step();
+            // an interval of 0 never logs
+            if self.params.log_interval != 0 && step % self.params.log_interval == 0 {
+                self.log_energies()
+            }
+            if self.params.save_step_svg
+                && self.params.svg_interval != 0
+                && step % self.params.svg_interval == 0
+            {
+                self.system.save_svg_doc(format!("img_{}.svg", step))?
+            }
+        }
+        let cpu_duration = start.elapsed();
+
+        if self.params.save_end_svg {
+            self.system.save_svg_doc("img_end.svg")?
+        }
+        if self.params.make_plots {
+            self.make_all_plots("Molecular Dynamics")?
+        }
+        fs::write(
+            log_dir.join("log.txt"),
+            format!("took {:.3}s", cpu_duration.as_secs_f32()),
+        )?;
+        println!("\nFinished Running");
+        Ok(())
+    }
+
+    pub fn make_all_plots(&self, caption: &str) -> anyhow::Result<()> {
+        let log_dir = self.system.get_log_dir();
+        plt::simple_line(
+            &self
+                .energies
+                .iter()
+                .zip(&self.kinetic_energies)
+                .map(|(energy, kinetic)| energy.tot() + kinetic)
+                .collect::<Vec<_>>(),
+            caption,
+            log_dir.join("tot.png"),
+        )?;
+        plt::simple_line(&self.kinetic_energies, caption, log_dir.join("kinetic.png"))?;
+        plt::divergent_chart(
+            &self.energies,
+            &self.system.energy_names(),
+            caption,
+            log_dir.join("all.png"),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::{Interpolation, Rect, Shape};
+    use monte_carlo::ModelParameters;
+
+    use super::*;
+
+    fn system(seed: u64, dir: &str) -> monte_carlo::Model {
+        system_in(seed, dir, None)
+    }
+
+    fn system_in(seed: u64, dir: &str, shape: Option<Shape>) -> monte_carlo::Model {
+        let params = ModelParameters::new()
+            .spline_count(5)
+            .segment_len(0.05)
+            .interaction_radius(0.05)
+            .precision(8)
+            // with a smooth potential the forces are the derivatives of the energy
+            .interpolation(Interpolation::Bicubic)
+            .seed(seed)
+            .unset_make_plots()
+            .build();
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let mut builder = monte_carlo::Model::new()
+            .potential_from_fn(|pos| pos.x * pos.y, bounds, (50, 50))
+            .add_params(params)
+            .log_dir(std::env::temp_dir().join(dir));
+        if let Some(shape) = shape {
+            builder = builder.canvas_shape(shape);
+        }
+        builder.build().unwrap()
+    }
+
+    fn quiet() -> ParamBuilder {
+        DynamicParameters::new()
+            .unset_make_plots()
+            .unset_save_parameters()
+            .unset_save_end_svg()
+    }
+
+    #[test]
+    fn energy_is_conserved_without_damping() {
+        let params = quiet()
+            .damping(0.0)
+            .time_step(1e-7)
+            .max_displacement(1.0)
+            .build();
+        let mut model = Model::new(system(1, "linewise_dynamic_nve_test"), params);
+        let total =
+            |model: &mut Model| model.system.calc_tot_energy().tot() + model.calc_kinetic_energy();
+        let start = total(&mut model);
+        let mut largest_kinetic: f32 = 0.0;
+        let mut largest_drift: f32 = 0.0;
+        for _ in 0..200 {
+            model.step();
+            largest_kinetic = largest_kinetic.max(model.calc_kinetic_energy());
+            largest_drift = largest_drift.max((total(&mut model) - start).abs());
+        }
+        // most of the potential energy turns into kinetic energy on the way
+        assert!(largest_kinetic > 0.5 * start);
+        assert!(
+            largest_drift < 0.05 * start,
+            "{} of {}",
+            largest_drift,
+            start
+        );
+    }
+
+    #[test]
+    fn splines_collide_with_the_canvas_shape() {
+        let (corner, apex) = (Vector::new(0.1, 0.1), Vector::new(0.5, 0.9));
+        let triangle = vec![corner, Vector::new(0.9, 0.1), apex];
+        let mut system = system_in(
+            4,
+            "linewise_dynamic_shape_test",
+            Some(Shape::Polygon(triangle)),
+        );
+        // the first spline is moved right next to the left edge, inside its bounding box
+        let outward = Vector::new(corner.y - apex.y, apex.x - corner.x).normalize();
+        let mut first = true;
+        system.update_splines(|_, mut spline| {
+            if std::mem::take(&mut first) {
+                let outermost = spline
+                    .segments()
+                    .flat_map(|segment| segment.pos_iter(8).collect::<Vec<_>>())
+                    .map(|position| (position - corner).dot(&outward))
+                    .fold(f32::MIN, f32::max);
+                spline.translate(-(outermost + 0.005) * outward);
+            }
+            spline
+        });
+        let shape = system.get_shape();
+        let params = quiet()
+            .damping(0.0)
+            .time_step(1e-5)
+            .max_displacement(0.01)
+            .build();
+        let mut model = Model::new(system, params);
+        let first_ref = model.system.spline_refs().next().unwrap();
+        let storage = model.system.get_storage();
+        assert!(shape.contains_spline(storage.get_spline(first_ref), 8, 0.0));
+        // it flies out of the triangle but not out of its bounding box, without
+        // the boundary force holding it back
+        model.velocities[first_ref]
+            .iter_mut()
+            .for_each(|v| *v = 1e4 * outward);
+        model.accelerations[first_ref]
+            .iter_mut()
+            .for_each(|a| *a = Vector::zeros());
+        model.step();
+        let storage = model.system.get_storage();
+        for spline_ref in model.system.spline_refs() {
+            assert!(shape.contains_spline(storage.get_spline(spline_ref), 8, 0.0));
+        }
+    }
+
+    #[test]
+    fn zero_intervals_never_log() {
+        let params = quiet()
+            .steps(3)
+            .log_interval(0)
+            .svg_interval(0)
+            .set_save_step_svg()
+            .build();
+        let model = Model::new(system(3, "linewise_dynamic_interval_test"), params);
+        model.run().unwrap();
+    }
+
+    #[test]
+    fn splines_with_infinite_forces_stay_frozen() {
+        let mut system = system(2, "linewise_dynamic_frozen_test");
+        // the first spline is pushed over the edge, its boundary energy is infinite
+        let edge = system.get_bounds().from_box_coords((1.0, 0.5));
+        let mut first = true;
+        system.update_splines(|_, mut spline| {
+            if std::mem::take(&mut first) {
+                spline.translate(edge - spline.as_slice()[0]);
+            }
+            spline
+        });
+        let coords = |model: &Model| -> Vec<Vec<Vector>> {
+            let storage = model.system.get_storage();
+            model
+                .system
+                .spline_refs()
+                .map(|spline_ref| storage.get_spline(spline_ref).as_slice().to_vec())
+                .collect()
+        };
+        let mut model = Model::new(system, quiet().build());
+        let before = coords(&model);
+        for _ in 0..20 {
+            model.step();
+        }
+        let after = coords(&model);
+        let frozen = after.iter().position(|spline| spline[0] == edge).unwrap();
+        assert!(before.contains(&after[frozen]));
+        let frozen_ref = model.system.spline_refs().nth(frozen).unwrap();
+        assert!(
+            model.velocities[frozen_ref]
+                .iter()
+                .all(|v| *v == Vector::zeros())
+        );
+        // the others do move
+        assert!(after.iter().any(|spline| !before.contains(spline)));
+    }
+}
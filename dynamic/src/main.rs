@@ -1,38 +1,91 @@
-use common::{Spline, Vector};
+use std::fs;
+use std::path::PathBuf;
 
-struct Model {
-    _pos: Spline,
-    _vel: Spline,
+use clap::Parser;
+use common::Interpolation;
+
+use dynamic::{DynamicParameters, Model};
+use monte_carlo::ModelParameters;
+
+/// Integrates the splines fitted to an image with molecular dynamics
+#[derive(Parser)]
+struct Args {
+    /// the image the splines are fitted to
+    #[arg(default_value = "./in/fern.jpg")]
+    image: PathBuf,
+
+    /// parameter file of the energy landscape, either the `parameters.ron` of an earlier
+    /// run or a TOML file with the same fields, without it the defaults in main.rs are used
+    #[arg(short, long)]
+    params: Option<PathBuf>,
+
+    /// overrides a single parameter of the energy landscape, the value is written in RON,
+    /// e.g. `--set energy_factors.strain=10 --set seed=Some(3)`
+    #[arg(short = 's', long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+
+    /// parameter file of the integrator like the `dynamic_parameters.ron` of an earlier run
+    #[arg(short, long)]
+    dynamic_params: Option<PathBuf>,
+
+    /// directory for the output, defaults to `out/<date>`
+    #[arg(short, long)]
+    out: Option<PathBuf>,
 }
 
-impl Model {
-    pub fn new(initial_pos: Spline, initial_vel: Spline) -> Self {
-        Self {
-            _pos: initial_pos,
-            _vel: initial_vel,
-        }
-    }
+fn default_parameters() -> ModelParameters {
+    // the same energy landscape as the Monte Carlo run
+    let default_energy = [
+        ("strain", 1000.0),
+        ("bending", 0.01),
+        ("potential", 100.0),
+        ("field", 1000.0),
+        ("interaction", 500.0),
+        ("boundary", 0.0001),
+    ];
+
+    ModelParameters::new()
+        .segment_len(0.005)
+        .interaction_radius(0.02)
+        .max_segments(8)
+        .spline_count(200)
+        .energy_factors(default_energy)
+        .precision(30)
+        // the forces are only the derivatives of the energy between interpolated samples
+        .interpolation(Interpolation::Bilinear)
+        .build()
 }
 
-impl Model {
-    pub fn update(&mut self) {
-        todo!()
-    }
+fn default_dynamic_parameters() -> DynamicParameters {
+    DynamicParameters::new()
+        .time_step(0.000001)
+        .steps(5000)
+        .damping(10000.0)
+        .set_save_start_svg()
+        .set_save_step_svg()
+        .build()
 }
 
-pub fn main() {
-    let initial_pos = Spline::new(
-        (0..4).map(|i| Vector::new(i as f32, 0.0)).collect(),
-        (0..4)
-            .map(|i| Vector::new(1.0 / 3.0, (-1.0_f32).powi(i) / 3.0))
-            .collect(),
-    );
-    let initial_vel = Spline::new(
-        (0..4).map(|_| Vector::zeros()).collect(),
-        (0..4).map(|_| Vector::zeros()).collect(),
-    );
-    let mut model = Model::new(initial_pos, initial_vel);
-    for _ in 0..100 {
-        model.update();
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let mut parameters = match &args.params {
+        Some(path) => ModelParameters::load(path)?,
+        None => default_parameters(),
+    };
+    for assignment in &args.overrides {
+        parameters = parameters.apply_override(assignment)?;
+    }
+    let dynamic_parameters = match &args.dynamic_params {
+        Some(path) => ron::from_str(&fs::read_to_string(path)?)?,
+        None => default_dynamic_parameters(),
+    };
+
+    let mut builder = monte_carlo::Model::new()
+        .add_samples_from_img(image::open(&args.image)?)
+        .add_params(parameters);
+    if let Some(out) = &args.out {
+        builder = builder.log_dir(out);
     }
+    Model::new(builder.build()?, dynamic_parameters).run()?;
+    Ok(())
 }
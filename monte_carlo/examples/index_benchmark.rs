@@ -0,0 +1,47 @@
+use std::time::Instant;
+
+use common::IndexKind;
+use monte_carlo::{Model, ModelParameters};
+
+const SWEEPS: usize = 5;
+
+/// times a few sweeps with many short splines on the fern for every spatial index
+/// run with `cargo run --release --example index_benchmark -- [IMAGE]`
+fn main() -> anyhow::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "./in/fern.jpg".to_string());
+    let img = image::open(&path)?;
+
+    for kind in [IndexKind::QuadTree, IndexKind::CellList] {
+        let parameters = ModelParameters::new()
+            .segment_len(0.005)
+            .max_segments(3)
+            .spline_count(5000)
+            .interaction_radius(0.01)
+            .precision(4)
+            .spatial_index(kind)
+            .seed(0)
+            .unset_make_plots()
+            .build();
+        let mut model = Model::new()
+            .add_samples_from_img(img.clone())
+            .add_params(parameters)
+            .log_dir(std::env::temp_dir().join("linewise_index_benchmark"))
+            .build()?;
+        model.calc_tot_energy();
+
+        let start = Instant::now();
+        for _ in 0..SWEEPS {
+            model.run_sweep(1.0);
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{:?}: {:.3}s per sweep of {} splines",
+            kind,
+            elapsed.as_secs_f32() / SWEEPS as f32,
+            model.count_splines()
+        );
+    }
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use common::{Energy, Vector, quad_tree::Rect};
+use common::{Vector, quad_tree::Rect};
 use monte_carlo::{Model, ModelParameters};
 use nalgebra::Rotation2;
 
@@ -17,14 +17,14 @@ fn main() -> anyhow::Result<()> {
     };
     let bounds = Rect::new(0.0, A4.0, 0.0, A4.1);
 
-    let energy_factors = Energy {
-        strain_energy: 100.0,
-        bending_energy: 0.001,
-        potential_energy: 10.0,
-        field_energy: 100.0,
-        interaction_energy: 0.00000001,
-        boundary_energy: 0.00001,
-    };
+    let energy_factors = [
+        ("strain", 100.0),
+        ("bending", 0.001),
+        ("potential", 10.0),
+        ("field", 100.0),
+        ("interaction", 0.00000001),
+        ("boundary", 0.00001),
+    ];
 
     let parameters = ModelParameters::new()
         .segment_len(0.01)
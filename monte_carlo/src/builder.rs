@@ -1,16 +1,21 @@
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use chrono::Utc;
 use common::spline::MatrixGenerator;
 use convolve2d::{convolve2d, kernel};
-use image::DynamicImage;
+use image::{DynamicImage, Luma};
 
-use super::{AcceptanceCounter, METHODS, Model, ModelParameters, SvgParams, TransitionScales};
-use common::energy::Energy;
-use common::quad_tree::{Bounded, QuadTree, Rect};
-use common::sampler::Samples2d;
+use super::{
+    AcceptanceCounter, EnergyTerm, FieldMode, METHODS, Model, ModelParameters, OrientationField,
+    Preprocessing, SvgParams, TransitionScales, preprocess::GrayImage,
+};
+use common::IndexKind;
+use common::quad_tree::{Bounded, Rect};
+use common::sampler::{EdgePolicy, Interpolation, Samples2d};
 use common::storage::SplineStorage;
-use common::{Spline, SplineRef, Vector};
+use common::{Shape, Spline, Vector};
 use random::Rng;
 
 pub struct ParamBuilder {
@@ -19,45 +24,94 @@ pub struct ParamBuilder {
     spline_count: Option<usize>,
     interaction_radius: Option<f32>,
 
-    energy_factors: Option<Energy>,
+    energy_factors: BTreeMap<String, f32>,
 
     precision: Option<usize>,
     temp_range: Option<(f32, f32)>,
     temp_steps: Option<usize>,
     sweeps_per_temp: Option<usize>,
+    seed: Option<u64>,
+    checkpoint_interval: Option<usize>,
+    swap_interval: Option<usize>,
+    threads: Option<usize>,
+    spatial_index: Option<IndexKind>,
+    preprocessing: Option<Preprocessing>,
+    orientation_field: Option<OrientationField>,
+    field_mode: Option<FieldMode>,
+    field_angle: Option<f32>,
+    interpolation: Option<Interpolation>,
+    width_range: Option<(f32, f32)>,
+    birth_death_rate: Option<f32>,
+    chemical_potential: Option<f32>,
+    topology_rate: Option<f32>,
+    merge_radius: Option<f32>,
+    fidelity_resolution: Option<usize>,
+    fidelity_blur: Option<f32>,
 
     save_parameters: bool,
+    save_checkpoints: bool,
     save_start_svg: bool,
     save_step_svg: bool,
     save_end_svg: bool,
     make_plots: bool,
+    vary_widths: bool,
+    fidelity: bool,
+    no_crossings: bool,
     time: bool,
 }
 
 impl ParamBuilder {
     pub fn build(self) -> ModelParameters {
-        let default_energy = Energy {
-            strain_energy: 1000000.0,
-            bending_energy: 0.01,
-            potential_energy: 100.0,
-            field_energy: 1000.0,
-            interaction_energy: 500000.0,
-            boundary_energy: 0.0001,
-        };
+        let mut energy_factors: BTreeMap<String, f32> = [
+            ("strain", 1000000.0),
+            ("bending", 0.01),
+            ("potential", 100.0),
+            ("field", 1000.0),
+            ("interaction", 500000.0),
+            ("boundary", 0.0001),
+            ("width", 100.0),
+            ("fidelity", 1000.0),
+        ]
+        .into_iter()
+        .map(|(name, factor)| (name.to_string(), factor))
+        .collect();
+        energy_factors.extend(self.energy_factors);
+        let segment_len = self.segment_len.unwrap_or(0.03);
         ModelParameters {
             interaction_radius: self.interaction_radius.unwrap_or(0.01),
             spline_count: self.spline_count.unwrap_or(900),
-            segment_len: self.segment_len.unwrap_or(0.03),
+            segment_len,
             max_segments: self.max_segments.unwrap_or(4),
 
-            energy_factors: self.energy_factors.unwrap_or(default_energy),
+            energy_factors,
             temp_range: self.temp_range.unwrap_or((1.0, 0.005)),
 
             precision: self.precision.unwrap_or(12),
             temp_steps: self.temp_steps.unwrap_or(10),
             sweeps_per_temp: self.sweeps_per_temp.unwrap_or(150),
+            seed: self.seed,
+            checkpoint_interval: self.checkpoint_interval.unwrap_or(50),
+            swap_interval: self.swap_interval.unwrap_or(10),
+            threads: self.threads.unwrap_or(1),
+            spatial_index: self.spatial_index.unwrap_or_default(),
+            preprocessing: self.preprocessing.unwrap_or_default(),
+            orientation_field: self.orientation_field,
+            field_mode: self.field_mode.unwrap_or_default(),
+            field_angle: self.field_angle.unwrap_or(0.0),
+            interpolation: self.interpolation.unwrap_or_default(),
+            width_range: self.width_range.unwrap_or((0.5, 2.0)),
+            birth_death_rate: self.birth_death_rate.unwrap_or(0.0),
+            chemical_potential: self.chemical_potential.unwrap_or(0.0),
+            topology_rate: self.topology_rate.unwrap_or(0.0),
+            merge_radius: self.merge_radius.unwrap_or(2.0 * segment_len),
+            fidelity_resolution: self.fidelity_resolution.unwrap_or(64),
+            fidelity_blur: self.fidelity_blur.unwrap_or(1.5),
+            vary_widths: self.vary_widths,
+            fidelity: self.fidelity,
+            no_crossings: self.no_crossings,
 
             save_parameters: self.save_parameters,
+            save_checkpoints: self.save_checkpoints,
             save_start_svg: self.save_start_svg,
             save_step_svg: self.save_step_svg,
             save_end_svg: self.save_end_svg,
@@ -81,8 +135,13 @@ impl ParamBuilder {
         self.interaction_radius = Some(interaction_radius);
         self
     }
-    pub fn energy_factors(mut self, energy_factors: Energy) -> Self {
-        self.energy_factors = Some(energy_factors);
+    /// the factors of the energy terms by name, terms which are not given keep their default
+    pub fn energy_factors<'a>(mut self, factors: impl IntoIterator<Item = (&'a str, f32)>) -> Self {
+        self.energy_factors.extend(
+            factors
+                .into_iter()
+                .map(|(name, factor)| (name.to_string(), factor)),
+        );
         self
     }
     pub fn precision(mut self, precision: usize) -> Self {
@@ -101,6 +160,128 @@ impl ParamBuilder {
         self.sweeps_per_temp = Some(sweeps_per_temp);
         self
     }
+    /// seeds the initial placement and the Monte Carlo chain,
+    /// without a seed one is drawn from the os and saved with the parameters
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+    /// number of sweeps between two checkpoints
+    pub fn checkpoint_interval(mut self, checkpoint_interval: usize) -> Self {
+        self.checkpoint_interval = Some(checkpoint_interval);
+        self
+    }
+    /// number of sweeps between two rounds of swaps in parallel tempering
+    pub fn swap_interval(mut self, swap_interval: usize) -> Self {
+        self.swap_interval = Some(swap_interval);
+        self
+    }
+    /// number of threads of a sweep, with more than one the boundary is split into
+    /// a checkerboard of cells which are updated concurrently
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+    /// the structure used to find the neighbours of a spline, the cell list is faster
+    /// for many short splines
+    pub fn spatial_index(mut self, spatial_index: IndexKind) -> Self {
+        self.spatial_index = Some(spatial_index);
+        self
+    }
+    /// the steps applied to an image before the potential and the field are derived from it
+    pub fn preprocessing(mut self, preprocessing: Preprocessing) -> Self {
+        self.preprocessing = Some(preprocessing);
+        self
+    }
+    /// derives the field of an image from its structure tensor instead of the raw gradient
+    pub fn orientation_field(mut self, orientation_field: OrientationField) -> Self {
+        self.orientation_field = Some(orientation_field);
+        self
+    }
+    /// whether the splines run along the gradient, along the edges, at `field_angle`
+    /// to the gradient or along a user field
+    pub fn field_mode(mut self, field_mode: FieldMode) -> Self {
+        self.field_mode = Some(field_mode);
+        self
+    }
+    /// the angle in degrees between the gradient and the splines with `FieldMode::Rotated`
+    pub fn field_angle(mut self, field_angle: f32) -> Self {
+        self.field_angle = Some(field_angle);
+        self
+    }
+    /// how the potential and the field are sampled between their pixels, smooth
+    /// interpolations give the gradient steps continuous derivatives
+    pub fn interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = Some(interpolation);
+        self
+    }
+    /// the stroke widths relative to the line width in the brightest and in the darkest regions
+    pub fn width_range(mut self, width_range: (f32, f32)) -> Self {
+        self.width_range = Some(width_range);
+        self
+    }
+    /// makes the stroke width of the splines a degree of freedom of the Monte Carlo steps,
+    /// its energy pulls it towards the width for the potential from `width_range`
+    pub fn set_vary_widths(mut self) -> Self {
+        self.vary_widths = true;
+        self
+    }
+    pub fn unset_vary_widths(mut self) -> Self {
+        self.vary_widths = false;
+        self
+    }
+    /// probability that a Monte Carlo step inserts or deletes a spline instead of moving one,
+    /// with 0 the number of splines stays `spline_count`
+    pub fn birth_death_rate(mut self, birth_death_rate: f32) -> Self {
+        self.birth_death_rate = Some(birth_death_rate);
+        self
+    }
+    /// energy gained by adding a spline, higher values lead to more splines
+    pub fn chemical_potential(mut self, chemical_potential: f32) -> Self {
+        self.chemical_potential = Some(chemical_potential);
+        self
+    }
+    /// probability that a Monte Carlo step changes the number of segments of a spline,
+    /// or splits a spline or merges two, with 0 the segments stay as they are built
+    pub fn topology_rate(mut self, topology_rate: f32) -> Self {
+        self.topology_rate = Some(topology_rate);
+        self
+    }
+    /// distance up to which the end of a spline is merged with the start of another,
+    /// defaults to twice the segment length
+    pub fn merge_radius(mut self, merge_radius: f32) -> Self {
+        self.merge_radius = Some(merge_radius);
+        self
+    }
+    /// number of pixels along the longer side of the boundary for the fidelity term
+    pub fn fidelity_resolution(mut self, fidelity_resolution: usize) -> Self {
+        self.fidelity_resolution = Some(fidelity_resolution);
+        self
+    }
+    /// standard deviation of the blur of the fidelity term in pixels
+    pub fn fidelity_blur(mut self, fidelity_blur: f32) -> Self {
+        self.fidelity_blur = Some(fidelity_blur);
+        self
+    }
+    /// adds an energy which compares the blurred rendering of the splines with the blurred image,
+    /// it only works with a single thread
+    pub fn set_fidelity(mut self) -> Self {
+        self.fidelity = true;
+        self
+    }
+    pub fn unset_fidelity(mut self) -> Self {
+        self.fidelity = false;
+        self
+    }
+    /// rejects every move after which two splines cross
+    pub fn set_no_crossings(mut self) -> Self {
+        self.no_crossings = true;
+        self
+    }
+    pub fn unset_no_crossings(mut self) -> Self {
+        self.no_crossings = false;
+        self
+    }
     pub fn set_make_plots(mut self) -> Self {
         self.make_plots = true;
         self
@@ -117,6 +298,14 @@ impl ParamBuilder {
         self.save_parameters = false;
         self
     }
+    pub fn set_save_checkpoints(mut self) -> Self {
+        self.save_checkpoints = true;
+        self
+    }
+    pub fn unset_save_checkpoints(mut self) -> Self {
+        self.save_checkpoints = false;
+        self
+    }
     pub fn set_save_start_svg(mut self) -> Self {
         self.save_start_svg = true;
         self
@@ -154,7 +343,11 @@ impl Default for ParamBuilder {
     fn default() -> Self {
         Self {
             make_plots: true,
+            vary_widths: false,
+            fidelity: false,
+            no_crossings: false,
             save_parameters: true,
+            save_checkpoints: true,
             save_start_svg: false,
             save_step_svg: false,
             save_end_svg: true,
@@ -163,18 +356,47 @@ impl Default for ParamBuilder {
             segment_len: None,
             max_segments: None,
             interaction_radius: None,
-            energy_factors: None,
+            energy_factors: BTreeMap::new(),
             precision: None,
             temp_range: None,
             temp_steps: None,
             sweeps_per_temp: None,
+            seed: None,
+            checkpoint_interval: None,
+            swap_interval: None,
+            threads: None,
+            spatial_index: None,
+            preprocessing: None,
+            orientation_field: None,
+            field_mode: None,
+            field_angle: None,
+            interpolation: None,
+            width_range: None,
+            birth_death_rate: None,
+            chemical_potential: None,
+            topology_rate: None,
+            merge_radius: None,
+            fidelity_resolution: None,
+            fidelity_blur: None,
         }
     }
 }
 
+/// `out/<date>`
+pub(crate) fn default_log_dir() -> PathBuf {
+    Path::new("out").join(Utc::now().format("%Y-%m-%d_%H-%M").to_string())
+}
+
+#[derive(Default)]
 pub struct ModelBuilder {
+    gray: Option<GrayImage>,
     field: Option<Samples2d<Vector>>,
+    user_field: Option<Samples2d<Vector>>,
     potential: Option<Samples2d<f32>>,
+    potential_edges: EdgePolicy<f32>,
+    field_edges: EdgePolicy<Vector>,
+    shape: Option<Shape>,
+    terms: Vec<Arc<dyn EnergyTerm>>,
     params: Option<ModelParameters>,
     svg_params: Option<SvgParams>,
     log_dir: Option<PathBuf>,
@@ -182,13 +404,28 @@ pub struct ModelBuilder {
 }
 
 impl ModelBuilder {
-    pub fn add_samples_from_img(mut self, img: DynamicImage) -> Self {
-        let gray = img.to_luma32f();
-        let (w, h, x) = convolve2d(&gray, &kernel::sobel::x::<f32>()).into_parts();
-        let (_, _, y) = convolve2d(&gray, &kernel::sobel::y::<f32>()).into_parts();
+    pub fn add_samples_from_img(self, img: DynamicImage) -> Self {
+        self.add_samples_from_gray(img.to_luma32f())
+    }
+
+    /// the potential is the value of the pixels and the field its gradient
+    /// or the `orientation_field` of the parameters, both are derived in `build`
+    /// after the image went through the `preprocessing` of the parameters
+    pub fn add_samples_from_gray(mut self, gray: GrayImage) -> Self {
+        self.aspect_ratio = Some(gray.width() as f32 / gray.height() as f32);
+        self.gray = Some(gray);
+        self.field = None;
+        self.potential = None;
+        self
+    }
+
+    fn samples_from_gray(
+        gray: &GrayImage,
+        aspect: f32,
+        orientation: Option<&OrientationField>,
+    ) -> (Samples2d<f32>, Samples2d<Vector>) {
         let width = gray.width() as usize;
         let height = gray.height() as usize;
-        let aspect = width as f32 / height as f32;
         let boundary = Rect::new(0.0, aspect.sqrt(), 0.0, 1.0 / aspect.sqrt());
 
         let potential = Samples2d::new(
@@ -198,23 +435,27 @@ impl ModelBuilder {
             boundary,
         );
 
-        let field = Samples2d::new(
-            x.iter()
-                .zip(y.iter())
-                // the division by 8 is for the max value a pixel could be
-                .map(|(&x, &y)| Vector::new(x / 8.0, y / 8.0))
-                .collect(),
-            w,
-            h,
-            boundary,
-        );
+        let field = match orientation {
+            Some(orientation) => Samples2d::new(orientation.compute(gray), width, height, boundary),
+            None => {
+                let (w, h, x) = convolve2d(gray, &kernel::sobel::x::<f32>()).into_parts();
+                let (_, _, y) = convolve2d(gray, &kernel::sobel::y::<f32>()).into_parts();
+                Samples2d::new(
+                    x.iter()
+                        .zip(y.iter())
+                        // the division by 8 is for the max value a pixel could be
+                        .map(|(&x, &y)| Vector::new(x / 8.0, y / 8.0))
+                        .collect(),
+                    w,
+                    h,
+                    boundary,
+                )
+            }
+        };
         // potential.as_img("pot.png");
         // field.map(|vec| vec.x).as_img("x.png");
         // field.map(|vec| vec.y).as_img("y.png");
-        self.aspect_ratio = Some(aspect);
-        self.field = Some(field);
-        self.potential = Some(potential);
-        self
+        (potential, field)
     }
 
     pub fn potential_from_fn(
@@ -230,8 +471,7 @@ impl ModelBuilder {
                 "tried to add field with different aspect ratio"
             );
         }
-        let mut potential =
-            Samples2d::from_fn(|vec| field(vec), sample_dim.0, sample_dim.1, sample_region);
+        let mut potential = Samples2d::from_fn(field, sample_dim.0, sample_dim.1, sample_region);
         potential.set_bounds(Rect::new(
             0.0,
             aspect_ratio.sqrt(),
@@ -248,6 +488,27 @@ impl ModelBuilder {
         sample_region: Rect,
         sample_dim: (usize, usize),
     ) -> Self {
+        self.field = Some(self.sample_vectors(field, sample_region, sample_dim));
+        self
+    }
+
+    /// the field the splines follow with `FieldMode::User` instead of the field of the image
+    pub fn user_field_from_fn(
+        mut self,
+        field: impl Fn(Vector) -> Vector,
+        sample_region: Rect,
+        sample_dim: (usize, usize),
+    ) -> Self {
+        self.user_field = Some(self.sample_vectors(field, sample_region, sample_dim));
+        self
+    }
+
+    fn sample_vectors(
+        &mut self,
+        field: impl Fn(Vector) -> Vector,
+        sample_region: Rect,
+        sample_dim: (usize, usize),
+    ) -> Samples2d<Vector> {
         let aspect_ratio = sample_region.aspect_ratio();
         if let Some(aspect) = self.aspect_ratio {
             assert!(
@@ -257,15 +518,57 @@ impl ModelBuilder {
         } else {
             self.aspect_ratio = Some(aspect_ratio)
         }
-        let mut field =
-            Samples2d::from_fn(|vec| field(vec), sample_dim.0, sample_dim.1, sample_region);
+        let mut field = Samples2d::from_fn(field, sample_dim.0, sample_dim.1, sample_region);
         field.set_bounds(Rect::new(
             0.0,
             aspect_ratio.sqrt(),
             0.0,
             1.0 / aspect_ratio.sqrt(),
         ));
-        self.field = Some(field);
+        field
+    }
+
+    /// the values of the potential outside of the image, by default there are none
+    pub fn potential_edges(mut self, edges: EdgePolicy<f32>) -> Self {
+        self.potential_edges = edges;
+        self
+    }
+
+    /// the values of the field outside of the image, by default there are none
+    pub fn field_edges(mut self, edges: EdgePolicy<Vector>) -> Self {
+        self.field_edges = edges;
+        self
+    }
+
+    /// the region of the canvas the splines are kept in, rects and polygons are given in
+    /// coordinates from 0 to 1 across the canvas and masks are stretched onto it
+    pub fn canvas_shape(mut self, shape: Shape) -> Self {
+        self.shape = Some(shape);
+        self
+    }
+
+    /// keeps the splines in the opaque pixels of `img` or with no alpha channel in its
+    /// bright pixels, the input image itself gives a cut out of its subject,
+    /// `build` fails if `img` doesn't have the aspect ratio of the input image
+    pub fn mask_from_img(self, img: &DynamicImage) -> Self {
+        let mask: GrayImage = if img.color().has_alpha() {
+            let rgba = img.to_rgba32f();
+            GrayImage::from_fn(img.width(), img.height(), |x, y| {
+                Luma([rgba.get_pixel(x, y).0[3]])
+            })
+        } else {
+            img.to_luma32f()
+        };
+        let aspect = mask.width() as f32 / mask.height() as f32;
+        let bounds = Rect::new(0.0, aspect.sqrt(), 0.0, 1.0 / aspect.sqrt());
+        self.canvas_shape(
+            Shape::from_mask(&mask, bounds).expect("the bounds have the aspect ratio of the mask"),
+        )
+    }
+
+    /// registers an energy term after the built in ones, see `Model::add_energy_term`
+    pub fn add_energy_term(mut self, term: impl EnergyTerm + 'static) -> Self {
+        self.terms.push(Arc::new(term));
         self
     }
 
@@ -274,24 +577,61 @@ impl ModelBuilder {
         self
     }
 
+    pub fn log_dir(mut self, log_dir: impl Into<PathBuf>) -> Self {
+        self.log_dir = Some(log_dir.into());
+        self
+    }
+
     pub fn add_svg_params(mut self, params: SvgParams) -> Self {
         self.svg_params = Some(params);
         self
     }
 
     pub fn build(self) -> anyhow::Result<Model> {
-        let params = self.params.unwrap_or(ModelParameters::new().build());
+        let mut params = self.params.unwrap_or(ModelParameters::new().build());
+        anyhow::ensure!(
+            !params.fidelity || params.threads <= 1,
+            "the fidelity term can only be used with a single thread"
+        );
         let aspect = self.aspect_ratio.unwrap_or(1.0);
+        let (mut potential, mut field) = (self.potential, self.field);
+        if let Some(gray) = self.gray {
+            let (gray_potential, gray_field) = Self::samples_from_gray(
+                &params.preprocessing.apply(gray),
+                aspect,
+                params.orientation_field.as_ref(),
+            );
+            // samples set from a function after the image take precedence
+            potential.get_or_insert(gray_potential);
+            field.get_or_insert(gray_field);
+        }
+        if params.field_mode == FieldMode::User {
+            field = Some(self.user_field.ok_or_else(|| {
+                anyhow::anyhow!("the user field mode needs a field from `user_field_from_fn`")
+            })?);
+        }
         let boundary = Rect::new(0.0, aspect.sqrt(), 0.0, 1.0 / aspect.sqrt());
-        let mut rng = random::new_rng();
+        let field = field
+            .unwrap_or(Samples2d::new_filled(Vector::zeros(), 1, 1, boundary))
+            .with_interpolation(params.interpolation)
+            .with_edges(self.field_edges);
+        let potential = potential
+            .unwrap_or(Samples2d::new_filled(0.0, 1, 1, boundary))
+            .with_interpolation(params.interpolation)
+            .with_edges(self.potential_edges);
+        let shape = self
+            .shape
+            .map_or(Ok(Shape::Rect(boundary)), |shape| shape.fit(boundary))?;
+        let seed = *params
+            .seed
+            .get_or_insert_with(|| random::new_rng().random());
+        let mut rng = random::rng_from_seed(seed);
 
-        let log_dir = self.log_dir.unwrap_or_else(|| {
-            Path::new("out").join(Utc::now().format("%Y-%m-%d_%H-%M").to_string())
-        });
+        let log_dir = self.log_dir.unwrap_or_else(default_log_dir);
         std::fs::create_dir_all(&log_dir)?;
 
         let mut storage = SplineStorage::new();
-        let mut splines: QuadTree<SplineRef> = QuadTree::new();
+        let mut splines = params.make_index(&storage, Vec::new());
         let max_iterations = params.spline_count * 100;
         // TODO: think about the influence of this algorithm for length distr of the splines
         // and if I even care
@@ -307,18 +647,15 @@ impl ModelBuilder {
                 rng.random_range(1..=params.max_segments),
                 &mut rng,
             );
-            let mut intersection = false;
-            'outer: for other in splines.query_intersects(spline.bounding_box()) {
-                for o_segment in storage.get_segments(other) {
-                    if spline.shortest_dist(&o_segment, params.precision) < 0.001 {
-                        intersection = true;
-                        break 'outer;
-                    }
-                }
+            if !shape.contains_spline(spline.as_borrowed_spline(), params.precision, 0.001) {
+                continue;
             }
+            let intersection = splines
+                .query_intersects(spline.bounding_box().add_radius(0.001))
+                .any(|other| spline.distance(&storage.get_spline(other)) < 0.001);
             if !intersection {
                 let polyref = storage.add_spline(spline);
-                splines.insert(polyref);
+                splines.insert(polyref, &storage);
             }
         }
         if splines.len() != params.spline_count {
@@ -328,20 +665,13 @@ impl ModelBuilder {
                 max_iterations
             )
         }
-        Ok(Model {
-            field: self.field.unwrap_or(Samples2d::new_filled(
-                Vector::new(0.0, 0.0),
-                1,
-                1,
-                boundary,
-            )),
-            potential: self
-                .potential
-                .unwrap_or(Samples2d::new_filled(0.0, 1, 1, boundary)),
-
-            splines: splines,
+        let mut model = Model {
+            field: Arc::new(field),
+            potential: Arc::new(potential),
+
+            splines,
             markings: storage.default_spline_info(),
-            storage: storage,
+            storage,
             precomp: MatrixGenerator::precompute_mats(params.precision),
             params,
             svg_params: self.svg_params.unwrap_or(SvgParams {
@@ -353,25 +683,79 @@ impl ModelBuilder {
                 margins: (1.2, 1.2),
             }),
             boundary,
+            shape: Arc::new(shape),
+            terms: Vec::new(),
+            factors: Vec::new(),
+            fidelity: None,
             energies: Vec::new(),
             acceptance_couter: AcceptanceCounter::zeros(),
             transition_scales: TransitionScales([0.005; METHODS]),
             rates: Vec::new(),
+            temp_idx: 0,
+            sweep: 0,
             rng,
             log_dir,
-        })
+        };
+        model.register_default_terms();
+        for term in self.terms {
+            model.register_term(term);
+        }
+        model.init_fidelity();
+        Ok(model)
     }
 }
 
-impl Default for ModelBuilder {
-    fn default() -> Self {
-        Self {
-            field: None,
-            potential: None,
-            params: None,
-            svg_params: None,
-            aspect_ratio: None,
-            log_dir: None,
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::seeded_builder;
+
+    #[test]
+    fn user_field_mode_needs_a_field() {
+        let params = ModelParameters::new()
+            .spline_count(5)
+            .field_mode(FieldMode::User)
+            .unset_make_plots()
+            .build();
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let builder = || {
+            Model::new()
+                .potential_from_fn(|pos| pos.x, bounds, (10, 10))
+                .add_params(params.clone())
+                .log_dir(std::env::temp_dir().join("linewise_user_field_test"))
+        };
+        assert!(builder().build().is_err());
+        let model = builder()
+            .user_field_from_fn(|_| Vector::new(0.0, 1.0), bounds, (10, 10))
+            .build()
+            .unwrap();
+        assert_eq!(
+            model.field.get_sample(Vector::new(0.5, 0.5)),
+            Some(Vector::new(0.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn splines_stay_in_the_canvas_shape() {
+        let triangle = vec![
+            Vector::new(0.1, 0.1),
+            Vector::new(0.9, 0.1),
+            Vector::new(0.5, 0.9),
+        ];
+        let log_dir = std::env::temp_dir().join("linewise_shape_test");
+        let mut model = seeded_builder(2, &log_dir, |params| params.spline_count(15))
+            .canvas_shape(Shape::Polygon(triangle))
+            .build()
+            .unwrap();
+        for _ in 0..5 {
+            model.run_sweep(0.1);
+        }
+        assert!(model.calc_tot_energy().is_finite());
+        for spline in model.storage.all_splines() {
+            assert!(model.shape.contains_spline(spline, 8, 0.0));
         }
+        let doc = model.make_svg_doc().to_string();
+        assert!(doc.contains("<clipPath id=\"canvas\">"));
+        assert!(doc.contains("clip-path=\"url(#canvas)\""));
     }
 }
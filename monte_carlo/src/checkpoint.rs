@@ -0,0 +1,249 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, ensure};
+use common::spline::MatrixGenerator;
+use common::{Energy, Rect, Samples2d, Shape, SplineStorage, Vector};
+use random::MyRng;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    AcceptanceCounter, EnergyTerm, Model, ModelParameters, SplineIndex, SvgParams, TransitionScales,
+};
+
+const CHECKPOINT_DIR: &str = "checkpoint";
+const ENVIRONMENT_FILE: &str = "environment.ron";
+const STATE_FILE: &str = "state.ron";
+
+// the parts of the model which stay the same during a run, written once at the start
+#[derive(Serialize)]
+struct EnvironmentRef<'a> {
+    field: &'a Samples2d<Vector>,
+    potential: &'a Samples2d<f32>,
+    params: &'a ModelParameters,
+    svg_params: &'a SvgParams,
+    boundary: Rect,
+    shape: &'a Shape,
+}
+
+#[derive(Deserialize)]
+struct Environment {
+    field: Samples2d<Vector>,
+    potential: Samples2d<f32>,
+    params: ModelParameters,
+    svg_params: SvgParams,
+    boundary: Rect,
+    shape: Shape,
+}
+
+// everything a run changes, written every `checkpoint_interval` sweeps
+#[derive(Serialize)]
+struct StateRef<'a> {
+    storage: &'a SplineStorage,
+    splines: &'a SplineIndex,
+    temp_idx: usize,
+    sweep: usize,
+    transition_scales: &'a TransitionScales,
+    rng: &'a MyRng,
+    energies: &'a [Energy],
+    rates: &'a [[f32; 3]],
+    terms: Vec<&'a str>,
+    fidelity_canvas: Option<&'a [f32]>,
+}
+
+#[derive(Deserialize)]
+struct State {
+    storage: SplineStorage,
+    splines: SplineIndex,
+    temp_idx: usize,
+    sweep: usize,
+    transition_scales: TransitionScales,
+    rng: MyRng,
+    energies: Vec<Energy>,
+    rates: Vec<[f32; 3]>,
+    terms: Vec<String>,
+    fidelity_canvas: Option<Vec<f32>>,
+}
+
+/// writes to a temporary file first, so an interrupted write never leaves a broken checkpoint
+fn write_replacing(path: PathBuf, contents: String) -> anyhow::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+impl Model {
+    fn checkpoint_dir(&self) -> anyhow::Result<PathBuf> {
+        let dir = self.log_dir.join(CHECKPOINT_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    pub fn save_environment(&self) -> anyhow::Result<()> {
+        let environment = EnvironmentRef {
+            field: &self.field,
+            potential: &self.potential,
+            params: &self.params,
+            svg_params: &self.svg_params,
+            boundary: self.boundary,
+            shape: &self.shape,
+        };
+        write_replacing(
+            self.checkpoint_dir()?.join(ENVIRONMENT_FILE),
+            ron::to_string(&environment)?,
+        )
+    }
+
+    /// the environment has to be saved before, `run` does this at the start
+    pub fn save_checkpoint(&self) -> anyhow::Result<()> {
+        let state = StateRef {
+            storage: &self.storage,
+            splines: &self.splines,
+            temp_idx: self.temp_idx,
+            sweep: self.sweep,
+            transition_scales: &self.transition_scales,
+            rng: &self.rng,
+            energies: &self.energies,
+            rates: &self.rates,
+            terms: self.terms.iter().map(|term| term.name()).collect(),
+            fidelity_canvas: self.fidelity.as_ref().map(|fidelity| fidelity.canvas()),
+        };
+        write_replacing(
+            self.checkpoint_dir()?.join(STATE_FILE),
+            ron::to_string(&state)?,
+        )
+    }
+
+    /// loads the last checkpoint from the log directory of a run,
+    /// `run` then continues exactly where the checkpoint was taken,
+    /// a run with energy terms which are not built in needs `resume_with_terms`
+    pub fn resume(log_dir: impl AsRef<Path>) -> anyhow::Result<Model> {
+        Self::resume_with_terms(log_dir, Vec::new())
+    }
+
+    /// like `resume`, `terms` are the terms which were added to the model
+    /// after the built in ones, in the same order
+    pub fn resume_with_terms(
+        log_dir: impl AsRef<Path>,
+        terms: Vec<Arc<dyn EnergyTerm>>,
+    ) -> anyhow::Result<Model> {
+        let log_dir = log_dir.as_ref();
+        let dir = log_dir.join(CHECKPOINT_DIR);
+        let environment: Environment = ron::from_str(
+            &fs::read_to_string(dir.join(ENVIRONMENT_FILE))
+                .with_context(|| format!("no checkpoint in {}", log_dir.display()))?,
+        )?;
+        let state: State = ron::from_str(&fs::read_to_string(dir.join(STATE_FILE))?)?;
+        let mut model = Model {
+            field: Arc::new(environment.field),
+            potential: Arc::new(environment.potential),
+            markings: state.storage.default_spline_info(),
+            storage: state.storage,
+            splines: state.splines,
+            precomp: MatrixGenerator::precompute_mats(environment.params.precision),
+            params: environment.params,
+            svg_params: environment.svg_params,
+            boundary: environment.boundary,
+            shape: Arc::new(environment.shape),
+            terms: Vec::new(),
+            factors: Vec::new(),
+            fidelity: None,
+            energies: state.energies,
+            acceptance_couter: AcceptanceCounter::zeros(),
+            transition_scales: state.transition_scales,
+            rates: state.rates,
+            temp_idx: state.temp_idx,
+            sweep: state.sweep,
+            rng: state.rng,
+            log_dir: log_dir.to_path_buf(),
+        };
+        model.register_default_terms();
+        for term in terms {
+            model.register_term(term);
+        }
+        let names: Vec<&str> = model.terms.iter().map(|term| term.name()).collect();
+        ensure!(
+            names == state.terms,
+            "the checkpoint has the energy terms {:?} but {:?} were rebuilt",
+            state.terms,
+            names
+        );
+        model.init_fidelity();
+        if let (Some(fidelity), Some(canvas)) = (&mut model.fidelity, state.fidelity_canvas) {
+            fidelity.restore_canvas(canvas)?;
+        }
+        Ok(model)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::BendingTerm;
+    use crate::test_util::{run_seeded, seeded_builder, seeded_model, seeded_model_with};
+
+    #[test]
+    fn fidelity_canvas_is_restored() {
+        let log_dir = std::env::temp_dir().join("linewise_fidelity_resume_test");
+        let mut model = seeded_model_with(5, &log_dir, |params| params.set_fidelity());
+        for _ in 0..3 {
+            model.run_sweep(0.1);
+        }
+        model.save_environment().unwrap();
+        model.save_checkpoint().unwrap();
+        let mut resumed = Model::resume(&log_dir).unwrap();
+        let canvas = |model: &Model| model.fidelity.as_ref().unwrap().canvas().to_vec();
+        assert_eq!(canvas(&model), canvas(&resumed));
+        for _ in 0..3 {
+            model.run_sweep(0.1);
+            resumed.run_sweep(0.1);
+        }
+        assert_eq!(canvas(&model), canvas(&resumed));
+        assert_eq!(
+            model.make_svg_doc().to_string(),
+            resumed.make_svg_doc().to_string()
+        );
+    }
+
+    #[test]
+    fn added_terms_have_to_be_rebuilt() {
+        let log_dir = std::env::temp_dir().join("linewise_terms_resume_test");
+        let model = seeded_builder(6, &log_dir, |params| params)
+            .add_energy_term(BendingTerm)
+            .build()
+            .unwrap();
+        model.save_environment().unwrap();
+        model.save_checkpoint().unwrap();
+        assert!(Model::resume(&log_dir).is_err());
+        let resumed = Model::resume_with_terms(&log_dir, vec![Arc::new(BendingTerm)]).unwrap();
+        assert_eq!(resumed.terms.len(), model.terms.len());
+    }
+
+    #[test]
+    fn resumed_run_matches_uninterrupted_run() {
+        let uninterrupted = run_seeded(7, "linewise_resume_test_a");
+
+        let log_dir = std::env::temp_dir().join("linewise_resume_test_b");
+        let (tx, _rx) = mpsc::channel();
+        // with the flag already set the run stops after its first sweep
+        let stopped = seeded_model(7, &log_dir).run(Some((tx, Arc::new(AtomicBool::new(true)))));
+        assert!(stopped.is_err());
+        // the second stop happens in the middle of the first temperature
+        let (tx, _rx) = mpsc::channel();
+        let model = Model::resume(&log_dir).unwrap();
+        assert_eq!((model.temp_idx, model.sweep), (0, 1));
+        let stopped = model.run(Some((tx, Arc::new(AtomicBool::new(true)))));
+        assert!(stopped.is_err());
+
+        let model = Model::resume(&log_dir).unwrap();
+        assert_eq!((model.temp_idx, model.sweep), (0, 2));
+        model.run(None).unwrap();
+        let resumed = fs::read_to_string(log_dir.join("img_end.svg")).unwrap();
+        assert_eq!(uninterrupted, resumed);
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::{fs, thread};
+
+use anyhow::{anyhow, bail};
+use common::{CLEAR_LINE, MOVE_UP, Vector};
+use image::{DynamicImage, ImageBuffer, Luma};
+use random::Rng;
+use ron::ser::{PrettyConfig, to_string_pretty};
+use svg::Document;
+
+use super::builder::default_log_dir;
+use super::{EnergyTerm, InteractionTerm, Model, ModelParameters, Sample, SamplePartials};
+
+/// how an image is separated into layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// red, green and blue, the lines follow the intensity of the channel
+    /// like light pens on dark paper
+    Rgb,
+    /// cyan, magenta, yellow and black, the lines follow the amount of ink on white paper
+    Cmyk,
+}
+
+impl FromStr for ColorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rgb" => Ok(ColorMode::Rgb),
+            "cmyk" => Ok(ColorMode::Cmyk),
+            _ => Err(anyhow!("unknown color mode {}, expected rgb or cmyk", s)),
+        }
+    }
+}
+
+impl ColorMode {
+    /// the names of the layers, which are also their colors in the svg
+    pub fn layers(self) -> &'static [&'static str] {
+        match self {
+            ColorMode::Rgb => &["red", "green", "blue"],
+            ColorMode::Cmyk => &["cyan", "magenta", "yellow", "black"],
+        }
+    }
+
+    /// the amount of every layer a pixel needs, between 0 and 1
+    fn separate(self, [r, g, b]: [f32; 3]) -> Vec<f32> {
+        match self {
+            ColorMode::Rgb => vec![r, g, b],
+            ColorMode::Cmyk => {
+                let k = 1.0 - r.max(g).max(b);
+                if k >= 1.0 {
+                    return vec![0.0, 0.0, 0.0, 1.0];
+                }
+                vec![
+                    (1.0 - r - k) / (1.0 - k),
+                    (1.0 - g - k) / (1.0 - k),
+                    (1.0 - b - k) / (1.0 - k),
+                    k,
+                ]
+            }
+        }
+    }
+
+    /// one image per layer where the lines belong to dark pixels, like a grayscale image
+    fn channel_images(self, img: &DynamicImage) -> Vec<ImageBuffer<Luma<f32>, Vec<f32>>> {
+        let rgb = img.to_rgb32f();
+        let (width, height) = rgb.dimensions();
+        let separated: Vec<Vec<f32>> = rgb.pixels().map(|pixel| self.separate(pixel.0)).collect();
+        (0..self.layers().len())
+            .map(|layer| {
+                ImageBuffer::from_fn(width, height, |x, y| {
+                    Luma([1.0 - separated[(y * width + x) as usize][layer]])
+                })
+            })
+            .collect()
+    }
+}
+
+/// samples in square buckets as wide as the interaction radius
+#[derive(Default)]
+struct Obstacles {
+    width: f32,
+    buckets: HashMap<(i32, i32), Vec<Sample>>,
+}
+
+impl Obstacles {
+    fn bucket(&self, position: Vector) -> (i32, i32) {
+        (
+            (position.x / self.width).floor() as i32,
+            (position.y / self.width).floor() as i32,
+        )
+    }
+
+    fn update(&mut self, samples: impl Iterator<Item = Sample>) {
+        self.buckets.clear();
+        for sample in samples {
+            let bucket = self.bucket(sample.position);
+            self.buckets.entry(bucket).or_default().push(sample);
+        }
+    }
+
+    /// all samples closer than the width and some more
+    fn near(&self, position: Vector) -> impl Iterator<Item = &Sample> {
+        let (i, j) = self.bucket(position);
+        (i - 1..=i + 1)
+            .flat_map(move |i| (j - 1..=j + 1).map(move |j| (i, j)))
+            .filter_map(|bucket| self.buckets.get(&bucket))
+            .flatten()
+    }
+}
+
+/// the interaction with the splines of the other layers,
+/// which stay fixed between two updates of the obstacles
+pub struct CrossInteraction {
+    interaction: InteractionTerm,
+    obstacles: Arc<RwLock<Obstacles>>,
+}
+
+impl EnergyTerm for CrossInteraction {
+    fn name(&self) -> &str {
+        "cross_interaction"
+    }
+
+    fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+        let obstacles = self.obstacles.read().expect("obstacles were poisoned");
+        let mut pair_sum = 0.0;
+        for sample in samples {
+            for other in obstacles.near(sample.position) {
+                pair_sum += self.interaction.pair(sample, other)
+            }
+        }
+        pair_sum * ds * ds
+    }
+
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        let obstacles = self.obstacles.read().expect("obstacles were poisoned");
+        samples
+            .iter()
+            .map(|sample| {
+                let (d_pos, d_der) = obstacles
+                    .near(sample.position)
+                    .map(|other| self.interaction.pair_partials(sample, other))
+                    .fold((Vector::zeros(), Vector::zeros()), |acc, d| {
+                        (acc.0 + d.0, acc.1 + d.1)
+                    });
+                (d_pos * ds * ds, d_der * ds * ds, Vector::zeros())
+            })
+            .collect()
+    }
+}
+
+struct Layer {
+    name: &'static str,
+    model: Model,
+    obstacles: Option<Arc<RwLock<Obstacles>>>,
+}
+
+/// One spline population per color layer of an image, each with its own potential and field.
+///
+/// The layers are annealed together, every layer on its own thread. With cross interaction
+/// the splines of a layer are repelled by the splines of the other layers, which are
+/// exchanged every `swap_interval` sweeps. The svg has one colored group per layer,
+/// marked as Inkscape layers, so every layer can be plotted with its own pen.
+pub struct ColorModel {
+    layers: Vec<Layer>,
+    params: ModelParameters,
+    log_dir: PathBuf,
+}
+
+impl ColorModel {
+    /// the `spline_count` of the parameters is split between the layers by their amount of ink,
+    /// layers without ink are left out, the layers write no checkpoints
+    pub fn from_img(
+        img: &DynamicImage,
+        mode: ColorMode,
+        mut params: ModelParameters,
+        cross_interaction: bool,
+        log_dir: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let log_dir = log_dir.unwrap_or_else(default_log_dir);
+        // the saved parameters should not promise checkpoints to resume from
+        params.save_checkpoints = false;
+        let seed = *params
+            .seed
+            .get_or_insert_with(|| random::new_rng().random());
+        let mut rng = random::rng_from_seed(seed);
+
+        let channels = mode.channel_images(img);
+        let ink: Vec<f32> = channels
+            .iter()
+            .map(|channel| channel.pixels().map(|p| 1.0 - p.0[0]).sum())
+            .collect();
+        let tot_ink: f32 = ink.iter().sum();
+        if tot_ink <= 0.0 {
+            bail!("the image has no ink in any layer")
+        }
+
+        let mut layers = Vec::new();
+        for ((&name, channel), ink) in mode.layers().iter().zip(channels).zip(ink) {
+            let spline_count = (params.spline_count as f32 * ink / tot_ink).round() as usize;
+            if spline_count == 0 {
+                continue;
+            }
+            let mut layer_params = params.clone();
+            layer_params.spline_count = spline_count;
+            layer_params.seed = Some(rng.random());
+            let interaction_factor = layer_params
+                .energy_factors
+                .get("interaction")
+                .copied()
+                .unwrap_or(1.0);
+            layer_params
+                .energy_factors
+                .entry("cross_interaction".to_string())
+                .or_insert(interaction_factor);
+
+            let obstacles = cross_interaction.then(|| {
+                Arc::new(RwLock::new(Obstacles {
+                    width: params.interaction_radius,
+                    ..Default::default()
+                }))
+            });
+            let mut builder = Model::new()
+                .add_samples_from_gray(channel)
+                .add_params(layer_params)
+                .log_dir(log_dir.join(name));
+            if let Some(obstacles) = &obstacles {
+                builder = builder.add_energy_term(CrossInteraction {
+                    interaction: InteractionTerm {
+                        radius: params.interaction_radius,
+                    },
+                    obstacles: Arc::clone(obstacles),
+                });
+            }
+            layers.push(Layer {
+                name,
+                model: builder.build()?,
+                obstacles,
+            });
+        }
+        Ok(Self {
+            layers,
+            params,
+            log_dir,
+        })
+    }
+
+    /// gives every layer the current samples of all other layers
+    fn update_obstacles(&mut self) {
+        let samples: Vec<Vec<Sample>> = self
+            .layers
+            .iter()
+            .map(|layer| layer.model.all_samples())
+            .collect();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(obstacles) = &layer.obstacles {
+                let others = samples
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .flat_map(|(_, samples)| samples.iter().copied());
+                obstacles
+                    .write()
+                    .expect("obstacles were poisoned")
+                    .update(others);
+            }
+        }
+    }
+
+    fn run_layers(&mut self, temp: f32, sweeps: usize) {
+        thread::scope(|scope| {
+            for layer in self.layers.iter_mut() {
+                scope.spawn(move || {
+                    for _ in 0..sweeps {
+                        layer.model.run_sweep(temp)
+                    }
+                });
+            }
+        });
+    }
+
+    fn print_status(&self, step: usize, temp: f32, sweep: usize) -> anyhow::Result<()> {
+        print!(
+            "{}{}{}running at temperature {:.3}   step {}/{:>2}\n{}running sweep {:>3}/{}",
+            CLEAR_LINE,
+            MOVE_UP,
+            CLEAR_LINE,
+            temp,
+            step,
+            self.params.temp_steps,
+            CLEAR_LINE,
+            sweep,
+            self.params.sweeps_per_temp
+        );
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+
+    pub fn make_svg_doc(&self) -> Document {
+        let first = &self.layers[0].model;
+        let transform = first.page_transform(first.get_bounds());
+        let mut doc = first.make_svg_page().set(
+            "xmlns:inkscape",
+            "http://www.inkscape.org/namespaces/inkscape",
+        );
+        for layer in &self.layers {
+            doc = doc.add(
+                layer
+                    .model
+                    .make_spline_group(layer.name)
+                    .set("clip-path", "url(#canvas)")
+                    .set("id", layer.name)
+                    .set("inkscape:groupmode", "layer")
+                    .set("inkscape:label", layer.name)
+                    .set("transform", transform.clone()),
+            );
+        }
+        doc.add(first.make_svg_clip()).add(
+            first
+                .get_shape()
+                .as_svg(first.calc_linewidth())
+                .set("transform", transform),
+        )
+    }
+
+    pub fn save_svg_doc(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        svg::save(self.log_dir.join(path), &self.make_svg_doc())
+    }
+
+    pub fn run(mut self) -> anyhow::Result<()> {
+        if self.params.save_parameters {
+            fs::write(
+                self.log_dir.join("parameters.ron"),
+                to_string_pretty(&self.params, PrettyConfig::default())?,
+            )?;
+        }
+        if self.params.save_start_svg {
+            self.save_svg_doc("img_start.svg")?
+        }
+        self.update_obstacles();
+        for layer in self.layers.iter_mut() {
+            anyhow::ensure!(
+                layer.model.calc_tot_energy().is_finite(),
+                "initial energy of the {} layer needs to be finite",
+                layer.name
+            );
+        }
+
+        let start = cpu_time::ProcessTime::now();
+        let sweeps = self.params.sweeps_per_temp;
+        let interval = self.params.swap_interval.max(1);
+        for (i, temp) in self.params.get_temps().into_iter().enumerate() {
+            self.layers
+                .iter_mut()
+                .for_each(|layer| layer.model.clear_logs());
+            let mut sweep = 0;
+            while sweep < sweeps {
+                self.print_status(i + 1, temp, sweep)?;
+                let round_sweeps = interval.min(sweeps - sweep);
+                self.run_layers(temp, round_sweeps);
+                self.update_obstacles();
+                sweep += round_sweeps;
+            }
+            println!();
+
+            if self.params.make_plots {
+                for layer in &self.layers {
+                    layer.model.make_all_plots(
+                        &format!("{} at temp {}", layer.name, temp),
+                        &i.to_string(),
+                    )?;
+                }
+            }
+            if self.params.save_step_svg {
+                self.save_svg_doc(format!("img_{}_{}.svg", i, temp))?;
+            }
+        }
+        let cpu_duration = start.elapsed();
+
+        if self.params.save_end_svg {
+            self.save_svg_doc("img_end.svg")?;
+        }
+        fs::write(
+            self.log_dir.join("log.txt"),
+            format!("took {:.3}s", cpu_duration.as_secs_f32()),
+        )?;
+        println!("\nFinished Running");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn layers_follow_the_channels() {
+        // red on the left, blue at the bottom and no green
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 40, |x, y| {
+            Rgb([(x * 255 / 39) as u8, 0, (y * 255 / 39) as u8])
+        }));
+        let params = ModelParameters::new()
+            .spline_count(20)
+            .segment_len(0.05)
+            .interaction_radius(0.05)
+            .precision(8)
+            .temp_steps(2)
+            .sweeps_per_temp(3)
+            .swap_interval(2)
+            .seed(5)
+            .unset_make_plots()
+            .build();
+        let log_dir = std::env::temp_dir().join("linewise_color_test");
+        let model = ColorModel::from_img(&img, ColorMode::Rgb, params, true, Some(log_dir.clone()))
+            .unwrap();
+        let names: Vec<_> = model.layers.iter().map(|layer| layer.name).collect();
+        assert_eq!(names, ["red", "blue"]);
+        let counts: Vec<_> = model
+            .layers
+            .iter()
+            .map(|layer| layer.model.count_splines())
+            .collect();
+        assert_eq!(counts, [10, 10]);
+        assert!(
+            model.layers[0]
+                .model
+                .energy_names()
+                .contains(&"cross_interaction")
+        );
+
+        model.run().unwrap();
+        let svg = fs::read_to_string(log_dir.join("img_end.svg")).unwrap();
+        assert!(svg.contains(r#"inkscape:label="red""#));
+        assert!(svg.contains(r#"stroke="blue""#));
+        assert!(!svg.contains(r#"stroke="green""#));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::{fmt, fs};
+
+use anyhow::{Context, anyhow, bail, ensure};
+use ron::Value;
+use serde::Deserializer;
+use serde::de::{Error, MapAccess, Visitor};
+
+use super::ModelParameters;
+
+// the fields of the struct `energy_factors` was before the energy terms were pluggable
+const OLD_FACTORS: [&str; 6] = [
+    "strain_energy",
+    "bending_energy",
+    "potential_energy",
+    "field_energy",
+    "interaction_energy",
+    "boundary_energy",
+];
+
+/// reads `energy_factors` as a map from the names of the terms to their factors,
+/// older parameter files have a struct with the field `strain_energy` for `strain` etc.
+/// and terms missing from the file keep their default factors
+pub(crate) fn deserialize_energy_factors<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, f32>, D::Error> {
+    struct FactorVisitor;
+
+    impl<'de> Visitor<'de> for FactorVisitor {
+        type Value = BTreeMap<String, f32>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map from the names of the energy terms to their factors")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut factors = ModelParameters::default().energy_factors;
+            // RON only reads the field names of a struct as a `Value`
+            while let Some((name, factor)) = map.next_entry::<Value, f32>()? {
+                let Value::String(name) = name else {
+                    return Err(A::Error::custom("expected the name of an energy term"));
+                };
+                let name = match OLD_FACTORS.contains(&name.as_str()) {
+                    true => name.trim_end_matches("_energy").to_string(),
+                    false => name,
+                };
+                factors.insert(name, factor);
+            }
+            Ok(factors)
+        }
+    }
+
+    deserializer.deserialize_any(FactorVisitor)
+}
+
+fn field_mut<'a>(value: &'a mut Value, key: &str) -> anyhow::Result<&'a mut Value> {
+    let mut field = value;
+    for name in key.split('.') {
+        let Value::Map(map) = field else {
+            bail!("{} has no field {}", key, name)
+        };
+        field = map
+            .iter_mut()
+            .find(|(k, _)| **k == Value::String(name.to_string()))
+            .map(|(_, v)| v)
+            .ok_or_else(|| anyhow!("unknown parameter {}", key))?;
+    }
+    Ok(field)
+}
+
+fn is_bare_word(value: &str) -> bool {
+    value.chars().next().is_some_and(|c| c.is_alphabetic())
+        && value.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !matches!(value, "true" | "false" | "None")
+}
+
+/// compares numbers with the precision of the parameters
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => a == b,
+            _ => a.into_f64() as f32 == b.into_f64() as f32,
+        },
+        (Value::Option(Some(a)), Value::Option(Some(b))) => same_value(a, b),
+        (Value::Seq(a), Value::Seq(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_value(a, b))
+        }
+        (Value::Map(a), Value::Map(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|((k_a, a), (k_b, b))| k_a == k_b && same_value(a, b))
+        }
+        (a, b) => a == b,
+    }
+}
+
+impl ModelParameters {
+    /// reads the parameters from a RON file like the `parameters.ron` a run writes,
+    /// or from a TOML file with the same fields
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("could not read parameters from {}", path.display()))?;
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            Ok(toml::from_str(&contents)?)
+        } else {
+            Ok(ron::from_str(&contents)?)
+        }
+    }
+
+    /// replaces a single field, `key` can reach into nested fields with dots,
+    /// e.g. `energy_factors.strain`, and `value` is written in RON,
+    /// a bare word like `CellList` is read as a string
+    pub fn set(self, key: &str, value: &str) -> anyhow::Result<Self> {
+        let mut params: Value = ron::from_str(&ron::to_string(&self)?)?;
+        let new_value: Value = if is_bare_word(value) {
+            Value::String(value.to_string())
+        } else {
+            ron::from_str(value).with_context(|| format!("invalid value for {}", key))?
+        };
+        *field_mut(&mut params, key)? = new_value.clone();
+        let params: Self = params
+            .into_rust()
+            .with_context(|| format!("invalid value for {}", key))?;
+
+        // converting from a `Value` casts numbers silently, e.g. -1 becomes usize::MAX,
+        // so the value has to survive a round trip
+        let mut written: Value = ron::from_str(&ron::to_string(&params)?)?;
+        ensure!(
+            same_value(field_mut(&mut written, key)?, &new_value),
+            "invalid value for {}: {}",
+            key,
+            value
+        );
+        Ok(params)
+    }
+
+    /// applies an override of the form `key=value`, see `set`
+    pub fn apply_override(self, assignment: &str) -> anyhow::Result<Self> {
+        let (key, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected key=value but got {}", assignment))?;
+        self.set(key.trim(), value.trim())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overrides_and_saved_parameters() {
+        let params = ModelParameters::new()
+            .build()
+            .apply_override("energy_factors.strain=10")
+            .unwrap()
+            .apply_override("seed = Some(3)")
+            .unwrap()
+            .apply_override("temp_range=(2.0, 0.1)")
+            .unwrap();
+        assert_eq!(params.energy_factors["strain"], 10.0);
+        assert_eq!(params.seed, Some(3));
+        assert_eq!(params.temp_range, (2.0, 0.1));
+        let params = params
+            .apply_override("spatial_index=CellList")
+            .unwrap()
+            .apply_override("field_mode=Isophote")
+            .unwrap()
+            .apply_override("interpolation=Bicubic")
+            .unwrap();
+        assert_eq!(params.field_mode, crate::FieldMode::Isophote);
+        assert_eq!(params.interpolation, common::Interpolation::Bicubic);
+        assert_eq!(params.spatial_index, common::IndexKind::CellList);
+        assert!(params.clone().set("spatial_index", "Grid").is_err());
+        let params = params
+            .apply_override("preprocessing.levels=Some((0.1, 0.9))")
+            .unwrap();
+        assert_eq!(
+            params.preprocessing,
+            crate::Preprocessing::new().levels(0.1, 0.9)
+        );
+        let params = params
+            .apply_override(
+                "orientation_field=Some((scale: 2.0, min_coherence: 0.2, diffusion: 10))",
+            )
+            .unwrap();
+        assert_eq!(
+            params.orientation_field,
+            Some(crate::OrientationField::new().scale(2.0).diffusion(10))
+        );
+
+        assert!(ModelParameters::new().build().set("seeed", "None").is_err());
+        assert!(
+            ModelParameters::new()
+                .build()
+                .set("spline_count", "-1")
+                .is_err()
+        );
+        assert!(ModelParameters::new().build().set("seed", "abc").is_err());
+
+        let path = std::env::temp_dir().join("linewise_config_test.ron");
+        fs::write(&path, ron::to_string(&params).unwrap()).unwrap();
+        let loaded = ModelParameters::load(&path).unwrap();
+        assert_eq!(
+            ron::to_string(&loaded).unwrap(),
+            ron::to_string(&params).unwrap()
+        );
+    }
+
+    #[test]
+    fn baseline_parameters_are_read() {
+        // written by the first version of the binary, before any of the newer fields
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/baseline_parameters.ron");
+        let params = ModelParameters::load(path).unwrap();
+        assert_eq!(params.spline_count, 200);
+        assert_eq!(params.segment_len, 0.005);
+        assert_eq!(params.energy_factors["strain"], 1000.0);
+        assert_eq!(params.energy_factors["interaction"], 500.0);
+        assert_eq!(params.energy_factors["boundary"], 0.0001);
+        // the newer terms and fields have the defaults of the builder
+        let defaults = ModelParameters::new().build();
+        assert_eq!(
+            params.energy_factors["width"],
+            defaults.energy_factors["width"]
+        );
+        assert_eq!(params.energy_factors.len(), defaults.energy_factors.len());
+        assert_eq!(params.checkpoint_interval, defaults.checkpoint_interval);
+        assert_eq!(params.swap_interval, defaults.swap_interval);
+        assert_eq!(params.threads, defaults.threads);
+        assert_eq!(params.width_range, defaults.width_range);
+        assert_eq!(params.interpolation, defaults.interpolation);
+        assert!(params.save_checkpoints);
+    }
+}
@@ -0,0 +1,369 @@
+use common::spline::BorrowedSpline;
+use common::{Rect, Samples2d, SplineStorage, Vector};
+use tiny_skia::{Color, Paint, Pixmap, Stroke, Transform};
+
+/// a rectangle of pixels, it can reach outside of the canvas
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    x: i32,
+    y: i32,
+    width: usize,
+    height: usize,
+}
+
+impl Window {
+    fn combine(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Self {
+            x,
+            y,
+            width: ((self.x + self.width as i32).max(other.x + other.width as i32) - x) as usize,
+            height: ((self.y + self.height as i32).max(other.y + other.height as i32) - y) as usize,
+        }
+    }
+
+    fn add_radius(self, radius: usize) -> Self {
+        Self {
+            x: self.x - radius as i32,
+            y: self.y - radius as i32,
+            width: self.width + 2 * radius,
+            height: self.height + 2 * radius,
+        }
+    }
+}
+
+/// the change of the blurred canvas by a move
+pub(crate) struct Delta {
+    window: Window,
+    values: Vec<f32>,
+}
+
+/// Compares the rendered splines with the image.
+///
+/// The splines are rasterized at a coarse resolution and blurred with a gaussian, the energy
+/// is the squared difference to the blurred darkness of the potential integrated over the
+/// boundary. The blur is linear, so only the blurred canvas is kept and a move changes it by
+/// the blurred difference of the coverage of the old and the new spline, which touches only
+/// the pixels around the two splines.
+#[derive(Clone)]
+pub(crate) struct Fidelity {
+    boundary: Rect,
+    // pixels per unit length
+    scale: f32,
+    width: usize,
+    height: usize,
+    line_width: f32,
+    kernel: Vec<f32>,
+    target: Vec<f32>,
+    blurred: Vec<f32>,
+}
+
+impl Fidelity {
+    /// `resolution` is the number of pixels along the longer side of the boundary
+    /// and `blur` the standard deviation of the gaussian in pixels
+    pub fn new(
+        potential: &Samples2d<f32>,
+        boundary: Rect,
+        resolution: usize,
+        blur: f32,
+        line_width: f32,
+    ) -> Self {
+        let scale = resolution.max(1) as f32 / boundary.width().max(boundary.height());
+        let width = (boundary.width() * scale).ceil() as usize;
+        let height = (boundary.height() * scale).ceil() as usize;
+        let radius = (3.0 * blur).ceil() as usize;
+        let mut kernel: Vec<f32> = (0..=2 * radius)
+            .map(|i| (-((i as f32 - radius as f32) / blur.max(f32::EPSILON)).powi(2) / 2.0).exp())
+            .collect();
+        let sum: f32 = kernel.iter().sum();
+        kernel.iter_mut().for_each(|val| *val /= sum);
+
+        let mut fidelity = Self {
+            boundary,
+            scale,
+            width,
+            height,
+            line_width,
+            kernel,
+            target: Vec::new(),
+            blurred: vec![0.0; width * height],
+        };
+        let mut darkness = Vec::with_capacity(width * height);
+        for j in 0..height {
+            for i in 0..width {
+                let position = fidelity.position(i, j);
+                darkness.push(1.0 - potential.get_sample(position).unwrap_or(1.0));
+            }
+        }
+        let window = Window {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        fidelity.target = fidelity.crop(fidelity.blur(window, darkness), window);
+        fidelity
+    }
+
+    /// the center of a pixel
+    fn position(&self, i: usize, j: usize) -> Vector {
+        self.boundary.from_box_coords((
+            (i as f32 + 0.5) / self.scale / self.boundary.width(),
+            (j as f32 + 0.5) / self.scale / self.boundary.height(),
+        ))
+    }
+
+    fn radius(&self) -> usize {
+        self.kernel.len() / 2
+    }
+
+    /// covered fraction of the pixels around the spline
+    fn coverage(&self, spline: BorrowedSpline) -> (Window, Vec<f32>) {
+        let stroke_width = self.line_width * spline.width();
+        let bounds = spline.calculate_bounds().add_radius(stroke_width);
+        let corner = bounds.from_box_coords((0.0, 0.0));
+        let (x, y) = self.boundary.to_box_coords(corner);
+        let window = Window {
+            x: (x * self.boundary.width() * self.scale).floor() as i32,
+            y: (y * self.boundary.height() * self.scale).floor() as i32,
+            width: (bounds.width() * self.scale).ceil() as usize + 2,
+            height: (bounds.height() * self.scale).ceil() as usize + 2,
+        };
+        let mut pixmap =
+            Pixmap::new(window.width as u32, window.height as u32).expect("pixmap size is valid");
+        let origin = self.boundary.from_box_coords((0.0, 0.0));
+        pixmap.stroke_path(
+            &spline.as_ts_path(),
+            &Paint {
+                shader: tiny_skia::Shader::SolidColor(Color::BLACK),
+                ..Default::default()
+            },
+            &Stroke {
+                width: stroke_width,
+                ..Default::default()
+            },
+            Transform::from_row(
+                self.scale,
+                0.0,
+                0.0,
+                self.scale,
+                -origin.x * self.scale - window.x as f32,
+                -origin.y * self.scale - window.y as f32,
+            ),
+            None,
+        );
+        let values = pixmap
+            .pixels()
+            .iter()
+            .map(|pixel| pixel.alpha() as f32 / 255.0)
+            .collect();
+        (window, values)
+    }
+
+    /// blurs `values` of `window` with zeros around it,
+    /// the result covers `window` grown by the radius of the kernel
+    fn blur(&self, window: Window, values: Vec<f32>) -> Vec<f32> {
+        let radius = self.radius();
+        let out = window.add_radius(radius);
+        let mut rows = vec![0.0; out.width * window.height];
+        for j in 0..window.height {
+            for i in 0..window.width {
+                let val = values[j * window.width + i];
+                if val == 0.0 {
+                    continue;
+                }
+                for (k, weight) in self.kernel.iter().enumerate() {
+                    rows[j * out.width + i + k] += weight * val;
+                }
+            }
+        }
+        let mut blurred = vec![0.0; out.width * out.height];
+        for j in 0..window.height {
+            for i in 0..out.width {
+                let val = rows[j * out.width + i];
+                if val == 0.0 {
+                    continue;
+                }
+                for (k, weight) in self.kernel.iter().enumerate() {
+                    blurred[(j + k) * out.width + i] += weight * val;
+                }
+            }
+        }
+        blurred
+    }
+
+    /// the part of the values of the grown `window` which lies inside the canvas
+    fn crop(&self, values: Vec<f32>, window: Window) -> Vec<f32> {
+        let out = window.add_radius(self.radius());
+        let mut canvas = vec![0.0; self.width * self.height];
+        for (idx, val) in self.canvas_indices(out) {
+            canvas[idx] = values[val];
+        }
+        canvas
+    }
+
+    /// pairs of the index in the canvas and the index in `window` for the pixels in both
+    fn canvas_indices(&self, window: Window) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..window.height).flat_map(move |j| {
+            (0..window.width).filter_map(move |i| {
+                let x = window.x + i as i32;
+                let y = window.y + j as i32;
+                if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+                    return None;
+                }
+                Some((y as usize * self.width + x as usize, j * window.width + i))
+            })
+        })
+    }
+
+    /// change of the blurred canvas if the splines `old` are replaced by `new`,
+    /// either can be empty for splines which are added or removed
+    pub fn delta(&self, old: &[BorrowedSpline], new: &[BorrowedSpline]) -> Delta {
+        let coverages: Vec<(f32, (Window, Vec<f32>))> = old
+            .iter()
+            .map(|&spline| (-1.0, self.coverage(spline)))
+            .chain(new.iter().map(|&spline| (1.0, self.coverage(spline))))
+            .collect();
+        let Some(window) = coverages
+            .iter()
+            .map(|(_, (window, _))| *window)
+            .reduce(Window::combine)
+        else {
+            return Delta {
+                window: Window {
+                    x: 0,
+                    y: 0,
+                    width: 0,
+                    height: 0,
+                },
+                values: Vec::new(),
+            };
+        };
+        let mut difference = vec![0.0; window.width * window.height];
+        for (sign, (part, values)) in coverages {
+            let (dx, dy) = ((part.x - window.x) as usize, (part.y - window.y) as usize);
+            for j in 0..part.height {
+                for i in 0..part.width {
+                    difference[(j + dy) * window.width + i + dx] +=
+                        sign * values[j * part.width + i];
+                }
+            }
+        }
+        Delta {
+            window: window.add_radius(self.radius()),
+            values: self.blur(window, difference),
+        }
+    }
+
+    fn pixel_area(&self) -> f32 {
+        1.0 / self.scale.powi(2)
+    }
+
+    /// the change of `energy` by applying `delta`
+    pub fn energy_change(&self, delta: &Delta) -> f32 {
+        self.canvas_indices(delta.window)
+            .map(|(idx, val)| {
+                let d = delta.values[val];
+                d * (2.0 * (self.blurred[idx] - self.target[idx]) + d)
+            })
+            .sum::<f32>()
+            * self.pixel_area()
+    }
+
+    pub fn apply(&mut self, delta: Delta) {
+        for (idx, val) in self.canvas_indices(delta.window).collect::<Vec<_>>() {
+            self.blurred[idx] += delta.values[val];
+        }
+    }
+
+    /// draws all splines of `storage` on an empty canvas
+    pub fn redraw(&mut self, storage: &SplineStorage) {
+        self.blurred = vec![0.0; self.width * self.height];
+        for spline in storage.all_splines() {
+            let delta = self.delta(&[], &[spline]);
+            self.apply(delta);
+        }
+    }
+
+    /// the blurred canvas, it is kept in checkpoints as a redraw differs by rounding
+    /// from the canvas the moves have built up
+    pub fn canvas(&self) -> &[f32] {
+        &self.blurred
+    }
+
+    pub fn restore_canvas(&mut self, canvas: Vec<f32>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            canvas.len() == self.blurred.len(),
+            "the fidelity canvas has {} pixels instead of {}",
+            canvas.len(),
+            self.blurred.len()
+        );
+        self.blurred = canvas;
+        Ok(())
+    }
+
+    pub fn energy(&self) -> f32 {
+        self.blurred
+            .iter()
+            .zip(&self.target)
+            .map(|(val, target)| (val - target).powi(2))
+            .sum::<f32>()
+            * self.pixel_area()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::Spline;
+
+    use super::*;
+
+    #[test]
+    fn incremental_change_matches_redraw() {
+        let boundary = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let potential = Samples2d::from_fn(|pos| pos.x, 20, 20, boundary);
+        let mut fidelity = Fidelity::new(&potential, boundary, 32, 1.5, 0.05);
+        let mut storage = SplineStorage::new();
+        let mut rng = random::rng_from_seed(1);
+        for _ in 0..5 {
+            storage.add_spline(Spline::new_random(Vector::new(0.5, 0.5), 0.1, 2, &mut rng));
+        }
+        fidelity.redraw(&storage);
+
+        let spline_ref = storage.make_refs().remove(2);
+        let old = storage.get_owned(&spline_ref);
+        let mut new = old.clone();
+        new.translate(Vector::new(0.2, -0.1));
+        let delta = fidelity.delta(&[old.as_borrowed_spline()], &[new.as_borrowed_spline()]);
+        assert!(fidelity.energy_change(&delta) != 0.0);
+        let expected = fidelity.energy() + fidelity.energy_change(&delta);
+        fidelity.apply(delta);
+        assert!((fidelity.energy() - expected).abs() < 1e-5);
+
+        storage.overwrite(spline_ref, new);
+        let incremental = fidelity.blurred.clone();
+        fidelity.redraw(&storage);
+        for (a, b) in incremental.iter().zip(&fidelity.blurred) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn model_keeps_the_canvas_up_to_date() {
+        let log_dir = std::env::temp_dir().join("linewise_fidelity_test");
+        let mut model =
+            crate::test_util::seeded_model_with(2, &log_dir, |params| params.set_fidelity());
+        for _ in 0..3 {
+            model.run_sweep(0.1);
+        }
+        let energy = model.calc_tot_energy();
+        assert_eq!(energy.len(), model.energy_names().len());
+        assert_eq!(model.energy_names().last(), Some(&"fidelity"));
+
+        let mut fidelity = model.fidelity.clone().unwrap();
+        fidelity.redraw(&model.storage);
+        let incremental = model.fidelity.as_ref().unwrap().energy();
+        assert!((fidelity.energy() - incremental).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,282 @@
+use common::spline::{BorrowedSpline, Precomputed};
+use common::{EnergyGradient, Rect, Spline, SplineRef, Vector, quad_tree::Bounded};
+use nalgebra::Vector4;
+
+use super::{Model, SamplePartials};
+
+/// adds the derivatives with respect to the position, derivative and second derivative
+/// at one sample to the derivatives with respect to the four columns of the segment
+fn chain_rule(
+    component: &mut [Vector],
+    basis: (&Vector4<f32>, &Vector4<f32>, &Vector4<f32>),
+    partials: SamplePartials,
+) {
+    for (j, grad) in component.iter_mut().enumerate() {
+        *grad += basis.0[j] * partials.0 + basis.1[j] * partials.1 + basis.2[j] * partials.2;
+    }
+}
+
+/// the basis vectors of the position and the derivatives at every sample
+fn bases(
+    precomp: &Precomputed,
+) -> impl Iterator<Item = (&Vector4<f32>, &Vector4<f32>, &Vector4<f32>)> {
+    precomp
+        .position()
+        .zip(precomp.derivative())
+        .zip(precomp.derivative2())
+        .map(|((pos, der), der2)| (pos, der, der2))
+}
+
+impl Model {
+    fn gradient_with_neighbours(
+        &self,
+        spline: BorrowedSpline,
+        bounds: Rect,
+        filter: impl Fn(&SplineRef) -> bool,
+    ) -> EnergyGradient {
+        let ds = self.ds();
+        let mut gradient = EnergyGradient::zeros(self.terms.len(), spline.as_slice().len());
+
+        // the segments of the neighbouring splines within range
+        let neighbours = match self.pair_range() {
+            Some(range) => self.neighbour_samples(bounds, range, filter),
+            None => Vec::new(),
+        };
+
+        for (i, segment) in spline.segments().enumerate() {
+            let columns = 2 * i..2 * i + 4;
+            let samples = self.segment_samples(&segment, spline.width());
+            let bounds = segment.tight_bounds();
+            for (t, term) in self.terms.iter().enumerate() {
+                let component = &mut gradient.component_mut(t)[columns.clone()];
+                let partials = term.segment_partials(&samples, ds);
+                for (basis, (sample, partials)) in
+                    bases(&self.precomp).zip(samples.iter().zip(partials))
+                {
+                    chain_rule(component, basis, partials);
+                    if let Some(range) = term.range() {
+                        let reach = bounds.add_radius(range);
+                        let (d_pos, d_der) = neighbours
+                            .iter()
+                            .filter(|other| reach.intersects(&other.bounds))
+                            .flat_map(|other| &other.samples)
+                            .map(|other| term.pair_partials(sample, other))
+                            .fold((Vector::zeros(), Vector::zeros()), |acc, d| {
+                                (acc.0 + d.0, acc.1 + d.1)
+                            });
+                        chain_rule(
+                            component,
+                            basis,
+                            (d_pos * ds * ds, d_der * ds * ds, Vector::zeros()),
+                        );
+                    }
+                }
+            }
+        }
+
+        for (t, factor) in self.factors.iter().enumerate() {
+            gradient
+                .component_mut(t)
+                .iter_mut()
+                .for_each(|grad| *grad *= *factor);
+        }
+        gradient
+    }
+
+    /// derivatives of every component of `energy_in_place` with respect to the points and vectors
+    /// of `spline`, this is also the gradient of the total energy of the model
+    pub fn gradient_in_place(&self, spline_ref: &SplineRef, spline: &Spline) -> EnergyGradient {
+        self.gradient_with_neighbours(spline.as_borrowed_spline(), spline.bounding_box(), |p| {
+            p != spline_ref
+        })
+    }
+
+    /// derivatives of every energy component of the spline behind `spline_ref`
+    /// with respect to its points and vectors, with `Interpolation::Nearest` the sampled
+    /// terms use finite differences of the pixels instead of the derivative of the energy
+    pub fn gradient(&self, spline_ref: &SplineRef) -> EnergyGradient {
+        self.gradient_with_neighbours(
+            self.storage.get_spline(spline_ref),
+            spline_ref.bounding_box(),
+            |p| p != spline_ref,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::{Interpolation, Rect, Spline, SplineRef, Vector};
+
+    use crate::{EnergyTerm, Model, ModelParameters, Sample};
+
+    // a term which only implements the energies, the derivatives are the default ones
+    struct Crowding {
+        range: f32,
+    }
+
+    impl EnergyTerm for Crowding {
+        fn name(&self) -> &str {
+            "crowding"
+        }
+
+        fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+            samples
+                .iter()
+                .map(|s| s.position.x * s.der.norm())
+                .sum::<f32>()
+                * ds
+        }
+
+        fn range(&self) -> Option<f32> {
+            Some(self.range)
+        }
+
+        fn pair(&self, sample: &Sample, other: &Sample) -> f32 {
+            let dist = (sample.position - other.position).norm();
+            (self.range - dist).max(0.0).powi(2) * sample.der.norm() * other.der.norm()
+        }
+    }
+
+    fn test_model(
+        dir: &str,
+        build: impl FnOnce(crate::ModelBuilder) -> crate::ModelBuilder,
+    ) -> Model {
+        let params = ModelParameters::new()
+            .spline_count(12)
+            .segment_len(0.08)
+            .max_segments(3)
+            .interaction_radius(0.15)
+            .precision(20)
+            // the nearest samples are piecewise constant and have no derivative to match
+            .interpolation(Interpolation::Bilinear)
+            .energy_factors([
+                ("strain", 10.0),
+                ("bending", 0.0001),
+                ("potential", 1.0),
+                ("field", 1.0),
+                ("interaction", 0.1),
+                ("boundary", 0.0001),
+            ])
+            .build();
+        let mut model = build(
+            Model::new()
+                .add_params(params)
+                .log_dir(std::env::temp_dir().join(dir)),
+        )
+        .build()
+        .unwrap();
+        // the random placement can leave splines almost touching, where the interaction
+        // is too steep for finite differences, so they are laid out in rows instead
+        let mut k = 0;
+        model.update_splines(|_, spline| {
+            let start = Vector::new(
+                0.1 + 0.3 * (k % 3) as f32,
+                0.1 + 0.12 * (k / 3) as f32 + 0.02 * (k % 3) as f32,
+            );
+            k += 1;
+            let points = (0..=spline.count_segments())
+                .map(|j| start + Vector::new(0.07 * j as f32, 0.01 * (j % 2) as f32))
+                .collect();
+            let vectors = (0..=spline.count_segments())
+                .map(|j| Vector::new(0.02, if j % 2 == 0 { 0.01 } else { -0.01 }))
+                .collect();
+            Spline::new(points, vectors)
+        });
+        model
+    }
+
+    /// central differences of every energy component
+    fn finite_differences(model: &Model, spline_ref: &SplineRef, step: f32) -> Vec<Vec<Vector>> {
+        let coords = model
+            .get_storage()
+            .get_spline(spline_ref)
+            .as_slice()
+            .to_vec();
+        let width = model.get_storage().get_owned(spline_ref).width();
+        let terms = model.energy_names().len();
+        let mut differences = vec![vec![Vector::zeros(); terms]; coords.len()];
+        for (i, difference) in differences.iter_mut().enumerate() {
+            for axis in 0..2 {
+                let mut plus = coords.clone();
+                plus[i][axis] += step;
+                let mut minus = coords.clone();
+                minus[i][axis] -= step;
+                let e_plus =
+                    model.energy_in_place(spline_ref, &Spline::from_vec(plus).with_width(width));
+                let e_minus =
+                    model.energy_in_place(spline_ref, &Spline::from_vec(minus).with_width(width));
+                for (component, (plus, minus)) in
+                    e_plus.as_slice().iter().zip(e_minus.as_slice()).enumerate()
+                {
+                    difference[component][axis] = (plus - minus) / (2.0 * step);
+                }
+            }
+        }
+        differences
+    }
+
+    fn assert_close(model: &Model, step: f32, tolerance: f32, components: &[usize]) {
+        for spline_ref in model.spline_refs() {
+            let analytic = model.gradient(spline_ref);
+            assert!(analytic.is_finite());
+            let numeric = finite_differences(model, spline_ref, step);
+            for &component in components {
+                let analytic = analytic.component(component);
+                let scale = analytic.iter().map(|grad| grad.norm()).fold(1.0, f32::max);
+                for (a, n) in analytic.iter().zip(numeric.iter().map(|n| n[component])) {
+                    assert!(
+                        (a - n).norm() < tolerance * scale,
+                        "{} gradient: analytic {:?} numeric {:?}",
+                        model.energy_names()[component],
+                        a,
+                        n
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gradient_matches_finite_differences() {
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let model = test_model("linewise_gradient_test", |builder| {
+            builder
+                .potential_from_fn(|_| 0.5, bounds, (1, 1))
+                .field_from_fn(|_| Vector::new(0.3, -0.4), bounds, (1, 1))
+        });
+        assert_close(&model, 0.0005, 0.01, &[0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn sampled_gradient_matches_finite_differences() {
+        // the field is chosen such that the splines never become perpendicular to it
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let model = test_model("linewise_sampled_gradient_test", |builder| {
+            builder
+                .potential_from_fn(|pos| pos.x + 2.0 * pos.y.powi(2), bounds, (50, 50))
+                .field_from_fn(
+                    |pos| Vector::new(1.0 + pos.y, pos.x.powi(2)),
+                    bounds,
+                    (50, 50),
+                )
+        });
+        assert_close(&model, 0.0005, 0.05, &[2, 3]);
+    }
+
+    #[test]
+    fn added_term_has_default_gradient() {
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let model = test_model("linewise_added_term_test", |builder| {
+            builder
+                .potential_from_fn(|_| 0.5, bounds, (1, 1))
+                .add_energy_term(Crowding { range: 0.2 })
+        });
+        let crowding = model
+            .energy_names()
+            .iter()
+            .position(|&name| name == "crowding")
+            .expect("the added term is registered");
+        assert_eq!(model.params.energy_factors["crowding"], 1.0);
+        assert_close(&model, 0.0005, 0.01, &[crowding]);
+    }
+}
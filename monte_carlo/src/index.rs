@@ -0,0 +1,192 @@
+use common::{
+    AnyIndex, IndexKind, Rect, SegmentRef, SpatialIndex, SplineRef, SplineStorage,
+    quad_tree::Bounded,
+};
+use random::MyRng;
+use serde::{Deserialize, Serialize};
+
+/// The splines of the model in one spatial index and their segments in a second one.
+///
+/// The splines are drawn and moved as a whole, the pairwise terms only need the segments
+/// which are within their range, so they query the segments by their exact boxes.
+/// Every change of a spline goes through `insert` and the removals, which keep the
+/// two indices in sync.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct SplineIndex {
+    splines: AnyIndex<SplineRef>,
+    segments: AnyIndex<SegmentRef>,
+}
+
+impl SplineIndex {
+    /// `spline_width` and `segment_width` are the cell widths of a cell list
+    pub fn new(
+        kind: IndexKind,
+        spline_width: f32,
+        segment_width: f32,
+        storage: &SplineStorage,
+        refs: Vec<SplineRef>,
+    ) -> Self {
+        let segments = refs
+            .iter()
+            .flat_map(|spline_ref| storage.make_segment_refs(spline_ref))
+            .collect();
+        Self {
+            splines: AnyIndex::from_vec(kind, spline_width, refs),
+            segments: AnyIndex::from_vec(kind, segment_width, segments),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.splines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.splines.is_empty()
+    }
+
+    pub fn insert(&mut self, spline_ref: SplineRef, storage: &SplineStorage) {
+        for segment_ref in storage.make_segment_refs(&spline_ref) {
+            self.segments.insert(segment_ref)
+        }
+        self.splines.insert(spline_ref)
+    }
+
+    pub fn pop_random(&mut self, rng: &mut MyRng) -> SplineRef {
+        let spline_ref = self.splines.pop_random(rng);
+        self.remove_segments(&spline_ref);
+        spline_ref
+    }
+
+    pub fn remove(&mut self, spline_ref: &SplineRef) -> Option<SplineRef> {
+        let removed = self.splines.remove(spline_ref)?;
+        self.remove_segments(&removed);
+        Some(removed)
+    }
+
+    // the exact boxes of the segments lie inside the box of their spline
+    fn remove_segments(&mut self, spline_ref: &SplineRef) {
+        while self
+            .segments
+            .remove_where(spline_ref.bounding_box(), |segment| {
+                segment.spline() == spline_ref
+            })
+            .is_some()
+        {}
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SplineRef> {
+        self.splines.iter()
+    }
+
+    /// every spline whose box intersects `bounds`
+    pub fn query_intersects(&self, bounds: Rect) -> impl Iterator<Item = &SplineRef> {
+        self.splines.query_intersects(bounds)
+    }
+
+    /// every segment whose exact box intersects `bounds`
+    pub fn query_segments(&self, bounds: Rect) -> impl Iterator<Item = &SegmentRef> {
+        self.segments.query_intersects(bounds)
+    }
+}
+
+impl From<SplineIndex> for Vec<SplineRef> {
+    fn from(val: SplineIndex) -> Self {
+        val.splines.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SegmentSamples;
+    use crate::test_util::{seeded_model, seeded_model_with};
+
+    #[test]
+    fn segments_follow_the_splines() {
+        let log_dir = std::env::temp_dir().join("linewise_segment_index_test");
+        for kind in [IndexKind::QuadTree, IndexKind::CellList] {
+            let mut model = seeded_model_with(3, &log_dir, |params| {
+                params
+                    .spatial_index(kind)
+                    .topology_rate(0.3)
+                    .birth_death_rate(0.2)
+            });
+            for _ in 0..5 {
+                model.run_sweep(0.1);
+            }
+            let tot = model.calc_tot_energy().tot();
+
+            let (storage, index) = (&model.storage, &model.splines);
+            let segments: usize = index
+                .iter()
+                .map(|spline_ref| storage.get_spline(spline_ref).count_segments())
+                .sum();
+            assert_eq!(index.segments.len(), segments);
+            for segment in index.segments.iter() {
+                assert!(
+                    index
+                        .iter()
+                        .any(|spline_ref| spline_ref == segment.spline())
+                );
+                assert_eq!(
+                    storage.get_segment(segment).tight_bounds(),
+                    segment.bounding_box()
+                );
+            }
+
+            // the energy from the neighbours in the index is the one of all pairs
+            let samples: Vec<_> = storage
+                .all_splines()
+                .map(|spline| model.spline_samples(spline))
+                .collect();
+            let mut expected = 0.0;
+            for (i, spline) in samples.iter().enumerate() {
+                for segment in spline {
+                    expected += model.segment_energy(&segment.samples).tot();
+                }
+                for other in &samples[i + 1..] {
+                    expected += model.pair_energy(spline, other).tot();
+                }
+            }
+            assert!((tot - expected).abs() <= 1e-4 * expected.abs().max(1.0));
+        }
+    }
+
+    #[test]
+    fn cell_list_sees_the_same_energy() {
+        let log_dir = std::env::temp_dir().join("linewise_cell_list_test");
+        let mut tree_model = seeded_model(5, &log_dir);
+        let mut cell_model = seeded_model_with(5, &log_dir, |params| {
+            params.spatial_index(IndexKind::CellList)
+        });
+        let tree_energy = tree_model.calc_tot_energy().tot();
+        let cell_energy = cell_model.calc_tot_energy().tot();
+        assert!((tree_energy - cell_energy).abs() <= 1e-4 * tree_energy.abs().max(1.0));
+        for _ in 0..5 {
+            cell_model.run_sweep(0.1);
+        }
+        assert_eq!(cell_model.count_splines(), 20);
+        assert!(cell_model.calc_tot_energy().is_finite());
+    }
+
+    #[test]
+    fn culled_pairs_carry_no_energy() {
+        let model = seeded_model(11, &std::env::temp_dir().join("linewise_culling_test"));
+        // every sample of a spline in one segment whose box covers everything
+        let unculled = |samples: Vec<SegmentSamples>| {
+            vec![SegmentSamples {
+                bounds: Rect::new(-10.0, 10.0, -10.0, 10.0),
+                samples: samples.into_iter().flat_map(|s| s.samples).collect(),
+            }]
+        };
+        let splines: Vec<_> = model.storage.all_splines().collect();
+        for (i, spline) in splines.iter().enumerate() {
+            for other in &splines[i + 1..] {
+                let (a, b) = (model.spline_samples(*spline), model.spline_samples(*other));
+                let culled = model.pair_energy(&a, &b).tot();
+                let all = model.pair_energy(&unculled(a), &unculled(b)).tot();
+                assert!((culled - all).abs() <= 1e-4 * all.abs().max(1.0));
+            }
+        }
+    }
+}
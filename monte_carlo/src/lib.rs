@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::f32::consts::TAU;
 use std::fmt::Display;
 use std::path::Path;
@@ -7,53 +8,137 @@ use std::sync::mpsc::Sender;
 use std::{fs, io::Write, path::PathBuf};
 
 use anyhow::anyhow;
-use common::spline::Precomputed;
+use common::spline::{BorrowedSpline, MatrixGenerator, Precomputed};
 use common::storage::SplineInfo;
 use random::{MyRng, Rng, gaussian_vector};
 use ron::ser::{PrettyConfig, to_string_pretty};
 use serde::{Deserialize, Serialize};
-use svg::{Document, Node, node::element::Group};
+use svg::{
+    Document, Node,
+    node::element::{ClipPath, Definitions, Group},
+};
 
 use common::{
-    CLEAR_LINE, Energy, MOVE_UP, PIXEL_PER_CM, QuadTree, Rect, Samples2d, Segment, Spline,
-    SplineRef, SplineStorage, Vector, plt, quad_tree::Bounded,
+    CLEAR_LINE, Energy, IndexKind, Interpolation, MOVE_UP, PIXEL_PER_CM, Rect, Samples2d, Segment,
+    Shape, Spline, SplineRef, SplineStorage, Vector, plt, quad_tree::Bounded,
 };
 
 mod builder;
+mod checkpoint;
+mod color;
+mod config;
+mod fidelity;
+mod gradient;
+mod index;
+mod orientation;
+mod parallel;
+mod preprocess;
+mod tempering;
+mod terms;
+mod topology;
+
+#[cfg(test)]
+mod test_util;
 
 use builder::{ModelBuilder, ParamBuilder};
+pub use color::{ColorMode, ColorModel, CrossInteraction};
+use fidelity::{Delta, Fidelity};
+use index::SplineIndex;
+pub use orientation::OrientationField;
+pub use preprocess::Preprocessing;
+pub use tempering::ReplicaExchange;
+pub use terms::{
+    BendingTerm, BoundaryTerm, EnergyTerm, FieldMode, FieldTerm, InteractionTerm, PotentialTerm,
+    Sample, SamplePartials, StrainTerm, WidthTerm,
+};
+
+pub const METHODS: usize = 7;
 
-pub const METHODS: usize = 6;
+// share of the storage taken by removed splines above which it is compacted after a sweep
+const MAX_FRAGMENTATION: f32 = 0.25;
 
-#[derive(Serialize, Deserialize)]
+/// the samples of a segment with the exact box around the segment,
+/// used to skip the pairs of segments which are out of the range of a term
+pub(crate) struct SegmentSamples {
+    bounds: Rect,
+    samples: Vec<Sample>,
+}
+
+/// fields missing from older parameter files take the defaults of the builder
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ModelParameters {
     spline_count: usize,
     segment_len: f32,
     max_segments: usize,
     interaction_radius: f32,
-    energy_factors: Energy,
+    #[serde(deserialize_with = "config::deserialize_energy_factors")]
+    energy_factors: BTreeMap<String, f32>,
     precision: usize,
     temp_range: (f32, f32),
     temp_steps: usize,
     sweeps_per_temp: usize,
+    seed: Option<u64>,
+    checkpoint_interval: usize,
+    swap_interval: usize,
+    threads: usize,
+    width_range: (f32, f32),
+    birth_death_rate: f32,
+    chemical_potential: f32,
+    topology_rate: f32,
+    // without it in the file it is twice the default and not the loaded segment length
+    merge_radius: f32,
+    fidelity_resolution: usize,
+    fidelity_blur: f32,
+    vary_widths: bool,
+    fidelity: bool,
+    no_crossings: bool,
+    spatial_index: IndexKind,
+    preprocessing: Preprocessing,
+    orientation_field: Option<OrientationField>,
+    field_mode: FieldMode,
+    field_angle: f32,
+    interpolation: Interpolation,
     make_plots: bool,
     save_parameters: bool,
+    save_checkpoints: bool,
     save_start_svg: bool,
     save_step_svg: bool,
     save_end_svg: bool,
     time: bool,
 }
 
+impl Default for ModelParameters {
+    fn default() -> Self {
+        ModelParameters::new().build()
+    }
+}
+
 impl ModelParameters {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> ParamBuilder {
         Default::default()
     }
 
+    /// an index of the splines and their segments of the kind from `spatial_index`,
+    /// the cells of a cell list are as wide as the longest spline or segment
+    /// or the interaction radius
+    pub(crate) fn make_index(&self, storage: &SplineStorage, refs: Vec<SplineRef>) -> SplineIndex {
+        let spline_width =
+            (self.max_segments as f32 * self.segment_len).max(self.interaction_radius);
+        let segment_width = self.segment_len.max(self.interaction_radius);
+        SplineIndex::new(
+            self.spatial_index,
+            spline_width,
+            segment_width,
+            storage,
+            refs,
+        )
+    }
+
     pub fn get_temps(&self) -> Vec<f32> {
         if self.temp_steps == 1 {
-            let mut vec = Vec::new();
-            vec.push(self.temp_range.0);
-            return vec;
+            return vec![self.temp_range.0];
         }
         (0..self.temp_steps)
             .map(|i| {
@@ -67,12 +152,14 @@ impl ModelParameters {
     }
 }
 
+/// the last method changes the width, it is only used with `vary_width`
 pub fn vary_spline(
     spline: &mut Spline,
     transition_scale: [f32; METHODS],
+    vary_width: bool,
     rng: &mut MyRng,
 ) -> usize {
-    let method = rng.random_range(0..METHODS);
+    let method = rng.random_range(0..if vary_width { METHODS } else { METHODS - 1 });
     match method {
         0 => spline.translate(gaussian_vector(rng) * transition_scale[0]),
         1 => spline.rotate((rng.random::<f32>() - 0.5) * transition_scale[1] * TAU),
@@ -83,10 +170,13 @@ pub fn vary_spline(
         3 => spline.scales_vecs(1.0 - (rng.random::<f32>() - 0.5) * 2.0 * transition_scale[3]),
         4 => spline.scales_vecs_random(transition_scale[4], rng),
         5 => spline.stretch(1.0 - (rng.random::<f32>() - 0.5) * 2.0 * transition_scale[5] / 1.0),
+        6 => spline
+            .set_width(spline.width() + (rng.random::<f32>() - 0.5) * 2.0 * transition_scale[6]),
         METHODS.. => unreachable!(),
     }
     method
 }
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SvgParams {
     format: (f32, f32),
     margins: (f32, f32),
@@ -107,6 +197,14 @@ impl AcceptanceCounter {
         self.0[method][result] += 1;
     }
 
+    pub fn merge(&mut self, other: &Self) {
+        for (counts, other_counts) in self.0.iter_mut().zip(other.0) {
+            for (count, other_count) in counts.iter_mut().zip(other_counts) {
+                *count += other_count
+            }
+        }
+    }
+
     pub fn update_transitions(&self, transitions: &mut TransitionScales) {
         for (rates, transition) in self.0.iter().zip(transitions.0.iter_mut()) {
             let rate = rates[Self::REJECTED] as f32 / rates.iter().sum::<u32>() as f32;
@@ -136,7 +234,7 @@ impl AcceptanceCounter {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransitionScales([f32; METHODS]);
 
 impl Display for TransitionScales {
@@ -150,27 +248,73 @@ impl Display for TransitionScales {
 }
 
 pub struct Model {
-    field: Samples2d<Vector>,
-    potential: Samples2d<f32>,
+    field: Arc<Samples2d<Vector>>,
+    potential: Arc<Samples2d<f32>>,
     storage: SplineStorage,
     markings: SplineInfo<bool>,
-    splines: QuadTree<SplineRef>,
+    splines: SplineIndex,
     params: ModelParameters,
     svg_params: SvgParams,
     precomp: Precomputed,
     boundary: Rect,
+    // the region inside `boundary` the splines are kept in
+    shape: Arc<Shape>,
+    terms: Vec<Arc<dyn EnergyTerm>>,
+    // the factors of the terms from the parameters
+    factors: Vec<f32>,
+    fidelity: Option<Fidelity>,
     energies: Vec<Energy>,
     acceptance_couter: AcceptanceCounter,
     transition_scales: TransitionScales,
     rates: Vec<[f32; 3]>,
+    temp_idx: usize,
+    sweep: usize,
     rng: MyRng,
     log_dir: PathBuf,
 }
 
 impl Model {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> ModelBuilder {
         ModelBuilder::default()
     }
+
+    /// an independent copy of the current configuration with its own random number generator,
+    /// the samples are shared and the logs start empty
+    pub fn fork(&self, seed: u64) -> Model {
+        let storage = self.storage.clone();
+        Model {
+            field: Arc::clone(&self.field),
+            potential: Arc::clone(&self.potential),
+            splines: self.params.make_index(&storage, storage.make_refs()),
+            markings: storage.default_spline_info(),
+            storage,
+            params: self.params.clone(),
+            svg_params: self.svg_params.clone(),
+            precomp: MatrixGenerator::precompute_mats(self.params.precision),
+            boundary: self.boundary,
+            shape: Arc::clone(&self.shape),
+            terms: self.terms.clone(),
+            factors: self.factors.clone(),
+            fidelity: self.fidelity.clone(),
+            energies: Vec::new(),
+            acceptance_couter: AcceptanceCounter::zeros(),
+            transition_scales: self.transition_scales.clone(),
+            rates: Vec::new(),
+            temp_idx: 0,
+            sweep: 0,
+            rng: random::rng_from_seed(seed),
+            log_dir: self.log_dir.clone(),
+        }
+    }
+
+    /// exchanges the splines with `other`, everything else stays
+    pub fn swap_configuration(&mut self, other: &mut Model) {
+        std::mem::swap(&mut self.storage, &mut other.storage);
+        std::mem::swap(&mut self.splines, &mut other.splines);
+        std::mem::swap(&mut self.markings, &mut other.markings);
+        std::mem::swap(&mut self.fidelity, &mut other.fidelity);
+    }
 }
 
 impl Model {
@@ -195,153 +339,289 @@ impl Model {
 
 // the energy terms
 impl Model {
-    fn potential_term(&self, potential_sum: &mut f32, position: Vector, der_norm: f32) {
-        if let Some(sample) = self.potential.get_sample(position) {
-            *potential_sum += sample * der_norm
+    /// registers an additional energy term, its factor is taken from `energy_factors`
+    /// or added there as 1 if the parameters have none for it,
+    /// a resumed run gets its terms from `Model::resume_with_terms` instead
+    pub fn add_energy_term(&mut self, term: impl EnergyTerm + 'static) {
+        self.register_term(Arc::new(term))
+    }
+
+    fn register_term(&mut self, term: Arc<dyn EnergyTerm>) {
+        let factor = *self
+            .params
+            .energy_factors
+            .entry(term.name().to_string())
+            .or_insert(1.0);
+        self.factors.push(factor);
+        self.terms.push(term);
+    }
+
+    fn register_default_terms(&mut self) {
+        let terms: [Arc<dyn EnergyTerm>; 6] = [
+            Arc::new(StrainTerm {
+                segment_len: self.params.segment_len,
+            }),
+            Arc::new(BendingTerm),
+            Arc::new(PotentialTerm {
+                potential: Arc::clone(&self.potential),
+            }),
+            Arc::new(FieldTerm {
+                field: Arc::clone(&self.field),
+                rotation: self.params.field_mode.rotation(self.params.field_angle),
+            }),
+            Arc::new(InteractionTerm {
+                radius: self.params.interaction_radius,
+            }),
+            Arc::new(BoundaryTerm {
+                shape: Arc::clone(&self.shape),
+            }),
+        ];
+        for term in terms {
+            self.register_term(term)
+        }
+        if self.params.vary_widths {
+            self.register_term(Arc::new(WidthTerm {
+                potential: Arc::clone(&self.potential),
+                width_range: self.params.width_range,
+            }))
         }
     }
 
-    fn length_term(&self, length_sum: &mut f32, der_norm: f32) {
-        *length_sum += der_norm;
+    /// compares the rendered splines with the image if `fidelity` is set in the parameters,
+    /// it is not an `EnergyTerm` as it depends on all splines at once
+    fn init_fidelity(&mut self) {
+        if !self.params.fidelity {
+            return;
+        }
+        let mut fidelity = Fidelity::new(
+            &self.potential,
+            self.boundary,
+            self.params.fidelity_resolution,
+            self.params.fidelity_blur,
+            Self::LINE_WIDTH_FACTOR * self.params.segment_len,
+        );
+        fidelity.redraw(&self.storage);
+        self.fidelity = Some(fidelity);
     }
 
-    fn bending_term(&self, bending_sum: &mut f32, der: Vector, der2: Vector, der_norm: f32) {
-        *bending_sum += (der.x * der2.y - der2.x * der.y).powi(2) / der_norm.powi(5);
+    fn fidelity_factor(&self) -> f32 {
+        self.params
+            .energy_factors
+            .get("fidelity")
+            .copied()
+            .unwrap_or(1.0)
     }
 
-    fn field_term(&self, field_sum: &mut f32, position: Vector, der: Vector) {
-        if let Some(vector) = self.field.get_sample(position) {
-            *field_sum -= der.dot(vector).abs();
+    /// the weighted change of the fidelity energy if `old` is replaced by `new`
+    /// and the change of the canvas to apply if the move is accepted
+    fn fidelity_delta(
+        &self,
+        old: &[BorrowedSpline],
+        new: &[BorrowedSpline],
+    ) -> (f32, Option<Delta>) {
+        match &self.fidelity {
+            Some(fidelity) => {
+                let delta = fidelity.delta(old, new);
+                (
+                    self.fidelity_factor() * fidelity.energy_change(&delta),
+                    Some(delta),
+                )
+            }
+            None => (0.0, None),
         }
     }
 
-    fn interaction_potential(&self, dist: f32) -> f32 {
-        if dist < self.params.interaction_radius {
-            let ratio = self.params.interaction_radius / 2.0_f32.powf(1.0 / 6.0) / dist;
-            ratio.powi(12) - ratio.powi(6) - 1.0 / 4.0
-        } else {
-            0.0
+    fn apply_fidelity(&mut self, delta: Option<Delta>) {
+        if let (Some(fidelity), Some(delta)) = (&mut self.fidelity, delta) {
+            fidelity.apply(delta)
         }
     }
 
-    pub fn boundary_term(&self, boundary_sum: &mut f32, position: Vector) {
-        let signed_dist = self.boundary.signed_distance(position);
-        if signed_dist > 0.0 {
-            *boundary_sum = f32::INFINITY;
-        } else {
-            *boundary_sum += 1.0 / signed_dist.powi(2)
+    /// the names of the terms in the order of the values of `Energy`
+    pub fn energy_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.terms.iter().map(|term| term.name()).collect();
+        if self.fidelity.is_some() {
+            names.push("fidelity")
         }
+        names
+    }
+
+    /// the largest range of the pairwise terms
+    fn pair_range(&self) -> Option<f32> {
+        self.terms
+            .iter()
+            .filter_map(|term| term.range())
+            .reduce(f32::max)
+    }
+
+    fn ds(&self) -> f32 {
+        1.0 / self.params.precision as f32
+    }
+
+    fn segment_samples(&self, segment: &Segment, width: f32) -> Vec<Sample> {
+        segment
+            .all_iters_p(&self.precomp)
+            .map(|(position, der, der2)| Sample {
+                position,
+                der,
+                der2,
+                width,
+            })
+            .collect()
+    }
+
+    fn spline_samples(&self, spline: BorrowedSpline) -> Vec<SegmentSamples> {
+        spline
+            .segments()
+            .map(|segment| SegmentSamples {
+                bounds: segment.tight_bounds(),
+                samples: self.segment_samples(&segment, spline.width()),
+            })
+            .collect()
+    }
+
+    /// the segments of the splines accepted by `filter` whose exact boxes are within
+    /// `range` of `bounds`, they are found in the index of the segments
+    fn neighbour_samples(
+        &self,
+        bounds: Rect,
+        range: f32,
+        filter: impl Fn(&SplineRef) -> bool,
+    ) -> Vec<SegmentSamples> {
+        self.splines
+            .query_segments(bounds.add_radius(range))
+            .filter(|segment| filter(segment.spline()))
+            .map(|segment| SegmentSamples {
+                bounds: segment.bounding_box(),
+                samples: self.segment_samples(
+                    &self.storage.get_segment(segment),
+                    self.storage.widths()[segment.spline()],
+                ),
+            })
+            .collect()
+    }
+
+    /// the samples of every spline
+    fn all_samples(&self) -> Vec<Sample> {
+        self.storage
+            .all_splines()
+            .flat_map(|spline| {
+                self.spline_samples(spline)
+                    .into_iter()
+                    .flat_map(|segment| segment.samples)
+            })
+            .collect()
     }
 }
 
 // energy calculation methods
 impl Model {
-    pub fn calculate_energy_from_segment(&self, segment: Segment) -> Energy {
-        let mut length_sum = 0.0;
-        let mut bending_sum = 0.0;
-        let mut potential_sum = 0.0;
-        let mut field_sum = 0.0;
-        let mut boundary_sum = 0.0;
-        for (position, der, der2) in segment.all_iters_p(&self.precomp) {
-            let der_norm = der.norm();
-            self.length_term(&mut length_sum, der_norm);
-            self.bending_term(&mut bending_sum, der, der2, der_norm);
-            self.potential_term(&mut potential_sum, position, der_norm);
-            self.field_term(&mut field_sum, position, der);
-            self.boundary_term(&mut boundary_sum, position);
-        }
-
-        let len = length_sum / self.params.precision as f32;
-        Energy {
-            strain_energy: self.params.energy_factors.strain_energy
-                * len
-                * ((self.params.segment_len - len) / self.params.segment_len).powi(2)
-                / 2.0,
-            bending_energy: self.params.energy_factors.bending_energy * bending_sum
-                / self.params.precision as f32,
-            potential_energy: self.params.energy_factors.potential_energy * potential_sum
-                / self.params.precision as f32,
-            field_energy: self.params.energy_factors.field_energy * field_sum
-                / self.params.precision as f32,
-            interaction_energy: 0.0,
-            boundary_energy: self.params.energy_factors.boundary_energy * boundary_sum
-                / self.params.precision as f32,
-        }
+    pub fn segment_energy(&self, samples: &[Sample]) -> Energy {
+        let ds = self.ds();
+        Energy::from_vec(
+            self.terms
+                .iter()
+                .zip(&self.factors)
+                .map(|(term, factor)| factor * term.segment(samples, ds))
+                .collect(),
+        )
     }
 
-    pub fn energy_for_delta(&self, spline: &Spline) -> Energy {
-        let mut energy = Energy::zero();
-        for segment in spline.segments() {
-            energy += self.calculate_energy_from_segment(segment)
-        }
-        let mut interaction_sum = 0.0;
-        for other in self
-            .splines
-            .query_intersects(
-                spline
-                    .bounding_box()
-                    .add_radius(self.params.interaction_radius),
-            )
-            .filter(|&p| self.storage.is_empty(&p))
-            .collect::<Vec<&SplineRef>>()
-        {
-            for other_segment in self.storage.get_segments(other) {
-                for (o_pos, o_der) in other_segment.pos_and_der_iter_p(&self.precomp) {
-                    let mut inner_sum = 0.0;
-                    for my_segment in spline.segments() {
-                        for (m_pos, m_der) in my_segment.pos_and_der_iter_p(&self.precomp) {
-                            inner_sum +=
-                                self.interaction_potential((m_pos - o_pos).norm()) * m_der.norm();
+    /// the pairwise terms between two groups of segments,
+    /// only pairs of segments within the range of a term are visited
+    fn pair_energy(&self, samples: &[SegmentSamples], other: &[SegmentSamples]) -> Energy {
+        let ds = self.ds();
+        Energy::from_vec(
+            self.terms
+                .iter()
+                .zip(&self.factors)
+                .map(|(term, factor)| {
+                    let Some(range) = term.range() else {
+                        return 0.0;
+                    };
+                    let mut pair_sum = 0.0;
+                    for o_segment in other {
+                        let reach = o_segment.bounds.add_radius(range);
+                        for m_segment in samples.iter().filter(|m| reach.intersects(&m.bounds)) {
+                            for o_sample in &o_segment.samples {
+                                for m_sample in &m_segment.samples {
+                                    pair_sum += term.pair(m_sample, o_sample);
+                                }
+                            }
                         }
                     }
-                    interaction_sum += inner_sum * o_der.norm()
-                }
-            }
+                    factor * pair_sum * ds * ds
+                })
+                .collect(),
+        )
+    }
+
+    fn energy_with_neighbours(
+        &self,
+        spline: BorrowedSpline,
+        bounds: Rect,
+        filter: impl Fn(&SplineRef) -> bool,
+    ) -> Energy {
+        let samples = self.spline_samples(spline);
+        let mut energy = Energy::zero(self.terms.len());
+        for segment in &samples {
+            energy += self.segment_energy(&segment.samples)
+        }
+        if let Some(range) = self.pair_range() {
+            let neighbours = self.neighbour_samples(bounds, range, filter);
+            energy += self.pair_energy(&samples, &neighbours);
         }
-        energy.interaction_energy = self.params.energy_factors.interaction_energy * interaction_sum
-            / self.params.precision.pow(2) as f32;
         energy
     }
 
-    pub fn energy_for_tot(&self, spline: &SplineRef) -> Energy {
-        let mut energy = Energy::zero();
-
-        for segment in self.storage.get_segments(&spline) {
-            energy += self.calculate_energy_from_segment(segment)
-        }
-        let mut interaction_sum = 0.0;
-        for other in self
-            .splines
-            .query_intersects(
-                spline
-                    .bounding_box()
-                    .add_radius(self.params.interaction_radius),
-            )
-            .filter(|&p| *spline < *p)
-            .collect::<Vec<&SplineRef>>()
-        {
-            for other_segment in self.storage.get_segments(other) {
-                for (o_pos, o_der) in other_segment.pos_and_der_iter_p(&self.precomp) {
-                    let mut inner_sum = 0.0;
-                    for my_segment in self.storage.get_segments(spline) {
-                        for (m_pos, m_der) in my_segment.pos_and_der_iter_p(&self.precomp) {
-                            let dist = (m_pos - o_pos).norm();
-                            let term = self.interaction_potential(dist) * m_der.norm();
-                            inner_sum += term;
-                        }
+    pub fn energy_for_delta(&self, spline: &Spline) -> Energy {
+        self.energy_with_neighbours(spline.as_borrowed_spline(), spline.bounding_box(), |p| {
+            !self.storage.is_empty(p)
+        })
+    }
+
+    /// energy of several checked out splines with the splines in the tree and with each other
+    pub fn energy_for_group(&self, splines: &[&Spline]) -> Energy {
+        let mut energy = Energy::zero(self.terms.len());
+        for spline in splines {
+            energy += self.energy_for_delta(spline);
+        }
+        if let Some(range) = self.pair_range() {
+            let samples: Vec<_> = splines
+                .iter()
+                .map(|spline| self.spline_samples(spline.as_borrowed_spline()))
+                .collect();
+            for i in 0..splines.len() {
+                let reach = splines[i].bounding_box().add_radius(range);
+                for j in i + 1..splines.len() {
+                    if reach.intersects(&splines[j].bounding_box()) {
+                        energy += self.pair_energy(&samples[i], &samples[j]);
                     }
-                    interaction_sum += inner_sum * o_der.norm()
                 }
             }
         }
-        energy.interaction_energy = self.params.energy_factors.interaction_energy * interaction_sum
-            / self.params.precision.pow(2) as f32;
         energy
     }
 
+    /// energy `spline` would have if it replaced the spline behind `spline_ref`,
+    /// without checking anything out of the storage
+    pub fn energy_in_place(&self, spline_ref: &SplineRef, spline: &Spline) -> Energy {
+        self.energy_with_neighbours(spline.as_borrowed_spline(), spline.bounding_box(), |p| {
+            p != spline_ref
+        })
+    }
+
+    pub fn energy_for_tot(&self, spline: &SplineRef) -> Energy {
+        self.energy_with_neighbours(
+            self.storage.get_spline(spline),
+            spline.bounding_box(),
+            |p| *spline < *p,
+        )
+    }
+
     pub fn calc_tot_energy(&mut self) -> Energy {
         self.markings.iter_mut().for_each(|val| *val = false);
-        let mut summed_energy = Energy::zero();
+        let mut summed_energy = Energy::zero(self.terms.len());
         for spline in self.splines.iter() {
             let res = self.energy_for_tot(spline);
             if !res.is_finite() {
@@ -351,6 +631,11 @@ impl Model {
 
             summed_energy += res;
         }
+        if let Some(fidelity) = &self.fidelity {
+            let mut values = summed_energy.as_slice().to_vec();
+            values.push(self.fidelity_factor() * fidelity.energy());
+            summed_energy = Energy::from_vec(values);
+        }
         summed_energy
     }
 
@@ -362,70 +647,220 @@ impl Model {
 
 impl Model {
     pub fn take_mc_step(&mut self, temp: f32) {
+        if self.params.birth_death_rate > 0.0
+            && self.rng.random::<f32>() < self.params.birth_death_rate
+        {
+            return self.take_birth_death_step(temp);
+        }
+        if self.params.topology_rate > 0.0 && self.rng.random::<f32>() < self.params.topology_rate {
+            return self.take_topology_step(temp);
+        }
+        if self.splines.is_empty() {
+            return;
+        }
         let mut spline = self.storage.read(self.splines.pop_random(&mut self.rng));
         let e_0 = self.energy_for_delta(&spline).tot();
+        let old = self.fidelity.as_ref().map(|_| spline.clone());
 
-        let method = vary_spline(&mut spline, self.transition_scales.0, &mut self.rng);
+        let method = vary_spline(
+            &mut spline,
+            self.transition_scales.0,
+            self.params.vary_widths,
+            &mut self.rng,
+        );
+        if self.crosses_neighbours(&spline) {
+            self.acceptance_couter
+                .increase(method, AcceptanceCounter::REJECTED);
+            self.splines
+                .insert(self.storage.revalidate_ref(spline), &self.storage);
+            return;
+        }
 
         let e_1 = self.energy_for_delta(&spline).tot();
 
-        let d_e = e_1 - e_0;
+        let (d_fidelity, delta) = match &old {
+            Some(old) => {
+                self.fidelity_delta(&[old.as_borrowed_spline()], &[spline.as_borrowed_spline()])
+            }
+            None => (0.0, None),
+        };
+        let d_e = e_1 - e_0 + d_fidelity;
 
         if d_e < 0.0 {
             self.acceptance_couter
                 .increase(method, AcceptanceCounter::LOWER);
-            self.splines.insert(self.storage.overwrite_spline(spline));
+            self.accept_move(spline, delta);
         } else if self.rng.random::<f32>() < (-d_e / temp).exp() {
             self.acceptance_couter
                 .increase(method, AcceptanceCounter::ACCEPTED);
-            self.splines.insert(self.storage.overwrite_spline(spline))
+            self.accept_move(spline, delta);
         } else {
             self.acceptance_couter
                 .increase(method, AcceptanceCounter::REJECTED);
-            self.splines.insert(self.storage.revalidate_ref(spline))
+            self.splines
+                .insert(self.storage.revalidate_ref(spline), &self.storage)
         }
     }
 
+    /// with `no_crossings` true if `spline` crosses one of the splines in the tree
+    /// which are not checked out
+    fn crosses_neighbours(&self, spline: &Spline) -> bool {
+        self.params.no_crossings
+            && self
+                .splines
+                .query_intersects(spline.bounding_box())
+                .filter(|&p| !self.storage.is_empty(p))
+                .any(|p| spline.crosses(&self.storage.get_spline(p)))
+    }
+
+    /// inserts a new random spline or deletes a random spline with equal probability.
+    ///
+    /// The acceptance is the one of the grand canonical ensemble with `chemical_potential`,
+    /// the volume is the area of the canvas shape in units of the squared noise of the
+    /// topology moves and the shape of a new spline enters with its proposal density,
+    /// so births and deaths sample the same measure as `vary_spline` and `Topology`.
+    pub fn take_birth_death_step(&mut self, temp: f32) {
+        let area = self.shape.area();
+        if area <= 0.0 {
+            return;
+        }
+        let volume = area / self.noise().powi(2);
+        let count = self.splines.len() as f32;
+        let chemical_potential = self.params.chemical_potential;
+        if self.rng.random::<bool>() {
+            let (spline, density) = self.propose_birth();
+            if self.crosses_neighbours(&spline) {
+                return;
+            }
+            let (d_fidelity, delta) = self.fidelity_delta(&[], &[spline.as_borrowed_spline()]);
+            let d_e = self
+                .energy_with_neighbours(spline.as_borrowed_spline(), spline.bounding_box(), |_| {
+                    true
+                })
+                .tot()
+                + d_fidelity;
+            let acceptance =
+                volume / (count + 1.0) / density * ((chemical_potential - d_e) / temp).exp();
+            if self.rng.random::<f32>() < acceptance {
+                self.apply_fidelity(delta);
+                let spline_ref = self.storage.add_spline(spline);
+                self.storage.grow_spline_info(&mut self.markings);
+                self.splines.insert(spline_ref, &self.storage);
+            }
+        } else if !self.splines.is_empty() {
+            let spline = self.storage.read(self.splines.pop_random(&mut self.rng));
+            let (d_fidelity, delta) = self.fidelity_delta(&[spline.as_borrowed_spline()], &[]);
+            let d_e = -self.energy_for_delta(&spline).tot() + d_fidelity;
+            let density = self.birth_density(&spline);
+            let acceptance = count / volume * density * (-(chemical_potential + d_e) / temp).exp();
+            if self.rng.random::<f32>() < acceptance {
+                self.apply_fidelity(delta);
+                self.storage.remove_spline(spline);
+            } else {
+                self.splines
+                    .insert(self.storage.revalidate_ref(spline), &self.storage)
+            }
+        }
+    }
+
+    fn accept_move(&mut self, spline: Spline, delta: Option<Delta>) {
+        self.apply_fidelity(delta);
+        self.splines
+            .insert(self.storage.overwrite_spline(spline), &self.storage)
+    }
+
+    /// drops the slots of removed splines once they take more than `MAX_FRAGMENTATION`
+    /// of the storage, the quad tree and the markings are rebuilt with the new references
+    fn compact_storage(&mut self) {
+        if self.storage.fragmentation() <= MAX_FRAGMENTATION {
+            return;
+        }
+        let refs = self
+            .storage
+            .compact(std::mem::take(&mut self.splines).into());
+        self.splines = self.params.make_index(&self.storage, refs);
+        self.markings = self.storage.default_spline_info();
+    }
+
+    /// one Monte Carlo step per spline, then the transition scales are adapted,
+    /// with more than one thread the steps are distributed with `run_parallel_steps`
+    pub fn run_sweep(&mut self, temp: f32) {
+        if self.params.threads > 1 {
+            self.run_parallel_steps(temp);
+            // the cells keep the number of splines and segments,
+            // so the birth and death and the topology steps run afterwards
+            let count = self.splines.len() as f32;
+            for _ in 0..(count * self.params.birth_death_rate).round() as usize {
+                self.take_birth_death_step(temp);
+            }
+            for _ in 0..(count * self.params.topology_rate).round() as usize {
+                self.take_topology_step(temp);
+            }
+        } else {
+            // without splines a sweep is a single step which can only insert one
+            for _ in 0..self.splines.len().max(1) {
+                self.take_mc_step(temp);
+            }
+        }
+
+        self.compact_storage();
+
+        self.acceptance_couter
+            .update_transitions(&mut self.transition_scales);
+
+        self.rates.push(self.acceptance_couter.to_rates());
+        self.acceptance_couter.clear();
+
+        if self.params.make_plots {
+            self.log_energies()
+        }
+    }
+
+    /// runs the remaining sweeps at `temp`,
+    /// returns false if it was stopped by `stop_flag` before all sweeps were done
     pub fn run_at_temp(
         &mut self,
         temp: f32,
         tx: Option<&Sender<SplineStorage>>,
-    ) -> anyhow::Result<()> {
-        self.clear_logs();
+        stop_flag: Option<&AtomicBool>,
+    ) -> anyhow::Result<bool> {
+        if self.sweep == 0 {
+            self.clear_logs();
+        }
 
-        for j in 1..=self.params.sweeps_per_temp {
-            self.print_sweep_status(j)?;
-            for _ in 0..self.splines.len() {
-                self.take_mc_step(temp);
-            }
+        while self.sweep < self.params.sweeps_per_temp {
+            self.sweep += 1;
+            self.print_sweep_status(self.sweep)?;
+            self.run_sweep(temp);
 
             if let Some(tx) = tx {
                 tx.send(self.storage.clone())?
             }
 
-            self.acceptance_couter
-                .update_transitions(&mut self.transition_scales);
-
-            self.rates.push(self.acceptance_couter.to_rates());
-            self.acceptance_couter.clear();
-
-            if self.params.make_plots {
-                self.log_energies()
+            let stopped = stop_flag.is_some_and(|flag| flag.load(Ordering::Relaxed));
+            if self.params.save_checkpoints
+                && (stopped || self.sweep.is_multiple_of(self.params.checkpoint_interval))
+            {
+                self.save_checkpoint()?
+            }
+            if stopped {
+                return Ok(false);
             }
         }
-        Ok(())
+        Ok(true)
     }
 
+    /// runs the annealing schedule, a model from `Model::resume` continues where it stopped
     pub fn run(
         mut self,
         display_opts: Option<(Sender<SplineStorage>, Arc<AtomicBool>)>,
     ) -> anyhow::Result<()> {
-        if self.params.save_parameters {
-            let path = self.log_dir.join("parameters.ron");
-            fs::write(
-                path,
-                to_string_pretty(&self.params, PrettyConfig::default())?,
-            )?
+        let resumed = self.temp_idx != 0 || self.sweep != 0;
+        if self.params.save_parameters && !resumed {
+            self.save_parameters()?
+        }
+        if self.params.save_checkpoints && !resumed {
+            self.save_environment()?
         }
 
         if let Some((tx, _)) = &display_opts {
@@ -433,7 +868,7 @@ impl Model {
         }
 
         self.calc_tot_energy();
-        if self.params.save_start_svg {
+        if self.params.save_start_svg && !resumed {
             self.save_svg_doc("img_start.svg")?
         }
         anyhow::ensure!(
@@ -442,25 +877,33 @@ impl Model {
         );
 
         let start = cpu_time::ProcessTime::now();
-        for (i, temp) in self.params.get_temps().into_iter().enumerate() {
+        let temps = self.params.get_temps();
+        while self.temp_idx < temps.len() {
+            let (i, temp) = (self.temp_idx, temps[self.temp_idx]);
             self.print_temp_status(i + 1, temp)?;
-            self.run_at_temp(temp, display_opts.as_ref().map(|(tx, _)| tx))?;
+            let finished = self.run_at_temp(
+                temp,
+                display_opts.as_ref().map(|(tx, _)| tx),
+                display_opts
+                    .as_ref()
+                    .map(|(_, stop_flag)| stop_flag.as_ref()),
+            )?;
+            if !finished {
+                if self.params.save_end_svg && !self.params.save_step_svg {
+                    self.save_svg_doc("img_end.svg")?;
+                }
+                return Err(anyhow!("Stopped running"));
+            }
 
             if self.params.make_plots {
                 self.make_all_plots(&format!("Temp {}", temp), &format!("{}", i))?;
             }
 
             if self.params.save_step_svg {
-                self.save_svg_doc(&format!("img_{}_{}.svg", i, temp))?;
-            }
-            if let Some((_, stop_flag)) = display_opts.as_ref() {
-                if stop_flag.load(Ordering::Relaxed) {
-                    if self.params.save_end_svg && !self.params.save_step_svg {
-                        self.save_svg_doc("img_end.svg")?;
-                    }
-                    return Err(anyhow!("Stopped running"));
-                }
+                self.save_svg_doc(format!("img_{}_{}.svg", i, temp))?;
             }
+            self.temp_idx += 1;
+            self.sweep = 0;
         }
         let cpu_duration = start.elapsed();
 
@@ -484,6 +927,46 @@ impl Model {
         self.boundary
     }
 
+    pub fn get_shape(&self) -> Arc<Shape> {
+        Arc::clone(&self.shape)
+    }
+
+    pub fn get_storage(&self) -> &SplineStorage {
+        &self.storage
+    }
+
+    pub fn get_precomputed(&self) -> &Precomputed {
+        &self.precomp
+    }
+
+    pub fn get_log_dir(&self) -> &Path {
+        &self.log_dir
+    }
+
+    pub fn spline_refs(&self) -> impl Iterator<Item = &SplineRef> {
+        self.splines.iter()
+    }
+
+    /// replaces every spline by the result of `func` and rebuilds the quad tree,
+    /// the segment counts must stay the same
+    pub fn update_splines(&mut self, mut func: impl FnMut(&SplineRef, Spline) -> Spline) {
+        let refs: Vec<SplineRef> = std::mem::take(&mut self.splines).into();
+        let mut updated = Vec::with_capacity(refs.len());
+        for spline_ref in refs {
+            let spline = func(&spline_ref, self.storage.get_owned(&spline_ref));
+            updated.push(self.storage.overwrite(spline_ref, spline));
+        }
+        self.splines = self.params.make_index(&self.storage, updated);
+    }
+
+    pub fn save_parameters(&self) -> anyhow::Result<()> {
+        fs::write(
+            self.log_dir.join("parameters.ron"),
+            to_string_pretty(&self.params, PrettyConfig::default())?,
+        )?;
+        Ok(())
+    }
+
     pub fn clear_logs(&mut self) {
         self.energies = Vec::new();
         self.rates = Vec::new();
@@ -494,40 +977,64 @@ impl Model {
         Self::LINE_WIDTH_FACTOR * self.params.segment_len
     }
 
-    pub fn make_svg_group(&self) -> (Group, Rect) {
-        let mut group = Group::new();
+    /// the splines drawn in `color`, marked ones in yellow
+    pub fn make_spline_group(&self, color: &'static str) -> Group {
         let mut splines = Group::new();
-        for (spline, mark) in self.storage.all_splines().zip(self.markings.iter()) {
+        for (spline, mark) in self.storage.all_splines_with_info(&self.markings) {
             splines.append(spline.as_svg_path(
-                if *mark { "yellow" } else { "black" },
+                if *mark { "yellow" } else { color },
                 Self::LINE_WIDTH_FACTOR * self.params.segment_len,
             ))
         }
-        group.append(splines);
+        splines
+    }
+
+    /// the shape as a `clipPath` with the id `canvas`
+    pub fn make_svg_clip(&self) -> Definitions {
+        Definitions::new().add(
+            ClipPath::new()
+                .set("id", "canvas")
+                .add(self.shape.as_svg_clip()),
+        )
+    }
+
+    pub fn make_svg_group(&self) -> (Group, Rect) {
+        let mut group = Group::new();
+        group.append(self.make_svg_clip());
+        group.append(
+            self.make_spline_group("black")
+                .set("clip-path", "url(#canvas)"),
+        );
         group.append(
-            self.boundary
+            self.shape
                 .as_svg(Self::LINE_WIDTH_FACTOR * self.params.segment_len),
         );
         (group, self.boundary)
     }
 
-    pub fn make_svg_doc(&self) -> Document {
-        let mut doc = Document::new()
+    /// an empty document with the format of the svg parameters
+    pub fn make_svg_page(&self) -> Document {
+        Document::new()
             .set("width", format!("{}cm", self.svg_params.format.0))
-            .set("height", format!("{}cm", self.svg_params.format.1));
-        let (group, rect) = self.make_svg_group();
+            .set("height", format!("{}cm", self.svg_params.format.1))
+    }
+
+    /// the transform which scales `rect` into the margins and centers it on the page
+    pub fn page_transform(&self, rect: Rect) -> String {
         let scale = ((self.svg_params.format.0 - 2.0 * self.svg_params.margins.0) / rect.width())
             .min((self.svg_params.format.1 - 2.0 * self.svg_params.margins.1) / rect.height());
-        doc.append(group.set(
-            "transform",
-            format!(
-                "translate({} {}) scale({})",
-                (self.svg_params.format.0 - rect.width() * scale) / 2.0 * PIXEL_PER_CM,
-                (self.svg_params.format.1 - rect.height() * scale) / 2.0 * PIXEL_PER_CM,
-                scale * PIXEL_PER_CM
-            ),
-        ));
-        doc
+        format!(
+            "translate({} {}) scale({})",
+            (self.svg_params.format.0 - rect.width() * scale) / 2.0 * PIXEL_PER_CM,
+            (self.svg_params.format.1 - rect.height() * scale) / 2.0 * PIXEL_PER_CM,
+            scale * PIXEL_PER_CM
+        )
+    }
+
+    pub fn make_svg_doc(&self) -> Document {
+        let (group, rect) = self.make_svg_group();
+        self.make_svg_page()
+            .add(group.set("transform", self.page_transform(rect)))
     }
 
     pub fn save_svg_doc(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
@@ -542,20 +1049,85 @@ impl Model {
                 .map(|val| val.tot())
                 .collect::<Vec<_>>(),
             caption,
-            self.log_dir.join(&format!("{}_tot.png", name)),
+            self.log_dir.join(format!("{}_tot.png", name)),
         )?;
 
         plt::divergent_chart(
             &self.energies,
+            &self.energy_names(),
             caption,
-            self.log_dir.join(&format!("{}_all.png", name)),
+            self.log_dir.join(format!("{}_all.png", name)),
         )?;
 
         plt::rate_plot(
             &self.rates,
             caption,
-            self.log_dir.join(&format!("{}_rates.png", name)),
+            self.log_dir.join(format!("{}_rates.png", name)),
         )?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::{run_seeded, seeded_model_with};
+
+    #[test]
+    fn same_seed_same_svg() {
+        let first = run_seeded(42, "linewise_seed_test_a");
+        let second = run_seeded(42, "linewise_seed_test_b");
+        assert_eq!(first, second);
+        let other = run_seeded(43, "linewise_seed_test_c");
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn splines_never_cross() {
+        let log_dir = std::env::temp_dir().join("linewise_crossing_test");
+        let mut model = seeded_model_with(7, &log_dir, |params| {
+            params
+                .spline_count(60)
+                .segment_len(0.08)
+                .interaction_radius(0.01)
+                .set_no_crossings()
+        });
+        for _ in 0..10 {
+            model.run_sweep(10.0);
+        }
+        let splines: Vec<_> = model.storage.all_splines().collect();
+        for (i, spline) in splines.iter().enumerate() {
+            for other in &splines[i + 1..] {
+                assert!(!spline.crosses(other));
+            }
+        }
+    }
+
+    #[test]
+    fn splines_are_born_and_die() {
+        let log_dir = std::env::temp_dir().join("linewise_birth_death_test");
+        // a single thread, so the seed fixes every move and the counts below
+        let mut model = seeded_model_with(9, &log_dir, |params| {
+            params
+                .threads(1)
+                .interaction_radius(0.02)
+                .birth_death_rate(0.5)
+                .chemical_potential(-1000.0)
+        });
+        for _ in 0..5 {
+            model.run_sweep(0.1);
+        }
+        // a low chemical potential removes splines, 11 of the 20 are left
+        assert!((6..=16).contains(&model.count_splines()));
+        assert_eq!(model.count_splines(), model.storage.count_splines());
+
+        model.params.chemical_potential = 1000.0;
+        for _ in 0..40 {
+            model.run_sweep(0.1);
+        }
+        // and a high one adds them, there are 73 afterwards
+        assert!((50..=100).contains(&model.count_splines()));
+        assert_eq!(model.count_splines(), model.storage.count_splines());
+        assert!(model.calc_tot_energy().is_finite());
+        model.make_svg_doc();
+    }
+}
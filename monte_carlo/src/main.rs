@@ -1,29 +1,108 @@
-use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, mpsc};
-use std::{fs::File, thread};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
 
-use common::energy::Energy;
-use image::ImageFormat;
+use clap::Parser;
+use common::{Rect, SplineStorage};
 use minifb::{Key, Window, WindowOptions};
 
-use monte_carlo::{Model, ModelParameters};
+use monte_carlo::{ColorMode, ColorModel, Model, ModelParameters, ReplicaExchange};
+
+/// Fits splines to an image with simulated annealing
+#[derive(Parser)]
+struct Args {
+    /// the image the splines are fitted to
+    #[arg(default_value = "./in/fern.jpg")]
+    image: PathBuf,
+
+    /// parameter file, either the `parameters.ron` of an earlier run or a TOML file
+    /// with the same fields, without it the defaults in main.rs are used
+    #[arg(short, long)]
+    params: Option<PathBuf>,
+
+    /// overrides a single parameter, the value is written in RON,
+    /// e.g. `--set energy_factors.strain=10 --set seed=Some(3)`
+    #[arg(short = 's', long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+
+    /// directory for the output, defaults to `out/<date>`
+    #[arg(short, long)]
+    out: Option<PathBuf>,
+
+    /// runs without the window
+    #[arg(long)]
+    headless: bool,
+
+    /// runs one replica per temperature with replica exchange instead of annealing
+    #[arg(long, conflicts_with = "resume")]
+    tempering: bool,
+
+    /// one spline population per color layer of the image, either `rgb` or `cmyk`,
+    /// the svg then has one layer per pen, this always runs without the window,
+    /// a mask or checkpoints
+    #[arg(long, value_name = "MODE", conflicts_with_all = ["resume", "tempering"])]
+    color: Option<ColorMode>,
+
+    /// lets the splines of different color layers repel each other
+    #[arg(long, requires = "color")]
+    cross_interaction: bool,
+
+    /// keeps the splines in the opaque or without alpha channel in the bright pixels of
+    /// this image, the image itself can be given to cut out its subject
+    #[arg(long, value_name = "IMAGE", conflicts_with_all = ["resume", "color"])]
+    mask: Option<PathBuf>,
+
+    /// continues the run from the last checkpoint in this log directory
+    #[arg(long, conflicts_with_all = ["params", "overrides", "out"])]
+    resume: Option<PathBuf>,
+}
 
 fn main() -> anyhow::Result<()> {
-    let file = File::open("./in/fern.jpg")?;
-    let buf = BufReader::new(file);
-    let img = image::load(buf, ImageFormat::Jpeg)?;
-
-    let default_energy = Energy {
-        strain_energy: 1000.0,
-        bending_energy: 0.01,
-        potential_energy: 100.0,
-        field_energy: 1000.0,
-        interaction_energy: 500.0,
-        boundary_energy: 0.0001,
+    let args = Args::parse();
+    if let Some(mode) = args.color {
+        let img = image::open(&args.image)?;
+        return ColorModel::from_img(
+            &img,
+            mode,
+            parameters(&args)?,
+            args.cross_interaction,
+            args.out.clone(),
+        )?
+        .run();
+    }
+    let model = match &args.resume {
+        Some(log_dir) => Model::resume(log_dir)?,
+        None => new_model(&args)?,
     };
 
-    let parameters = ModelParameters::new()
+    let bounds = model.get_bounds();
+    let line_width = model.calc_linewidth();
+    let run: Box<dyn FnOnce(DisplayOpts) -> anyhow::Result<()> + Send> = if args.tempering {
+        let exchange = ReplicaExchange::new(model)?;
+        Box::new(move |display_opts| exchange.run(display_opts))
+    } else {
+        Box::new(move |display_opts| model.run(display_opts))
+    };
+    if args.headless {
+        run(None)
+    } else {
+        run_in_window(bounds, line_width, run)
+    }
+}
+
+fn default_parameters() -> ModelParameters {
+    let default_energy = [
+        ("strain", 1000.0),
+        ("bending", 0.01),
+        ("potential", 100.0),
+        ("field", 1000.0),
+        ("interaction", 500.0),
+        ("boundary", 0.0001),
+    ];
+
+    ModelParameters::new()
         .segment_len(0.005)
         .interaction_radius(0.02)
         .max_segments(8)
@@ -36,20 +115,42 @@ fn main() -> anyhow::Result<()> {
         .unset_make_plots()
         .set_save_start_svg()
         .set_save_step_svg()
-        .build();
-    let model = Model::new()
-        .add_samples_from_img(img)
-        .add_params(parameters)
-        .build()?;
+        .build()
+}
 
-    run_in_window(model)?;
-    // model.run(None)?;
-    Ok(())
+/// the parameter file or the defaults with the overrides applied
+fn parameters(args: &Args) -> anyhow::Result<ModelParameters> {
+    let mut parameters = match &args.params {
+        Some(path) => ModelParameters::load(path)?,
+        None => default_parameters(),
+    };
+    for assignment in &args.overrides {
+        parameters = parameters.apply_override(assignment)?;
+    }
+    Ok(parameters)
 }
 
-fn run_in_window(model: Model) -> anyhow::Result<()> {
-    let bounds = model.get_bounds();
-    let line_width = model.calc_linewidth();
+fn new_model(args: &Args) -> anyhow::Result<Model> {
+    let img = image::open(&args.image)?;
+    let mut builder = Model::new()
+        .add_samples_from_img(img)
+        .add_params(parameters(args)?);
+    if let Some(mask) = &args.mask {
+        builder = builder.mask_from_img(&image::open(mask)?);
+    }
+    if let Some(out) = &args.out {
+        builder = builder.log_dir(out);
+    }
+    builder.build()
+}
+
+type DisplayOpts = Option<(Sender<SplineStorage>, Arc<AtomicBool>)>;
+
+fn run_in_window(
+    bounds: Rect,
+    line_width: f32,
+    run: impl FnOnce(DisplayOpts) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<()> {
     let (tx, rx) = mpsc::channel();
 
     let window_scale = 700.0;
@@ -66,7 +167,7 @@ fn run_in_window(model: Model) -> anyhow::Result<()> {
     let sim_flag = Arc::clone(&stop_flag);
 
     let sim_thread = thread::spawn(move || -> anyhow::Result<()> {
-        model.run(Some((tx, Arc::clone(&sim_flag))))?;
+        run(Some((tx, Arc::clone(&sim_flag))))?;
         sim_flag.store(true, Ordering::Relaxed);
         Ok(())
     });
@@ -97,3 +198,16 @@ fn run_in_window(model: Model) -> anyhow::Result<()> {
         Ok(_) => Ok(()),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn color_conflicts_with_mask_and_resume() {
+        assert!(Args::try_parse_from(["monte_carlo", "--color", "rgb"]).is_ok());
+        for flag in ["--mask", "--resume"] {
+            assert!(Args::try_parse_from(["monte_carlo", "--color", "rgb", flag, "x"]).is_err());
+        }
+    }
+}
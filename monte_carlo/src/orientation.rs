@@ -0,0 +1,198 @@
+use common::Vector;
+use serde::{Deserialize, Serialize};
+
+use crate::preprocess::GrayImage;
+
+/// A field of directions from the smoothed structure tensor of the image.
+///
+/// The direction is the dominant gradient direction averaged over `scale` pixels,
+/// the length is the coherence, one where all gradients around a pixel are parallel
+/// and zero in flat or isotropic regions.
+/// Pixels with a coherence below `min_coherence` can take the direction of their
+/// surroundings by `diffusion` steps, like in an edge tangent flow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrientationField {
+    scale: f32,
+    min_coherence: f32,
+    diffusion: usize,
+}
+
+impl Default for OrientationField {
+    fn default() -> Self {
+        Self {
+            scale: 3.0,
+            min_coherence: 0.2,
+            diffusion: 0,
+        }
+    }
+}
+
+impl OrientationField {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the standard deviation in pixels of the gaussian the tensor is smoothed with
+    pub fn scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// the coherence below which a pixel is filled in from its surroundings
+    pub fn min_coherence(mut self, min_coherence: f32) -> Self {
+        self.min_coherence = min_coherence;
+        self
+    }
+
+    /// the number of steps the directions are spread into incoherent regions
+    pub fn diffusion(mut self, diffusion: usize) -> Self {
+        self.diffusion = diffusion;
+        self
+    }
+
+    /// one vector per pixel, row by row
+    pub fn compute(&self, gray: &GrayImage) -> Vec<Vector> {
+        let width = gray.width() as usize;
+        let height = gray.height() as usize;
+        let value = |i: usize, j: usize| gray.get_pixel(i as u32, j as u32).0[0];
+
+        let mut xx = Vec::with_capacity(width * height);
+        let mut xy = Vec::with_capacity(width * height);
+        let mut yy = Vec::with_capacity(width * height);
+        for j in 0..height {
+            for i in 0..width {
+                let (left, right) = (i.saturating_sub(1), (i + 1).min(width - 1));
+                let (below, above) = (j.saturating_sub(1), (j + 1).min(height - 1));
+                let d_x = (value(right, j) - value(left, j)) / (right - left).max(1) as f32;
+                let d_y = (value(i, above) - value(i, below)) / (above - below).max(1) as f32;
+                xx.push(d_x * d_x);
+                xy.push(d_x * d_y);
+                yy.push(d_y * d_y);
+            }
+        }
+        let xx = blur(xx, width, height, self.scale);
+        let xy = blur(xy, width, height, self.scale);
+        let yy = blur(yy, width, height, self.scale);
+
+        // the orientation as a vector of twice its angle, so opposite directions agree
+        let mut doubled: Vec<Vector> = (0..width * height)
+            .map(|idx| {
+                let diff = Vector::new(xx[idx] - yy[idx], 2.0 * xy[idx]);
+                let trace = xx[idx] + yy[idx];
+                // (l_1 - l_2) / (l_1 + l_2) with the eigenvalues l_1 >= l_2
+                let coherence = diff.norm() / (trace + f32::EPSILON);
+                if coherence > 0.0 {
+                    diff.normalize() * coherence
+                } else {
+                    Vector::zeros()
+                }
+            })
+            .collect();
+
+        let fixed: Vec<bool> = doubled
+            .iter()
+            .map(|vector| vector.norm() >= self.min_coherence)
+            .collect();
+        for _ in 0..self.diffusion {
+            doubled = (0..width * height)
+                .map(|idx| {
+                    if fixed[idx] {
+                        return doubled[idx];
+                    }
+                    let (i, j) = (idx % width, idx / width);
+                    let neighbours = [
+                        (i > 0).then(|| idx - 1),
+                        (i + 1 < width).then(|| idx + 1),
+                        (j > 0).then(|| idx - width),
+                        (j + 1 < height).then(|| idx + width),
+                    ];
+                    let (sum, count) = neighbours
+                        .into_iter()
+                        .flatten()
+                        .fold((Vector::zeros(), 0), |(sum, count), idx| {
+                            (sum + doubled[idx], count + 1)
+                        });
+                    sum / count.max(1) as f32
+                })
+                .collect();
+        }
+
+        doubled
+            .into_iter()
+            .map(|vector| {
+                let angle = vector.y.atan2(vector.x) / 2.0;
+                Vector::new(angle.cos(), angle.sin()) * vector.norm()
+            })
+            .collect()
+    }
+}
+
+/// a separable gaussian blur of a row by row grid, the edges are repeated
+fn blur(values: Vec<f32>, width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    if sigma <= 0.0 {
+        return values;
+    }
+    let radius = (3.0 * sigma).ceil() as isize;
+    let weights: Vec<f32> = (-radius..=radius)
+        .map(|k| (-(k as f32 / sigma).powi(2) / 2.0).exp())
+        .collect();
+    let norm: f32 = weights.iter().sum();
+
+    let pass = |values: &[f32], step: usize, len: usize| -> Vec<f32> {
+        let mut out = vec![0.0; values.len()];
+        for (idx, out) in out.iter_mut().enumerate() {
+            let pos = ((idx / step) % len) as isize;
+            let start = idx - pos as usize * step;
+            *out = weights
+                .iter()
+                .zip(-radius..=radius)
+                .map(|(weight, k)| {
+                    let other = (pos + k).clamp(0, len as isize - 1) as usize;
+                    weight * values[start + other * step]
+                })
+                .sum::<f32>()
+                / norm;
+        }
+        out
+    };
+    let horizontal = pass(&values, 1, width);
+    pass(&horizontal, width, height)
+}
+
+#[cfg(test)]
+mod test {
+    use image::{ImageBuffer, Luma};
+
+    use super::*;
+
+    #[test]
+    fn directions_follow_the_gradient() {
+        // rings around the center, the gradient points away from it
+        let rings: GrayImage = ImageBuffer::from_fn(60, 60, |x, y| {
+            let dist = Vector::new(x as f32 - 30.0, y as f32 - 30.0).norm();
+            Luma([(dist / 4.0).sin() / 2.0 + 0.5])
+        });
+        let field = OrientationField::new().scale(2.0).compute(&rings);
+        for (x, y) in [(45, 30), (30, 12), (40, 40), (18, 36)] {
+            let vector = field[y * 60 + x];
+            let radial = Vector::new(x as f32 - 30.0, y as f32 - 30.0).normalize();
+            assert!(vector.norm() > 0.5, "{} at {} {}", vector, x, y);
+            assert!(vector.normalize().dot(&radial).abs() > 0.95);
+        }
+    }
+
+    #[test]
+    fn diffusion_fills_flat_regions() {
+        // a ramp along x on the left, flat on the right
+        let image: GrayImage =
+            ImageBuffer::from_fn(40, 20, |x, _| Luma([(x.min(20) as f32 / 40.0)]));
+        let flat = OrientationField::new().compute(&image);
+        assert_eq!(flat[10 * 40 + 35], Vector::zeros());
+        assert!((flat[10 * 40 + 5].x.abs() - 1.0).abs() < 0.01);
+
+        let filled = OrientationField::new().diffusion(200).compute(&image);
+        let vector = filled[10 * 40 + 35];
+        assert!(vector.norm() > 0.3);
+        assert!(vector.normalize().x.abs() > 0.99);
+    }
+}
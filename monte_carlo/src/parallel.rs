@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::thread;
+
+use common::{Rect, Spline, SplineRef, Vector, quad_tree::Bounded};
+use random::{MyRng, Rng};
+
+use super::{AcceptanceCounter, Model, vary_spline};
+
+type Cell = (i32, i32);
+
+// the splines of one cell, the results are returned in the same order
+struct CellJob {
+    cell: Cell,
+    refs: Vec<SplineRef>,
+    rng: MyRng,
+}
+
+struct CellResult {
+    refs: Vec<SplineRef>,
+    splines: Vec<Spline>,
+    counter: AcceptanceCounter,
+}
+
+/// square cells of `width` shifted by `offset`, colored like a checkerboard
+/// with four colors so cells of the same color never touch
+struct Grid {
+    width: f32,
+    offset: Vector,
+}
+
+impl Grid {
+    fn cell_of(&self, position: Vector) -> Cell {
+        let scaled = (position - self.offset) / self.width;
+        (scaled.x.floor() as i32, scaled.y.floor() as i32)
+    }
+
+    fn rect(&self, (i, j): Cell) -> Rect {
+        let corner = self.offset + Vector::new(i as f32, j as f32) * self.width;
+        Rect::new(
+            corner.x,
+            corner.x + self.width,
+            corner.y,
+            corner.y + self.width,
+        )
+    }
+
+    fn color((i, j): Cell) -> usize {
+        (i.rem_euclid(2) + 2 * j.rem_euclid(2)) as usize
+    }
+}
+
+impl Model {
+    /// cells of the same color are at least one cell width apart,
+    /// so the width has to be at least the range of the pairwise terms
+    fn cell_width(&self) -> f32 {
+        (2.0 * self.params.max_segments as f32 * self.params.segment_len)
+            .max(self.pair_range().unwrap_or(0.0))
+    }
+
+    /// one step per spline distributed over `threads` threads.
+    ///
+    /// The boundary is split into a checkerboard with a random offset every sweep and the
+    /// four colors are updated one after another. During a color only the splines which lie
+    /// inside a cell of that color move and moves leaving the cell are rejected, everything
+    /// else is fixed. Two moving splines in different cells are further apart than the
+    /// range of the pairwise terms, so the cells are independent Metropolis chains and every
+    /// phase keeps detailed balance. Every cell draws its own random number generator
+    /// from the model, so the result does not depend on the number of threads.
+    pub(crate) fn run_parallel_steps(&mut self, temp: f32) {
+        let width = self.cell_width();
+        let grid = Grid {
+            width,
+            offset: Vector::new(self.rng.random(), self.rng.random()) * width,
+        };
+        for color in 0..4 {
+            let mut cells: BTreeMap<Cell, Vec<SplineRef>> = BTreeMap::new();
+            let mut fixed = Vec::new();
+            for spline_ref in Vec::from(std::mem::take(&mut self.splines)) {
+                let bounds = spline_ref.bounding_box();
+                let cell = grid.cell_of(bounds.get_center());
+                if Grid::color(cell) == color && grid.rect(cell).contains(&bounds) {
+                    cells.entry(cell).or_default().push(spline_ref)
+                } else {
+                    fixed.push(spline_ref)
+                }
+            }
+            // only the fixed splines are in the tree while the cells are updated
+            self.splines = self.params.make_index(&self.storage, fixed);
+            let jobs = cells
+                .into_iter()
+                .map(|(cell, refs)| CellJob {
+                    cell,
+                    refs,
+                    rng: random::rng_from_seed(self.rng.random()),
+                })
+                .collect();
+
+            let results = self.run_cells(&grid, jobs, temp);
+
+            let mut refs: Vec<SplineRef> = std::mem::take(&mut self.splines).into();
+            for result in results {
+                self.acceptance_couter.merge(&result.counter);
+                for (spline_ref, spline) in result.refs.into_iter().zip(result.splines) {
+                    refs.push(self.storage.overwrite(spline_ref, spline));
+                }
+            }
+            self.splines = self.params.make_index(&self.storage, refs);
+        }
+    }
+
+    fn run_cells(&self, grid: &Grid, jobs: Vec<CellJob>, temp: f32) -> Vec<CellResult> {
+        let threads = self.params.threads.max(1);
+        let mut chunks: Vec<Vec<(usize, CellJob)>> = (0..threads).map(|_| Vec::new()).collect();
+        for (i, job) in jobs.into_iter().enumerate() {
+            chunks[i % threads].push((i, job));
+        }
+        let mut results: Vec<(usize, CellResult)> = thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .into_iter()
+                            .map(|(i, job)| (i, self.run_cell(grid.rect(job.cell), job, temp)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("a cell thread panicked"))
+                .collect()
+        });
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    fn run_cell(&self, cell: Rect, job: CellJob, temp: f32) -> CellResult {
+        let CellJob { refs, mut rng, .. } = job;
+        let mut splines: Vec<Spline> = refs
+            .iter()
+            .map(|spline_ref| self.storage.get_owned(spline_ref))
+            .collect();
+        let mut counter = AcceptanceCounter::zeros();
+        for _ in 0..splines.len() {
+            let idx = rng.random_range(0..splines.len());
+            let e_0 = self.cell_energy(&splines, idx, &splines[idx]);
+
+            let mut spline = splines[idx].clone();
+            let method = vary_spline(
+                &mut spline,
+                self.transition_scales.0,
+                self.params.vary_widths,
+                &mut rng,
+            );
+            if !cell.contains(&spline.bounding_box())
+                || self.crosses_neighbours(&spline)
+                || (self.params.no_crossings
+                    && splines
+                        .iter()
+                        .enumerate()
+                        .any(|(i, other)| i != idx && spline.crosses(&other.as_borrowed_spline())))
+            {
+                counter.increase(method, AcceptanceCounter::REJECTED);
+                continue;
+            }
+
+            let d_e = self.cell_energy(&splines, idx, &spline) - e_0;
+
+            if d_e < 0.0 {
+                counter.increase(method, AcceptanceCounter::LOWER);
+                splines[idx] = spline;
+            } else if rng.random::<f32>() < (-d_e / temp).exp() {
+                counter.increase(method, AcceptanceCounter::ACCEPTED);
+                splines[idx] = spline;
+            } else {
+                counter.increase(method, AcceptanceCounter::REJECTED);
+            }
+        }
+        CellResult {
+            refs,
+            splines,
+            counter,
+        }
+    }
+
+    /// energy of `spline` in place of the spline at `idx` of a cell,
+    /// with the fixed splines in the quad tree and the other splines of the cell
+    fn cell_energy(&self, cell_splines: &[Spline], idx: usize, spline: &Spline) -> f32 {
+        let bounds = spline.bounding_box();
+        let mut energy = self.energy_with_neighbours(spline.as_borrowed_spline(), bounds, |_| true);
+        if let Some(range) = self.pair_range() {
+            let samples = self.spline_samples(spline.as_borrowed_spline());
+            let reach = bounds.add_radius(range);
+            for (_, other) in cell_splines
+                .iter()
+                .enumerate()
+                .filter(|(i, other)| *i != idx && reach.intersects(&other.bounding_box()))
+            {
+                let other = self.spline_samples(other.as_borrowed_spline());
+                energy += self.pair_energy(&samples, &other);
+            }
+        }
+        energy.tot()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::seeded_model_with;
+
+    fn run_threaded(threads: usize) -> (String, f32) {
+        let log_dir = std::env::temp_dir().join("linewise_parallel_test");
+        let mut model = seeded_model_with(3, &log_dir, |params| {
+            params.spline_count(30).threads(threads)
+        });
+        let start = model.make_svg_doc().to_string();
+        for _ in 0..4 {
+            model.run_sweep(0.1);
+        }
+        let end = model.make_svg_doc().to_string();
+        assert_ne!(start, end);
+        assert_eq!(model.count_splines(), 30);
+        (end, model.calc_tot_energy().tot())
+    }
+
+    #[test]
+    fn result_does_not_depend_on_thread_count() {
+        let (two, energy) = run_threaded(2);
+        assert!(energy.is_finite());
+        assert_eq!(two, run_threaded(4).0);
+    }
+}
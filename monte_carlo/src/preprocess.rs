@@ -0,0 +1,184 @@
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Luma};
+use serde::{Deserialize, Serialize};
+
+pub type GrayImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+const HISTOGRAM_BINS: usize = 256;
+
+/// The steps applied to the gray image before the potential and the field are derived.
+///
+/// Every step is optional, they run in the order of the fields:
+/// downscaling, linearization, levels, histogram equalization, blur, unsharp masking
+/// and inversion.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Preprocessing {
+    max_size: Option<u32>,
+    gamma: Option<f32>,
+    levels: Option<(f32, f32)>,
+    equalize: bool,
+    blur: Option<f32>,
+    unsharp: Option<(f32, f32)>,
+    invert: bool,
+}
+
+impl Preprocessing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// downscales the image so its longer side has at most `max_size` pixels
+    pub fn max_size(mut self, max_size: u32) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// raises every value to the power `gamma`, 2.2 linearizes sRGB values
+    pub fn gamma(mut self, gamma: f32) -> Self {
+        self.gamma = Some(gamma);
+        self
+    }
+
+    /// maps `black` to 0 and `white` to 1, values outside are clipped
+    pub fn levels(mut self, black: f32, white: f32) -> Self {
+        self.levels = Some((black, white));
+        self
+    }
+
+    /// spreads the values so they are evenly distributed
+    pub fn set_equalize(mut self) -> Self {
+        self.equalize = true;
+        self
+    }
+    pub fn unset_equalize(mut self) -> Self {
+        self.equalize = false;
+        self
+    }
+
+    /// a gaussian blur with a standard deviation of `sigma` pixels
+    pub fn blur(mut self, sigma: f32) -> Self {
+        self.blur = Some(sigma);
+        self
+    }
+
+    /// adds `amount` times the difference to the image blurred by `sigma` pixels
+    pub fn unsharp(mut self, sigma: f32, amount: f32) -> Self {
+        self.unsharp = Some((sigma, amount));
+        self
+    }
+
+    /// turns dark into bright regions and vice versa
+    pub fn set_invert(mut self) -> Self {
+        self.invert = true;
+        self
+    }
+    pub fn unset_invert(mut self) -> Self {
+        self.invert = false;
+        self
+    }
+
+    pub fn apply(&self, mut gray: GrayImage) -> GrayImage {
+        if let Some(max_size) = self.max_size {
+            let longer = gray.width().max(gray.height());
+            if longer > max_size {
+                let scale = max_size as f32 / longer as f32;
+                let width = ((gray.width() as f32 * scale).round() as u32).max(1);
+                let height = ((gray.height() as f32 * scale).round() as u32).max(1);
+                gray = imageops::resize(&gray, width, height, FilterType::Triangle);
+            }
+        }
+        if let Some(gamma) = self.gamma {
+            map_values(&mut gray, |val| val.max(0.0).powf(gamma));
+        }
+        if let Some((black, white)) = self.levels {
+            let range = (white - black).max(f32::EPSILON);
+            map_values(&mut gray, |val| ((val - black) / range).clamp(0.0, 1.0));
+        }
+        if self.equalize {
+            equalize(&mut gray);
+        }
+        if let Some(sigma) = self.blur {
+            gray = imageops::blur(&gray, sigma);
+        }
+        if let Some((sigma, amount)) = self.unsharp {
+            let blurred = imageops::blur(&gray, sigma);
+            for (val, blurred) in gray.iter_mut().zip(blurred.iter()) {
+                *val = (*val + amount * (*val - blurred)).clamp(0.0, 1.0);
+            }
+        }
+        if self.invert {
+            map_values(&mut gray, |val| 1.0 - val);
+        }
+        gray
+    }
+}
+
+fn map_values(gray: &mut GrayImage, func: impl Fn(f32) -> f32) {
+    for val in gray.iter_mut() {
+        *val = func(*val);
+    }
+}
+
+fn bin(val: f32) -> usize {
+    ((val.clamp(0.0, 1.0) * HISTOGRAM_BINS as f32) as usize).min(HISTOGRAM_BINS - 1)
+}
+
+/// maps every value to the share of the pixels which are at most as bright
+fn equalize(gray: &mut GrayImage) {
+    let mut cumulative = [0usize; HISTOGRAM_BINS];
+    for val in gray.iter() {
+        cumulative[bin(*val)] += 1;
+    }
+    for i in 1..HISTOGRAM_BINS {
+        cumulative[i] += cumulative[i - 1];
+    }
+    let lowest = cumulative.iter().copied().find(|&count| count > 0);
+    let total = gray.len();
+    let Some(lowest) = lowest.filter(|&lowest| lowest < total) else {
+        return;
+    };
+    let range = (total - lowest) as f32;
+    map_values(gray, |val| {
+        (cumulative[bin(val)].saturating_sub(lowest)) as f32 / range
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> GrayImage {
+        // a quadratic ramp, most pixels are dark
+        ImageBuffer::from_fn(width, height, |x, _| {
+            Luma([(x as f32 / (width - 1) as f32).powi(2)])
+        })
+    }
+
+    #[test]
+    fn steps_change_the_image_as_configured() {
+        let image = gradient(64, 32);
+        assert_eq!(Preprocessing::new().apply(image.clone()), image);
+
+        let small = Preprocessing::new().max_size(16).apply(image.clone());
+        assert_eq!(small.dimensions(), (16, 8));
+
+        let inverted = Preprocessing::new()
+            .levels(0.25, 0.75)
+            .set_invert()
+            .apply(image.clone());
+        assert_eq!(inverted.get_pixel(0, 0).0[0], 1.0);
+        assert_eq!(inverted.get_pixel(63, 0).0[0], 0.0);
+
+        // after equalization half of the pixels are below one half
+        let equalized = Preprocessing::new().set_equalize().apply(image.clone());
+        let dark = equalized.iter().filter(|val| **val < 0.5).count();
+        assert!((dark as f32 / equalized.len() as f32 - 0.5).abs() < 0.05);
+        assert!(equalized.iter().all(|val| (0.0..=1.0).contains(val)));
+
+        let mean = |image: &GrayImage| image.iter().sum::<f32>() / image.len() as f32;
+        let blurred = Preprocessing::new().blur(2.0).apply(image.clone());
+        assert!((mean(&blurred) - mean(&image)).abs() < 0.02);
+        let sharpened = Preprocessing::new().unsharp(2.0, 1.0).apply(image.clone());
+        assert!(sharpened.iter().all(|val| (0.0..=1.0).contains(val)));
+    }
+}
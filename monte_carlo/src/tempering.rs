@@ -0,0 +1,238 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc::Sender};
+use std::{fs, io::Write, thread};
+
+use anyhow::anyhow;
+use common::{CLEAR_LINE, SplineStorage, plt};
+use random::{MyRng, Rng};
+
+use super::Model;
+
+fn swap_rate([accepted, attempted]: [u32; 2]) -> f32 {
+    if attempted == 0 {
+        0.0
+    } else {
+        accepted as f32 / attempted as f32
+    }
+}
+
+/// Parallel tempering, one replica of the model runs at every temperature of
+/// `ModelParameters::get_temps` on its own thread and every `swap_interval` sweeps
+/// neighbouring replicas exchange their configurations with the Metropolis criterion.
+pub struct ReplicaExchange {
+    // ordered like the temperatures, from hot to cold
+    replicas: Vec<Model>,
+    temps: Vec<f32>,
+    // accepted and attempted swaps between the replicas i and i + 1
+    swaps: Vec<[u32; 2]>,
+    swap_rates: Vec<Vec<f32>>,
+    rng: MyRng,
+}
+
+impl ReplicaExchange {
+    /// all replicas start from the configuration of `model`, the replicas write no checkpoints
+    /// so `save_checkpoints` has to be off
+    pub fn new(mut model: Model) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !model.params.save_checkpoints,
+            "parallel tempering writes no checkpoints, turn off save_checkpoints"
+        );
+        let temps = model.params.get_temps();
+        let mut rng = random::rng_from_seed(model.rng.random());
+        let mut replicas: Vec<Model> = (1..temps.len()).map(|_| model.fork(rng.random())).collect();
+        replicas.insert(0, model);
+        Ok(Self {
+            swaps: vec![[0; 2]; temps.len() - 1],
+            swap_rates: vec![Vec::new(); temps.len() - 1],
+            replicas,
+            temps,
+            rng,
+        })
+    }
+
+    pub fn coldest(&self) -> &Model {
+        self.replicas.last().expect("there is at least one replica")
+    }
+
+    fn print_status(&self, sweep: usize) -> anyhow::Result<()> {
+        let rates = self
+            .swaps
+            .iter()
+            .map(|&swaps| format!("{:.2}", swap_rate(swaps)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        print!(
+            "{}running sweep {:>4}/{}    swap rates: [{}]",
+            CLEAR_LINE,
+            sweep,
+            self.coldest().params.sweeps_per_temp,
+            rates
+        );
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+
+    /// runs `sweeps` sweeps on every replica in parallel
+    fn run_replicas(&mut self, sweeps: usize) {
+        thread::scope(|scope| {
+            for (replica, &temp) in self.replicas.iter_mut().zip(&self.temps) {
+                scope.spawn(move || {
+                    for _ in 0..sweeps {
+                        replica.run_sweep(temp)
+                    }
+                });
+            }
+        });
+    }
+
+    /// proposes swaps between every second pair of neighbours, alternating with `round`
+    fn swap_replicas(&mut self, round: usize) {
+        let energies: Vec<f32> = self
+            .replicas
+            .iter_mut()
+            .map(|replica| replica.calc_tot_energy().tot())
+            .collect();
+        for i in (round % 2..self.replicas.len() - 1).step_by(2) {
+            let d_beta = 1.0 / self.temps[i] - 1.0 / self.temps[i + 1];
+            let d_e = energies[i] - energies[i + 1];
+            self.swaps[i][1] += 1;
+            if self.rng.random::<f32>() < (d_beta * d_e).exp() {
+                self.swaps[i][0] += 1;
+                let (hot, cold) = self.replicas.split_at_mut(i + 1);
+                hot[i].swap_configuration(&mut cold[0]);
+            }
+        }
+        for (rates, &swaps) in self.swap_rates.iter_mut().zip(&self.swaps) {
+            rates.push(swap_rate(swaps))
+        }
+    }
+
+    pub fn run(
+        mut self,
+        display_opts: Option<(Sender<SplineStorage>, Arc<AtomicBool>)>,
+    ) -> anyhow::Result<()> {
+        let params = &self.coldest().params;
+        let (sweeps, swap_interval) = (params.sweeps_per_temp, params.swap_interval.max(1));
+        if params.save_parameters {
+            self.coldest().save_parameters()?
+        }
+        if params.save_start_svg {
+            self.coldest().save_svg_doc("img_start.svg")?
+        }
+        anyhow::ensure!(
+            self.replicas[0].calc_tot_energy().is_finite(),
+            "initial energy needs to be finite"
+        );
+
+        let (tx, stop_flag) = match &display_opts {
+            Some((tx, stop_flag)) => (Some(tx), Some(stop_flag.as_ref())),
+            None => (None, None),
+        };
+        let start = cpu_time::ProcessTime::now();
+        let mut sweep = 0;
+        let mut round = 0;
+        while sweep < sweeps {
+            self.print_status(sweep)?;
+            let round_sweeps = swap_interval.min(sweeps - sweep);
+            self.run_replicas(round_sweeps);
+            sweep += round_sweeps;
+            self.swap_replicas(round);
+            round += 1;
+
+            if let Some(tx) = tx {
+                tx.send(self.coldest().storage.clone())?;
+            }
+            if stop_flag.is_some_and(|flag| flag.load(Ordering::Relaxed)) {
+                self.finish()?;
+                return Err(anyhow!("Stopped running"));
+            }
+        }
+        let cpu_duration = start.elapsed();
+        self.finish()?;
+
+        let log_dir = &self.coldest().log_dir;
+        let mut log = format!("took {:.3}s\nswap rates:", cpu_duration.as_secs_f32());
+        for (i, [accepted, attempted]) in self.swaps.iter().enumerate() {
+            log = format!(
+                "{}\n{:.4} <-> {:.4}: {}/{}",
+                log,
+                self.temps[i],
+                self.temps[i + 1],
+                accepted,
+                attempted
+            );
+        }
+        fs::write(log_dir.join("log.txt"), log)?;
+        println!("\nFinished Running");
+        Ok(())
+    }
+
+    /// writes the plots of every replica and the svg of the coldest
+    fn finish(&self) -> anyhow::Result<()> {
+        let coldest = self.coldest();
+        if coldest.params.save_end_svg {
+            coldest.save_svg_doc("img_end.svg")?;
+        }
+        if coldest.params.make_plots {
+            for (i, (replica, temp)) in self.replicas.iter().zip(&self.temps).enumerate() {
+                replica.make_all_plots(&format!("Temp {}", temp), &format!("replica_{}", i))?;
+            }
+        }
+        if coldest.params.make_plots && !self.swaps.is_empty() {
+            let names: Vec<String> = self
+                .temps
+                .windows(2)
+                .map(|pair| format!("{:.3} <-> {:.3}", pair[0], pair[1]))
+                .collect();
+            plt::labeled_rate_plot(
+                &self.swap_rates,
+                &names,
+                "Swap rates",
+                coldest.log_dir.join("swap_rates.png"),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::seeded_model_with;
+
+    fn run_tempering(dir: &str) -> (String, Vec<[u32; 2]>) {
+        let log_dir = std::env::temp_dir().join(dir);
+        let model = seeded_model_with(11, &log_dir, |params| {
+            params
+                .temp_range((1.0, 0.01))
+                .temp_steps(4)
+                .swap_interval(2)
+                .unset_save_checkpoints()
+        });
+        let mut exchange = ReplicaExchange::new(model).unwrap();
+        for round in 0..6 {
+            exchange.run_replicas(2);
+            exchange.swap_replicas(round);
+        }
+        exchange.finish().unwrap();
+        (
+            fs::read_to_string(log_dir.join("img_end.svg")).unwrap(),
+            exchange.swaps,
+        )
+    }
+
+    #[test]
+    fn checkpoints_are_rejected() {
+        let log_dir = std::env::temp_dir().join("linewise_tempering_checkpoint_test");
+        let model = seeded_model_with(11, &log_dir, |params| params.temp_steps(2));
+        assert!(ReplicaExchange::new(model).is_err());
+    }
+
+    #[test]
+    fn replicas_are_reproducible() {
+        let (svg, swaps) = run_tempering("linewise_tempering_test_a");
+        // alternating rounds attempt every pair three times
+        assert!(swaps.iter().all(|[_, attempted]| *attempted == 3));
+        assert_eq!(svg, run_tempering("linewise_tempering_test_b").0);
+    }
+}
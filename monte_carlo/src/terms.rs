@@ -0,0 +1,540 @@
+use std::sync::Arc;
+
+use common::{Rotation, Samples2d, Shape, Vector};
+use serde::{Deserialize, Serialize};
+
+/// the position and the derivatives of a spline at one sample and the width of the spline
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub position: Vector,
+    pub der: Vector,
+    pub der2: Vector,
+    pub width: f32,
+}
+
+/// derivatives with respect to the position, derivative and second derivative of a sample
+pub type SamplePartials = (Vector, Vector, Vector);
+
+// step of the central differences in the default derivatives
+const DIFF_STEP: f32 = 0.0001;
+
+fn cross(a: Vector, b: Vector) -> f32 {
+    a.x * b.y - b.x * a.y
+}
+
+/// One term of the energy of a model.
+///
+/// A term has a per segment contribution and optionally a pairwise one between samples of
+/// different splines. The model multiplies it with the factor stored under `name` in
+/// `ModelParameters::energy_factors`. The derivatives are used for the gradient and default
+/// to central differences, so a new term only needs `name` and `segment` or `pair`.
+pub trait EnergyTerm: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// energy of one segment given its samples, `ds` is the step of the curve parameter
+    /// between two samples, so an integral over the segment is a sum times `ds`
+    fn segment(&self, _samples: &[Sample], _ds: f32) -> f32 {
+        0.0
+    }
+
+    /// derivatives of `segment` with respect to every sample
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        let mut samples = samples.to_vec();
+        let mut partials = vec![(Vector::zeros(), Vector::zeros(), Vector::zeros()); samples.len()];
+        for (i, partial) in partials.iter_mut().enumerate() {
+            for axis in 0..2 {
+                let mut difference = |get: fn(&mut Sample) -> &mut Vector| {
+                    let original = *get(&mut samples[i]);
+                    get(&mut samples[i])[axis] = original[axis] + DIFF_STEP;
+                    let plus = self.segment(&samples, ds);
+                    get(&mut samples[i])[axis] = original[axis] - DIFF_STEP;
+                    let minus = self.segment(&samples, ds);
+                    *get(&mut samples[i]) = original;
+                    (plus - minus) / (2.0 * DIFF_STEP)
+                };
+                partial.0[axis] = difference(|s| &mut s.position);
+                partial.1[axis] = difference(|s| &mut s.der);
+                partial.2[axis] = difference(|s| &mut s.der2);
+            }
+        }
+        partials
+    }
+
+    /// distance beyond which `pair` vanishes, `None` if the term has no pairwise contribution
+    fn range(&self) -> Option<f32> {
+        None
+    }
+
+    /// energy density between a sample and a sample of another spline,
+    /// it is integrated over both splines
+    fn pair(&self, _sample: &Sample, _other: &Sample) -> f32 {
+        0.0
+    }
+
+    /// derivatives of `pair` with respect to the position and derivative of `sample`
+    fn pair_partials(&self, sample: &Sample, other: &Sample) -> (Vector, Vector) {
+        let mut sample = *sample;
+        let mut partials = (Vector::zeros(), Vector::zeros());
+        for axis in 0..2 {
+            let mut difference = |get: fn(&mut Sample) -> &mut Vector| {
+                let original = *get(&mut sample);
+                get(&mut sample)[axis] = original[axis] + DIFF_STEP;
+                let plus = self.pair(&sample, other);
+                get(&mut sample)[axis] = original[axis] - DIFF_STEP;
+                let minus = self.pair(&sample, other);
+                *get(&mut sample) = original;
+                (plus - minus) / (2.0 * DIFF_STEP)
+            };
+            partials.0[axis] = difference(|s| &mut s.position);
+            partials.1[axis] = difference(|s| &mut s.der);
+        }
+        partials
+    }
+}
+
+/// deviation of the length of a segment from `segment_len`
+pub struct StrainTerm {
+    pub segment_len: f32,
+}
+
+impl EnergyTerm for StrainTerm {
+    fn name(&self) -> &str {
+        "strain"
+    }
+
+    fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+        let len = samples.iter().map(|s| s.der.norm()).sum::<f32>() * ds;
+        len * ((self.segment_len - len) / self.segment_len).powi(2) / 2.0
+    }
+
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        let segment_len = self.segment_len;
+        let len = samples.iter().map(|s| s.der.norm()).sum::<f32>() * ds;
+        // derivative of the energy with respect to the length of the segment
+        let d_strain = (segment_len - len) * (segment_len - 3.0 * len) / segment_len.powi(2) / 2.0;
+        samples
+            .iter()
+            .map(|s| {
+                (
+                    Vector::zeros(),
+                    d_strain * s.der / s.der.norm() * ds,
+                    Vector::zeros(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// squared curvature integrated over the parameter
+pub struct BendingTerm;
+
+impl EnergyTerm for BendingTerm {
+    fn name(&self) -> &str {
+        "bending"
+    }
+
+    fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+        samples
+            .iter()
+            .map(|s| cross(s.der, s.der2).powi(2) / s.der.norm().powi(5))
+            .sum::<f32>()
+            * ds
+    }
+
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        samples
+            .iter()
+            .map(|s| {
+                let cross = cross(s.der, s.der2);
+                let der_norm = s.der.norm();
+                (
+                    Vector::zeros(),
+                    (2.0 * cross * Vector::new(s.der2.y, -s.der2.x) / der_norm.powi(5)
+                        - 5.0 * cross.powi(2) * s.der / der_norm.powi(7))
+                        * ds,
+                    2.0 * cross * Vector::new(-s.der.y, s.der.x) / der_norm.powi(5) * ds,
+                )
+            })
+            .collect()
+    }
+}
+
+/// the potential integrated along the spline
+pub struct PotentialTerm {
+    pub potential: Arc<Samples2d<f32>>,
+}
+
+impl EnergyTerm for PotentialTerm {
+    fn name(&self) -> &str {
+        "potential"
+    }
+
+    fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+        samples
+            .iter()
+            .filter_map(|s| Some(self.potential.get_sample(s.position)? * s.der.norm()))
+            .sum::<f32>()
+            * ds
+    }
+
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        samples
+            .iter()
+            .map(|s| {
+                let der_norm = s.der.norm();
+                match (
+                    self.potential.get_sample(s.position),
+                    self.potential.get_gradient(s.position),
+                ) {
+                    (Some(sample), Some((d_x, d_y))) => (
+                        Vector::new(d_x, d_y) * der_norm * ds,
+                        sample * s.der / der_norm * ds,
+                        Vector::zeros(),
+                    ),
+                    _ => (Vector::zeros(), Vector::zeros(), Vector::zeros()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// which direction of the field the splines follow, written as its name in parameter files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum FieldMode {
+    /// along the gradient of the image, across its edges
+    #[default]
+    Gradient,
+    /// along the edges of the image, perpendicular to the gradient
+    Isophote,
+    /// along the gradient turned by `field_angle` degrees
+    Rotated,
+    /// along the field given with `ModelBuilder::user_field_from_fn`
+    User,
+}
+
+impl FieldMode {
+    /// the rotation applied to the field before the tangents are compared with it
+    pub fn rotation(self, angle: f32) -> Rotation {
+        match self {
+            Self::Gradient | Self::User => Rotation::identity(),
+            Self::Isophote => Rotation::new(std::f32::consts::FRAC_PI_2),
+            Self::Rotated => Rotation::new(angle.to_radians()),
+        }
+    }
+}
+
+impl From<FieldMode> for String {
+    fn from(mode: FieldMode) -> Self {
+        format!("{:?}", mode)
+    }
+}
+
+impl TryFrom<String> for FieldMode {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match name.as_str() {
+            "Gradient" => Ok(Self::Gradient),
+            "Isophote" => Ok(Self::Isophote),
+            "Rotated" => Ok(Self::Rotated),
+            "User" => Ok(Self::User),
+            _ => Err(anyhow::anyhow!("unknown field mode {}", name)),
+        }
+    }
+}
+
+/// alignment of the spline with the rotated field, lower when parallel or antiparallel
+pub struct FieldTerm {
+    pub field: Arc<Samples2d<Vector>>,
+    pub rotation: Rotation,
+}
+
+impl EnergyTerm for FieldTerm {
+    fn name(&self) -> &str {
+        "field"
+    }
+
+    fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+        -samples
+            .iter()
+            .filter_map(|s| {
+                Some(
+                    s.der
+                        .dot(&(self.rotation * self.field.get_sample(s.position)?))
+                        .abs(),
+                )
+            })
+            .sum::<f32>()
+            * ds
+    }
+
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        samples
+            .iter()
+            .map(|s| {
+                match (
+                    self.field.get_sample(s.position),
+                    self.field.get_gradient(s.position),
+                ) {
+                    (Some(vector), Some((d_x, d_y))) => {
+                        let (vector, d_x, d_y) = (
+                            self.rotation * vector,
+                            self.rotation * d_x,
+                            self.rotation * d_y,
+                        );
+                        let sign = s.der.dot(&vector).signum();
+                        (
+                            -sign * Vector::new(s.der.dot(&d_x), s.der.dot(&d_y)) * ds,
+                            -sign * vector * ds,
+                            Vector::zeros(),
+                        )
+                    }
+                    _ => (Vector::zeros(), Vector::zeros(), Vector::zeros()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Lennard-Jones like repulsion between the splines, cut off at `radius`
+pub struct InteractionTerm {
+    pub radius: f32,
+}
+
+impl InteractionTerm {
+    fn potential(&self, dist: f32) -> f32 {
+        if dist < self.radius {
+            let ratio = self.radius / 2.0_f32.powf(1.0 / 6.0) / dist;
+            ratio.powi(12) - ratio.powi(6) - 1.0 / 4.0
+        } else {
+            0.0
+        }
+    }
+
+    fn potential_derivative(&self, dist: f32) -> f32 {
+        if dist < self.radius {
+            let ratio = self.radius / 2.0_f32.powf(1.0 / 6.0) / dist;
+            (6.0 * ratio.powi(6) - 12.0 * ratio.powi(12)) / dist
+        } else {
+            0.0
+        }
+    }
+}
+
+impl EnergyTerm for InteractionTerm {
+    fn name(&self) -> &str {
+        "interaction"
+    }
+
+    fn segment_partials(&self, samples: &[Sample], _ds: f32) -> Vec<SamplePartials> {
+        vec![(Vector::zeros(), Vector::zeros(), Vector::zeros()); samples.len()]
+    }
+
+    fn range(&self) -> Option<f32> {
+        Some(self.radius)
+    }
+
+    fn pair(&self, sample: &Sample, other: &Sample) -> f32 {
+        self.potential((sample.position - other.position).norm())
+            * sample.der.norm()
+            * other.der.norm()
+    }
+
+    fn pair_partials(&self, sample: &Sample, other: &Sample) -> (Vector, Vector) {
+        let delta = sample.position - other.position;
+        let dist = delta.norm();
+        let (der_norm, o_der_norm) = (sample.der.norm(), other.der.norm());
+        (
+            self.potential_derivative(dist) * delta / dist * der_norm * o_der_norm,
+            self.potential(dist) * o_der_norm * sample.der / der_norm,
+        )
+    }
+}
+
+/// keeps the splines inside the shape of the canvas, infinite outside
+pub struct BoundaryTerm {
+    pub shape: Arc<Shape>,
+}
+
+impl EnergyTerm for BoundaryTerm {
+    fn name(&self) -> &str {
+        "boundary"
+    }
+
+    fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+        let mut boundary_sum = 0.0;
+        for s in samples {
+            let signed_dist = self.shape.signed_distance(s.position);
+            if signed_dist > 0.0 {
+                return f32::INFINITY;
+            }
+            boundary_sum += 1.0 / signed_dist.powi(2)
+        }
+        boundary_sum * ds
+    }
+
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        samples
+            .iter()
+            .map(|s| {
+                let signed_dist = self.shape.signed_distance(s.position);
+                let d_pos = if signed_dist > 0.0 {
+                    Vector::repeat(f32::INFINITY)
+                } else {
+                    -2.0 / signed_dist.powi(3)
+                        * self.shape.signed_distance_gradient(s.position)
+                        * ds
+                };
+                (d_pos, Vector::zeros(), Vector::zeros())
+            })
+            .collect()
+    }
+}
+
+/// deviation of the stroke width from the width given by the potential,
+/// dark regions with a low potential ask for wide strokes
+pub struct WidthTerm {
+    pub potential: Arc<Samples2d<f32>>,
+    pub width_range: (f32, f32),
+}
+
+impl WidthTerm {
+    fn target(&self, potential: f32) -> f32 {
+        let (min, max) = self.width_range;
+        min + (max - min) * (1.0 - potential.clamp(0.0, 1.0))
+    }
+}
+
+impl EnergyTerm for WidthTerm {
+    fn name(&self) -> &str {
+        "width"
+    }
+
+    fn segment(&self, samples: &[Sample], ds: f32) -> f32 {
+        if samples.iter().any(|s| s.width <= 0.0) {
+            return f32::INFINITY;
+        }
+        samples
+            .iter()
+            .filter_map(|s| {
+                let target = self.target(self.potential.get_sample(s.position)?);
+                Some((s.width - target).powi(2) * s.der.norm())
+            })
+            .sum::<f32>()
+            * ds
+    }
+
+    fn segment_partials(&self, samples: &[Sample], ds: f32) -> Vec<SamplePartials> {
+        let (min, max) = self.width_range;
+        samples
+            .iter()
+            .map(|s| {
+                let der_norm = s.der.norm();
+                match (
+                    self.potential.get_sample(s.position),
+                    self.potential.get_gradient(s.position),
+                ) {
+                    (Some(potential), Some((d_x, d_y))) => {
+                        let deviation = s.width - self.target(potential);
+                        // the target is constant where the potential is clamped
+                        let d_target = if (0.0..=1.0).contains(&potential) {
+                            -(max - min) * Vector::new(d_x, d_y)
+                        } else {
+                            Vector::zeros()
+                        };
+                        (
+                            -2.0 * deviation * d_target * der_norm * ds,
+                            deviation.powi(2) * s.der / der_norm * ds,
+                            Vector::zeros(),
+                        )
+                    }
+                    _ => (Vector::zeros(), Vector::zeros(), Vector::zeros()),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::Rect;
+
+    use super::*;
+    use crate::{Model, ModelParameters};
+
+    fn field_energy(mode: FieldMode, angle: f32, der: Vector) -> f32 {
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let term = FieldTerm {
+            field: Arc::new(Samples2d::new_filled(Vector::new(1.0, 0.0), 4, 4, bounds)),
+            rotation: mode.rotation(angle),
+        };
+        let sample = Sample {
+            position: Vector::new(0.5, 0.5),
+            der,
+            der2: Vector::zeros(),
+            width: 1.0,
+        };
+        let energy = term.segment(&[sample], 1.0);
+        if energy.abs() < 1e-3 {
+            // the absolute value has a kink here
+            return energy;
+        }
+        // the derivative with respect to the tangent is the rotated field
+        let (_, d_der, _) = term.segment_partials(&[sample], 1.0)[0];
+        let step = Vector::new(1e-3, 1e-3);
+        let shifted = Sample {
+            der: der + step,
+            ..sample
+        };
+        let difference = term.segment(&[shifted], 1.0) - energy;
+        assert!((d_der.dot(&step) - difference).abs() < 1e-5);
+        energy
+    }
+
+    #[test]
+    fn field_modes_rotate_the_preferred_direction() {
+        let along_x = Vector::new(1.0, 0.0);
+        let along_y = Vector::new(0.0, 1.0);
+        assert_eq!(field_energy(FieldMode::Gradient, 0.0, along_x), -1.0);
+        assert!(field_energy(FieldMode::Gradient, 0.0, along_y).abs() < 1e-6);
+        assert!(field_energy(FieldMode::Isophote, 0.0, along_x).abs() < 1e-6);
+        assert!((field_energy(FieldMode::Isophote, 0.0, along_y) + 1.0).abs() < 1e-6);
+        let diagonal = field_energy(FieldMode::Rotated, 45.0, Vector::new(1.0, 1.0));
+        assert!((diagonal + 2_f32.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn widths_follow_the_darkness() {
+        let params = ModelParameters::new()
+            .spline_count(40)
+            .segment_len(0.05)
+            .interaction_radius(0.02)
+            .precision(8)
+            .set_vary_widths()
+            .seed(5)
+            .unset_make_plots()
+            .build();
+        let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+        let mut model = Model::new()
+            .potential_from_fn(|pos| pos.x, bounds, (50, 50))
+            .add_params(params)
+            .log_dir(std::env::temp_dir().join("linewise_width_test"))
+            .build()
+            .unwrap();
+        for _ in 0..30 {
+            model.run_sweep(0.01);
+        }
+        let (mut dark, mut bright) = (Vec::new(), Vec::new());
+        for spline in model.storage.all_splines() {
+            if spline.calculate_bounds().get_center().x < 0.5 {
+                dark.push(spline.width())
+            } else {
+                bright.push(spline.width())
+            }
+        }
+        let mean = |widths: &[f32]| widths.iter().sum::<f32>() / widths.len() as f32;
+        assert!(mean(&dark) > mean(&bright));
+        assert!(model.make_svg_doc().to_string().contains("stroke-width"));
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::Path;
+
+use common::Rect;
+
+use crate::builder::{ModelBuilder, ParamBuilder};
+use crate::{Model, ModelParameters};
+
+pub(crate) fn seeded_model(seed: u64, log_dir: &Path) -> Model {
+    seeded_model_with(seed, log_dir, |params| params)
+}
+
+/// a small model of 20 splines on the potential x * y, `tweak` changes its parameters
+pub(crate) fn seeded_model_with(
+    seed: u64,
+    log_dir: &Path,
+    tweak: impl FnOnce(ParamBuilder) -> ParamBuilder,
+) -> Model {
+    seeded_builder(seed, log_dir, tweak).build().unwrap()
+}
+
+/// the builder of `seeded_model_with` for tests which also change the model
+pub(crate) fn seeded_builder(
+    seed: u64,
+    log_dir: &Path,
+    tweak: impl FnOnce(ParamBuilder) -> ParamBuilder,
+) -> ModelBuilder {
+    let params = ModelParameters::new()
+        .spline_count(20)
+        .segment_len(0.05)
+        .interaction_radius(0.05)
+        .precision(8)
+        .temp_steps(2)
+        .sweeps_per_temp(5)
+        .checkpoint_interval(2)
+        .seed(seed)
+        .unset_make_plots();
+    let bounds = Rect::new(0.0, 1.0, 0.0, 1.0);
+    Model::new()
+        .potential_from_fn(|pos| pos.x * pos.y, bounds, (50, 50))
+        .add_params(tweak(params).build())
+        .log_dir(log_dir)
+}
+
+/// runs the seeded model in a directory of the temp dir and returns the final svg
+pub(crate) fn run_seeded(seed: u64, dir: &str) -> String {
+    let log_dir = std::env::temp_dir().join(dir);
+    seeded_model(seed, &log_dir).run(None).unwrap();
+    let parameters = fs::read_to_string(log_dir.join("parameters.ron")).unwrap();
+    assert!(parameters.contains(&format!("seed: Some({})", seed)));
+    fs::read_to_string(log_dir.join("img_end.svg")).unwrap()
+}
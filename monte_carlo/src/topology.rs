@@ -0,0 +1,375 @@
+use std::f32::consts::TAU;
+
+use common::{Spline, SplineRef, Vector, quad_tree::Bounded};
+use random::{Rng, gaussian_vector, rand_unit};
+
+use super::Model;
+
+// width of the gaussian noise of new points and vectors relative to the segment length,
+// the noise is measured in units of this width which fixes the weight of a segment
+const NOISE: f32 = 0.1;
+
+/// density of the standard normal distribution of two two dimensional vectors
+fn normal_density(a: Vector, b: Vector) -> f32 {
+    (-(a.norm_squared() + b.norm_squared()) / 2.0).exp() / TAU.powi(2)
+}
+
+/// the moves which change the number of segments or splines, every move has its inverse
+/// in the list so they are reversible
+#[derive(Debug, Clone, Copy)]
+enum Topology {
+    Grow,
+    Shrink,
+    Subdivide,
+    Coarsen,
+    Split,
+    Merge,
+}
+
+impl Topology {
+    const ALL: [Self; 6] = [
+        Self::Grow,
+        Self::Shrink,
+        Self::Subdivide,
+        Self::Coarsen,
+        Self::Split,
+        Self::Merge,
+    ];
+}
+
+impl Model {
+    pub(crate) fn noise(&self) -> f32 {
+        NOISE * self.params.segment_len
+    }
+
+    /// the point and vector a new segment at the end of `spline` is drawn around
+    fn continuation(spline: &Spline, at_start: bool) -> (Vector, Vector) {
+        let (point, vector) = spline.end(at_start);
+        if at_start {
+            (point - 2.0 * vector, vector)
+        } else {
+            (point + 2.0 * vector, vector)
+        }
+    }
+
+    /// the splines whose start lies within `merge_radius` of `point`
+    fn merge_candidates(&self, point: Vector) -> impl Iterator<Item = &SplineRef> {
+        let radius = self.params.merge_radius;
+        self.splines
+            .query_intersects(point.bounding_box().add_radius(radius))
+            .filter(move |&other| {
+                !self.storage.is_empty(other)
+                    && (self.storage.get_spline(other).as_slice()[0] - point).norm() <= radius
+            })
+    }
+
+    /// Changes the number of segments of a spline or the number of splines with one of the
+    /// moves of `Topology` chosen uniformly.
+    ///
+    /// New points and vectors are drawn from a gaussian around the continuation of the curve,
+    /// so the acceptance has the Metropolis-Hastings factor of the proposal densities
+    /// of the move and its inverse. A move which is impossible for the chosen spline,
+    /// e.g. growing a spline with `max_segments`, is rejected.
+    pub fn take_topology_step(&mut self, temp: f32) {
+        if self.splines.is_empty() {
+            return;
+        }
+        let count = self.splines.len() as f32;
+        let move_type = Topology::ALL[self.rng.random_range(0..Topology::ALL.len())];
+        let old = self.storage.read(self.splines.pop_random(&mut self.rng));
+        let segments = old.count_segments();
+        let max_segments = self.params.max_segments;
+        let noise = self.noise();
+
+        // the new spline and the ratio of the proposal densities
+        let (new, ratio) = match move_type {
+            Topology::Grow if segments < max_segments => {
+                let at_start = self.rng.random::<bool>();
+                let (z_point, z_vector) = (
+                    gaussian_vector(&mut self.rng),
+                    gaussian_vector(&mut self.rng),
+                );
+                let (point, vector) = Self::continuation(&old, at_start);
+                let mut new = old.clone();
+                new.push_segment(at_start, point + noise * z_point, vector + noise * z_vector);
+                (new, 1.0 / normal_density(z_point, z_vector))
+            }
+            Topology::Shrink if segments > 1 => {
+                let at_start = self.rng.random::<bool>();
+                let mut new = old.clone();
+                let (point, vector) = new.pop_segment(at_start);
+                let (center, center_vector) = Self::continuation(&new, at_start);
+                let ratio =
+                    normal_density((point - center) / noise, (vector - center_vector) / noise);
+                (new, ratio)
+            }
+            Topology::Subdivide if segments < max_segments => {
+                let segment = self.rng.random_range(0..segments);
+                let (z_point, z_vector) = (
+                    gaussian_vector(&mut self.rng),
+                    gaussian_vector(&mut self.rng),
+                );
+                let mut subdivided = old.clone();
+                subdivided.subdivide(segment);
+                let mut values = subdivided.into_vec();
+                values[2 * segment + 2] += noise * z_point;
+                values[2 * segment + 3] += noise * z_vector;
+                let new = Spline::from_vec(values).with_width(old.width());
+                // halving the two outer vectors has the jacobian 1/16
+                (new, 1.0 / 16.0 / normal_density(z_point, z_vector))
+            }
+            Topology::Coarsen if segments > 1 => {
+                let point = self.rng.random_range(1..segments);
+                let mut new = old.clone();
+                let (removed_point, removed_vector) = new.join_at(point);
+                let (mid_point, mid_vector) = new.midpoint(point - 1);
+                let ratio = 16.0
+                    * normal_density(
+                        (removed_point - mid_point) / noise,
+                        (removed_vector - mid_vector) / noise,
+                    );
+                (new, ratio)
+            }
+            Topology::Split if segments > 2 => {
+                return self.propose_split(old, count, temp);
+            }
+            Topology::Merge => {
+                return self.propose_merge(old, count, temp);
+            }
+            _ => {
+                self.splines
+                    .insert(self.storage.revalidate_ref(old), &self.storage);
+                return;
+            }
+        };
+
+        if self.crosses_neighbours(&new) {
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+            return;
+        }
+        let e_0 = self.energy_for_delta(&old).tot();
+        let e_1 = self.energy_for_delta(&new).tot();
+        let (d_fidelity, delta) =
+            self.fidelity_delta(&[old.as_borrowed_spline()], &[new.as_borrowed_spline()]);
+        let d_e = e_1 - e_0 + d_fidelity;
+        if self.rng.random::<f32>() < ratio * (-d_e / temp).exp() {
+            self.apply_fidelity(delta);
+            let spline_ref = self.storage.replace_spline(new);
+            self.storage.grow_spline_info(&mut self.markings);
+            self.splines.insert(spline_ref, &self.storage);
+        } else {
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+        }
+    }
+
+    /// a new spline for `take_birth_death_step` with the density of its shape
+    /// from `birth_density`, its start is uniform in the canvas shape,
+    /// which needs a positive area
+    pub(crate) fn propose_birth(&mut self) -> (Spline, f32) {
+        let noise = self.noise();
+        let segments = self.rng.random_range(1..=self.params.max_segments);
+        let bounds = self.shape.bounds();
+        let start = loop {
+            let start = bounds.from_box_coords((self.rng.random(), self.rng.random()));
+            if self.shape.signed_distance(start) < 0.0 {
+                break start;
+            }
+        };
+        let length = 0.5 * self.params.segment_len + noise * gaussian_vector(&mut self.rng).x;
+        let first_vector = length.abs() * rand_unit(&mut self.rng);
+        let mut values = vec![start, first_vector];
+        for _ in 0..segments {
+            let (point, vector) = (values[values.len() - 2], values[values.len() - 1]);
+            values.push(point + 2.0 * vector + noise * gaussian_vector(&mut self.rng));
+            values.push(vector + noise * gaussian_vector(&mut self.rng));
+        }
+        let mut spline = Spline::from_vec(values);
+        if self.params.vary_widths {
+            let (min, max) = self.params.width_range;
+            spline.set_width(min + self.rng.random::<f32>() * (max - min));
+        }
+        let density = self.birth_density(&spline);
+        (spline, density)
+    }
+
+    /// density of `propose_birth` drawing the shape of `spline` in the units of the other
+    /// topology moves, the start is not included as it is counted by the volume.
+    ///
+    /// The first vector has a uniform direction and a length drawn from a gaussian around
+    /// half the segment length, every further point and vector is drawn around
+    /// the continuation like in `Topology::Grow`, so new splines are roughly straight.
+    pub(crate) fn birth_density(&self, spline: &Spline) -> f32 {
+        let noise = self.noise();
+        let segments = spline.count_segments();
+        let (start, first_vector) = spline.end(true);
+        if segments > self.params.max_segments || self.shape.signed_distance(start) >= 0.0 {
+            return 0.0;
+        }
+        // the length is folded at zero and the direction spreads it over a circle
+        let (length, mean) = (first_vector.norm(), 0.5 * self.params.segment_len);
+        let normal = |z: f32| (-z * z / 2.0).exp() / TAU.sqrt();
+        let mut density = (normal((length - mean) / noise) + normal((length + mean) / noise))
+            * noise
+            / (TAU * length)
+            / self.params.max_segments as f32;
+        for pair in spline.as_slice().windows(4).step_by(2) {
+            let (point, vector, next_point, next_vector) = (pair[0], pair[1], pair[2], pair[3]);
+            density *= normal_density(
+                (next_point - point - 2.0 * vector) / noise,
+                (next_vector - vector) / noise,
+            );
+        }
+        if self.params.vary_widths {
+            let (min, max) = self.params.width_range;
+            if !(min..=max).contains(&spline.width()) {
+                return 0.0;
+            }
+            density /= max - min;
+        }
+        density
+    }
+
+    /// removes a random inner segment of `old`, the inverse of `propose_merge`
+    fn propose_split(&mut self, old: Spline, count: f32, temp: f32) {
+        let segments = old.count_segments();
+        let segment = self.rng.random_range(1..segments - 1);
+        let (first, second) = old.clone().split_at(segment);
+        let end = first.end(false).0;
+
+        // the merge back chooses `first` among count + 1 splines and `second`
+        // among the splines starting close to the end of `first`
+        if (second.end(true).0 - end).norm() > self.params.merge_radius
+            || self.crosses_neighbours(&first)
+            || self.crosses_neighbours(&second)
+        {
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+            return;
+        }
+        let candidates = self.merge_candidates(end).count() + 1;
+        let ratio = count / (count + 1.0) * (segments - 2) as f32 / candidates as f32;
+
+        let e_0 = self.energy_for_delta(&old).tot();
+        let e_1 = self.energy_for_group(&[&first, &second]).tot();
+        let (d_fidelity, delta) = self.fidelity_delta(
+            &[old.as_borrowed_spline()],
+            &[first.as_borrowed_spline(), second.as_borrowed_spline()],
+        );
+        let d_e = e_1 - e_0 + d_fidelity;
+        if self.rng.random::<f32>() < ratio * (-d_e / temp).exp() {
+            self.apply_fidelity(delta);
+            let first_ref = self.storage.replace_spline(first);
+            let second_ref = self.storage.add_spline(second);
+            self.storage.grow_spline_info(&mut self.markings);
+            self.splines.insert(first_ref, &self.storage);
+            self.splines.insert(second_ref, &self.storage);
+        } else {
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+        }
+    }
+
+    /// joins the end of `old` to the start of a random spline starting close to it
+    /// with a new segment, the inverse of `propose_split`, splines of different widths
+    /// are not merged
+    fn propose_merge(&mut self, old: Spline, count: f32, temp: f32) {
+        let end = old.end(false).0;
+        let candidates = self.merge_candidates(end).count();
+        if candidates == 0 {
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+            return;
+        }
+        let choice = self.rng.random_range(0..candidates);
+        let other_ref = self.storage.make_ref(
+            self.merge_candidates(end)
+                .nth(choice)
+                .expect("the choice is one of the candidates"),
+        );
+        let other = self.storage.get_owned(&other_ref);
+        // `split_at` gives both halves the width of the spline, so only splines
+        // of the same width can be merged reversibly
+        let merged = old.clone().join(&other);
+        if merged.count_segments() > self.params.max_segments || old.width() != other.width() {
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+            return;
+        }
+        // the split back chooses `merged` among count - 1 splines
+        // and the new segment among its inner segments
+        let ratio =
+            count / (count - 1.0) * candidates as f32 / (merged.count_segments() - 2) as f32;
+
+        // the other spline is checked out next to `old` so neither is a neighbour of `merged`
+        let other_ref = self
+            .splines
+            .remove(&other_ref)
+            .expect("the other spline is in the tree");
+        let mut transaction = self.storage.checkout(vec![other_ref]);
+        if self.crosses_neighbours(&merged) {
+            for spline_ref in self.storage.rollback(transaction) {
+                self.splines.insert(spline_ref, &self.storage);
+            }
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+            return;
+        }
+        let e_0 = self.energy_for_group(&[&old, &other]).tot();
+        let e_1 = self.energy_for_delta(&merged).tot();
+        let (d_fidelity, delta) = self.fidelity_delta(
+            &[old.as_borrowed_spline(), other.as_borrowed_spline()],
+            &[merged.as_borrowed_spline()],
+        );
+        let d_e = e_1 - e_0 + d_fidelity;
+        if self.rng.random::<f32>() < ratio * (-d_e / temp).exp() {
+            self.apply_fidelity(delta);
+            transaction.remove(0);
+            self.storage.commit(transaction);
+            let merged_ref = self.storage.replace_spline(merged);
+            self.storage.grow_spline_info(&mut self.markings);
+            self.splines.insert(merged_ref, &self.storage);
+        } else {
+            for spline_ref in self.storage.rollback(transaction) {
+                self.splines.insert(spline_ref, &self.storage);
+            }
+            self.splines
+                .insert(self.storage.revalidate_ref(old), &self.storage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::seeded_model_with;
+
+    use super::*;
+
+    #[test]
+    fn topology_steps_change_the_segments() {
+        let log_dir = std::env::temp_dir().join("linewise_topology_test");
+        let mut model = seeded_model_with(6, &log_dir, |params| {
+            params
+                .spline_count(30)
+                .max_segments(6)
+                .interaction_radius(0.02)
+                .topology_rate(0.5)
+        });
+        let segments = |model: &Model| -> Vec<usize> {
+            model
+                .storage
+                .all_splines()
+                .map(|spline| spline.count_segments())
+                .collect()
+        };
+        let start = segments(&model);
+        for _ in 0..10 {
+            model.run_sweep(1.0);
+        }
+        assert_ne!(start, segments(&model));
+        assert!(segments(&model).iter().all(|&segments| segments <= 6));
+        assert_eq!(model.count_splines(), model.storage.count_splines());
+        assert!(model.calc_tot_energy().is_finite());
+    }
+}
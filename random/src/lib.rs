@@ -26,6 +26,10 @@ pub fn new_rng() -> MyRng {
     MyRng::try_from_os_rng().expect("failed to get rng from os rng")
 }
 
+pub fn rng_from_seed(seed: u64) -> MyRng {
+    MyRng::seed_from_u64(seed)
+}
+
 pub fn rand_unit(rng: &mut MyRng) -> Vector {
     gaussian_vector(rng).normalize()
 }